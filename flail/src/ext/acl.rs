@@ -0,0 +1,360 @@
+use super::*;
+
+/// The on-disk version of ext4's ACL xattrs, which is not the one in the
+/// xattrs the kernel hands to userspace.
+const EXT4_ACL_VERSION: u32 = 1;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Which of an inode's ACLs to use.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ExtAclKind {
+    /// Decides who can access the inode itself.
+    Access,
+    /// Only on directories: what new entries inside start out with.
+    Default,
+}
+
+impl ExtAclKind {
+    fn xattr_key(&self) -> &'static str {
+        match self {
+            ExtAclKind::Access => "system.posix_acl_access",
+            ExtAclKind::Default => "system.posix_acl_default",
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ExtAclPerms: u16 {
+        const READ = 0o4;
+        const WRITE = 0o2;
+        const EXECUTE = 0o1;
+    }
+}
+
+/// Who an [`ExtAclEntry`] applies to.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ExtAclTag {
+    /// The owner, like the user bits of the mode.
+    UserObj,
+    User(u32),
+    /// The owning group, like the group bits of the mode.
+    GroupObj,
+    Group(u32),
+    /// The most that any `User`, `Group` or `GroupObj` entry can grant.
+    Mask,
+    Other,
+}
+
+impl ExtAclTag {
+    fn raw(&self) -> u16 {
+        match self {
+            ExtAclTag::UserObj => ACL_USER_OBJ,
+            ExtAclTag::User(_) => ACL_USER,
+            ExtAclTag::GroupObj => ACL_GROUP_OBJ,
+            ExtAclTag::Group(_) => ACL_GROUP,
+            ExtAclTag::Mask => ACL_MASK,
+            ExtAclTag::Other => ACL_OTHER,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ExtAclEntry {
+    pub tag: ExtAclTag,
+    pub perms: ExtAclPerms,
+}
+
+/// A POSIX ACL, stored in the `system.posix_acl_access` and
+/// `system.posix_acl_default` xattrs.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct ExtAcl(Vec<ExtAclEntry>);
+
+impl ExtAcl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ACL equivalent to the permission bits of `mode`.
+    pub fn from_mode(mode: u16) -> Self {
+        Self::new()
+            .entry(ExtAclTag::UserObj, perms(mode >> 6))
+            .entry(ExtAclTag::GroupObj, perms(mode >> 3))
+            .entry(ExtAclTag::Other, perms(mode))
+    }
+
+    /// Adds an entry, replacing any other for the same tag.
+    pub fn entry(mut self, tag: ExtAclTag, perms: ExtAclPerms) -> Self {
+        self.0.retain(|entry| entry.tag != tag);
+        self.0.push(ExtAclEntry { tag, perms });
+        self.0.sort_by_key(|entry| entry.tag);
+        self
+    }
+
+    pub fn entries(&self) -> &[ExtAclEntry] {
+        &self.0
+    }
+
+    pub fn get(&self, tag: ExtAclTag) -> Option<ExtAclPerms> {
+        self.0
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perms)
+    }
+
+    /// Whether the ACL says no more than the permission bits of a mode
+    /// would, so that the kernel doesn't bother storing it.
+    pub fn is_minimal(&self) -> bool {
+        self.0.len() == 3
+    }
+
+    /// Checks that the ACL is one the kernel accepts: exactly one `UserObj`,
+    /// `GroupObj` and `Other` entry, and a `Mask` if there are any `User` or
+    /// `Group` entries.
+    pub fn validate(&self) -> Result<()> {
+        let has = |tag| self.get(tag).is_some();
+        let named = self
+            .0
+            .iter()
+            .any(|entry| matches!(entry.tag, ExtAclTag::User(_) | ExtAclTag::Group(_)));
+        if !has(ExtAclTag::UserObj) || !has(ExtAclTag::GroupObj) || !has(ExtAclTag::Other) {
+            return Err(eyre!("ACLs need user, group and other entries"));
+        }
+        if named && !has(ExtAclTag::Mask) {
+            return Err(eyre!("ACLs with named user or group entries need a mask"));
+        }
+        Ok(())
+    }
+
+    /// The permission bits of a mode that go with this ACL, as `chmod` would
+    /// show them. The group bits come from the mask if there is one.
+    pub fn mode(&self) -> u16 {
+        let bits = |tag| self.get(tag).map(|perms| perms.bits()).unwrap_or(0);
+        let group = match self.get(ExtAclTag::Mask) {
+            Some(mask) => mask.bits(),
+            None => bits(ExtAclTag::GroupObj),
+        };
+        bits(ExtAclTag::UserObj) << 6 | group << 3 | bits(ExtAclTag::Other)
+    }
+
+    /// Encodes the ACL in ext4's on-disk format: a version, then every
+    /// entry in order, with ids only for named users and groups.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = EXT4_ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.0 {
+            out.extend_from_slice(&entry.tag.raw().to_le_bytes());
+            out.extend_from_slice(&entry.perms.bits().to_le_bytes());
+            if let ExtAclTag::User(id) | ExtAclTag::Group(id) = entry.tag {
+                out.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Decodes an ACL in ext4's on-disk format.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let u16_at = |at: usize| -> Result<u16> {
+            buf.get(at..at + 2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .ok_or_else(|| eyre!("ACL truncated at byte {at}"))
+        };
+        let u32_at = |at: usize| -> Result<u32> {
+            buf.get(at..at + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| eyre!("ACL truncated at byte {at}"))
+        };
+
+        let version = u32_at(0)?;
+        if version != EXT4_ACL_VERSION {
+            return Err(eyre!("unknown ACL version {version}"));
+        }
+        let mut acl = Self::new();
+        let mut at = 4;
+        while at < buf.len() {
+            let tag = u16_at(at)?;
+            let perms = ExtAclPerms::from_bits_truncate(u16_at(at + 2)?);
+            at += 4;
+            let tag = match tag {
+                ACL_USER_OBJ => ExtAclTag::UserObj,
+                ACL_GROUP_OBJ => ExtAclTag::GroupObj,
+                ACL_MASK => ExtAclTag::Mask,
+                ACL_OTHER => ExtAclTag::Other,
+                ACL_USER | ACL_GROUP => {
+                    let id = u32_at(at)?;
+                    at += 4;
+                    if tag == ACL_USER {
+                        ExtAclTag::User(id)
+                    } else {
+                        ExtAclTag::Group(id)
+                    }
+                }
+                tag => return Err(eyre!("unknown ACL tag {tag:#x}")),
+            };
+            if acl.get(tag).is_some() {
+                return Err(eyre!("duplicate ACL entry for {tag:?}"));
+            }
+            acl = acl.entry(tag, perms);
+        }
+        Ok(acl)
+    }
+}
+
+fn perms(bits: u16) -> ExtAclPerms {
+    ExtAclPerms::from_bits_truncate(bits & 0o7)
+}
+
+impl ExtFilesystem {
+    /// Reads the `kind` ACL of `path`, or `None` if it doesn't have one. An
+    /// inode without an access ACL is governed by its mode alone, see
+    /// [`ExtAcl::from_mode`].
+    pub fn get_acl<P: Into<PathBuf>>(&self, path: P, kind: ExtAclKind) -> Result<Option<ExtAcl>> {
+        self.get_acl_by_inode(self.find_inode(path)?.0, kind)
+    }
+
+    pub fn get_acl_by_inode(&self, inode: u32, kind: ExtAclKind) -> Result<Option<ExtAcl>> {
+        self.read_xattr(inode, kind.xattr_key(), libe2fs_sys::XATTR_HANDLE_FLAG_RAW)?
+            .map(|value| ExtAcl::decode(&value))
+            .transpose()
+    }
+
+    /// Sets the `kind` ACL of `path`. Like the kernel, setting the access
+    /// ACL also sets the permission bits of the mode to match, and a minimal
+    /// access ACL is only kept as the mode. Default ACLs can only be set on
+    /// directories.
+    pub fn set_acl<P: Into<PathBuf>>(&self, path: P, kind: ExtAclKind, acl: &ExtAcl) -> Result<()> {
+        self.set_acl_by_inode(self.find_inode(path)?.0, kind, acl)
+    }
+
+    pub fn set_acl_by_inode(&self, inode: u32, kind: ExtAclKind, acl: &ExtAcl) -> Result<()> {
+        self.check_writable()?;
+        acl.validate()?;
+
+        let mut ext_inode = self.read_inode(inode)?;
+        if kind == ExtAclKind::Default && !ext_inode.is_dir() {
+            return Err(ExtError::EACCES.into());
+        }
+
+        if kind == ExtAclKind::Access {
+            ext_inode.1.i_mode = (ext_inode.1.i_mode & !0o777) | acl.mode();
+            ext_inode.stamp(ExtInodeTimes::CTIME, self.now_time());
+            self.write_inode(&mut ext_inode)?;
+            if acl.is_minimal() {
+                return self.remove_acl_by_inode(inode, kind);
+            }
+        }
+
+        self.write_xattr(
+            inode,
+            kind.xattr_key(),
+            &acl.encode(),
+            libe2fs_sys::XATTR_HANDLE_FLAG_RAW,
+        )
+    }
+
+    /// Removes the `kind` ACL of `path`, if it has one.
+    pub fn remove_acl<P: Into<PathBuf>>(&self, path: P, kind: ExtAclKind) -> Result<()> {
+        self.remove_acl_by_inode(self.find_inode(path)?.0, kind)
+    }
+
+    pub fn remove_acl_by_inode(&self, inode: u32, kind: ExtAclKind) -> Result<()> {
+        match self.remove_xattr_by_inode(inode, kind.xattr_key()) {
+            Err(err) if matches!(err.downcast_ref::<ExtError>(), Some(ExtError::ENODATA)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    #[test]
+    pub fn test_acls_round_trip() -> Result<()> {
+        let acl = ExtAcl::from_mode(0o750)
+            .entry(
+                ExtAclTag::User(1000),
+                ExtAclPerms::READ | ExtAclPerms::WRITE,
+            )
+            .entry(ExtAclTag::Group(100_000), ExtAclPerms::READ)
+            .entry(ExtAclTag::Mask, ExtAclPerms::READ | ExtAclPerms::WRITE);
+        acl.validate()?;
+        assert_eq!(0o760, acl.mode());
+
+        let encoded = acl.encode();
+        // header, four short entries and two with ids
+        assert_eq!(4 + 4 * 4 + 2 * 8, encoded.len());
+        assert_eq!(
+            &[1, 0, 0, 0, 0x01, 0, 0o7, 0, 0x02, 0, 0o6, 0, 0xe8, 0x03, 0, 0],
+            &encoded[..16]
+        );
+        assert_eq!(acl, ExtAcl::decode(&encoded)?);
+
+        assert!(ExtAcl::new()
+            .entry(ExtAclTag::UserObj, ExtAclPerms::all())
+            .validate()
+            .is_err());
+        assert!(ExtAcl::from_mode(0o644)
+            .entry(ExtAclTag::User(1000), ExtAclPerms::READ)
+            .validate()
+            .is_err());
+        assert!(ExtAcl::decode(&[2, 0, 0, 0]).is_err());
+        assert!(ExtAcl::decode(&encoded[..encoded.len() - 2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_acls_are_stored() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("acls.ext4");
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                16 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().inode_size(256)),
+            )?;
+            fs.mkdir("/", "shared")?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+            assert_eq!(None, fs.get_acl("/shared", ExtAclKind::Default)?);
+
+            let default = ExtAcl::from_mode(0o770)
+                .entry(ExtAclTag::Group(100_000), ExtAclPerms::all())
+                .entry(ExtAclTag::Mask, ExtAclPerms::all());
+            fs.set_acl("/shared", ExtAclKind::Default, &default)?;
+            assert_eq!(Some(default), fs.get_acl("/shared", ExtAclKind::Default)?);
+
+            let access = ExtAcl::from_mode(0o640)
+                .entry(ExtAclTag::User(1000), ExtAclPerms::READ)
+                .entry(ExtAclTag::Mask, ExtAclPerms::READ);
+            fs.set_acl("/test.txt", ExtAclKind::Access, &access)?;
+            assert_eq!(Some(access), fs.get_acl("/test.txt", ExtAclKind::Access)?);
+            assert_eq!(0o640, fs.find_inode("/test.txt")?.mode() & 0o777);
+
+            // libe2fs hands out the kernel's userspace format, version 2
+            let xattr = fs
+                .get_xattr("/test.txt", "system.posix_acl_access")?
+                .unwrap();
+            assert_eq!(&[2, 0, 0, 0], &xattr[..4]);
+
+            // default ACLs only make sense on directories
+            assert!(fs
+                .set_acl("/test.txt", ExtAclKind::Default, &ExtAcl::from_mode(0o644))
+                .is_err());
+
+            // minimal access ACLs are just the mode
+            fs.set_acl("/test.txt", ExtAclKind::Access, &ExtAcl::from_mode(0o600))?;
+            assert_eq!(None, fs.get_acl("/test.txt", ExtAclKind::Access)?);
+            assert_eq!(0o600, fs.find_inode("/test.txt")?.mode() & 0o777);
+        }
+        assert_fsck_clean(&img)?;
+
+        Ok(())
+    }
+}
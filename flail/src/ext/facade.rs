@@ -15,7 +15,10 @@ use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
 use tokio::sync::RwLock;
 
 use super::file::ExtFile;
-use super::inode::ExtInode;
+use super::inode::{ExtInode, ExtInodeTimes};
+use super::memory::ExtMemoryImage;
+use super::mkfs::ExtMkfsOptions;
+use super::open::ExtOpenOptions;
 use super::{ExtFileOpenFlags, ExtFilesystemOpenFlags};
 
 #[derive(Debug, Clone)]
@@ -28,30 +31,126 @@ unsafe impl Sync for ExtFacadeFloppyDisk {}
 
 impl ExtFacadeFloppyDisk {
     pub fn new<P: Into<PathBuf> + std::fmt::Debug>(path: P) -> Result<Self> {
+        Self::new_with_options(
+            path,
+            ExtOpenOptions::new()
+                .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )
+    }
+
+    /// Opens a filesystem inside a larger image, see
+    /// [`super::ExtFilesystem::open_with_options`].
+    pub fn new_with_options<P: Into<PathBuf> + std::fmt::Debug>(
+        path: P,
+        options: ExtOpenOptions,
+    ) -> Result<Self> {
         Ok(Self {
             fs: Arc::new(RwLock::new(
-                super::ExtFilesystem::open(
-                    path,
-                    None,
-                    Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
-                )
-                .map_err(wrap_report)?,
+                super::ExtFilesystem::open_with_options(path, options).map_err(wrap_report)?,
             )),
         })
     }
 
     pub fn create<P: Into<PathBuf> + std::fmt::Debug>(
         path: P,
-        mut size_bytes: u64,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        Ok(Self {
+            fs: Arc::new(RwLock::new(
+                super::ExtFilesystem::create(path, size_bytes, options).map_err(wrap_report)?,
+            )),
+        })
+    }
+
+    /// Creates a filesystem inside a larger image, see
+    /// [`super::ExtFilesystem::create_at`].
+    pub fn create_at<P: Into<PathBuf> + std::fmt::Debug>(
+        path: P,
+        offset: u64,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        Ok(Self {
+            fs: Arc::new(RwLock::new(
+                super::ExtFilesystem::create_at(path, offset, size_bytes, options)
+                    .map_err(wrap_report)?,
+            )),
+        })
+    }
+
+    /// Creates a filesystem filling all of an in-memory image, see
+    /// [`super::ExtFilesystem::create_in_memory`].
+    pub fn create_in_memory(
+        image: &ExtMemoryImage,
+        options: Option<ExtMkfsOptions>,
     ) -> Result<Self> {
-        // Block alignment
-        size_bytes = size_bytes + (1024 - (size_bytes % 1024));
         Ok(Self {
             fs: Arc::new(RwLock::new(
-                super::ExtFilesystem::create(path, size_bytes).map_err(wrap_report)?,
+                super::ExtFilesystem::create_in_memory(image, options).map_err(wrap_report)?,
+            )),
+        })
+    }
+
+    /// Opens the filesystem in an in-memory image for reading and writing.
+    pub fn open_in_memory(image: &ExtMemoryImage) -> Result<Self> {
+        Ok(Self {
+            fs: Arc::new(RwLock::new(
+                super::ExtFilesystem::open_in_memory(image, None).map_err(wrap_report)?,
             )),
         })
     }
+
+    /// Sets the access and modification times of `path`, like `utimes`. See
+    /// [`super::ExtFilesystem::set_times`].
+    pub async fn set_times<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        let fs = self.fs.write().await;
+        fs.set_times(path.as_ref(), atime, mtime)
+            .map_err(wrap_report)
+    }
+
+    /// Lists the keys of every xattr on `path`, see
+    /// [`super::ExtFilesystem::list_xattrs`].
+    pub async fn list_xattrs<P: AsRef<Path> + Send>(&self, path: P) -> Result<Vec<String>> {
+        let fs = self.fs.read().await;
+        fs.list_xattrs(path.as_ref()).map_err(wrap_report)
+    }
+
+    /// Reads the xattr `key` of `path`, or `None` if it isn't set.
+    pub async fn get_xattr<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let fs = self.fs.read().await;
+        fs.get_xattr(path.as_ref(), key).map_err(wrap_report)
+    }
+
+    pub async fn set_xattr<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        key: &str,
+        value: &[u8],
+    ) -> Result<()> {
+        let fs = self.fs.write().await;
+        fs.set_xattr(path.as_ref(), key, value).map_err(wrap_report)
+    }
+
+    pub async fn remove_xattr<P: AsRef<Path> + Send>(&self, path: P, key: &str) -> Result<()> {
+        let fs = self.fs.write().await;
+        fs.remove_xattr(path.as_ref(), key).map_err(wrap_report)
+    }
+
+    /// Grows or shrinks the disk, see [`super::ExtFilesystem::resize`].
+    pub async fn resize(&self, new_size_bytes: u64) -> Result<()> {
+        let fs = self.fs.write().await;
+        fs.resize(new_size_bytes).map_err(wrap_report)
+    }
 }
 
 #[async_trait::async_trait]
@@ -89,6 +188,7 @@ impl<'a> FloppyDisk<'a> for ExtFacadeFloppyDisk {
             let fs = self.fs.write().await;
             let mut inode = fs.find_inode(to).map_err(wrap_report)?;
             inode.1.i_mode = (inode.1.i_mode & 0o70000) | permissions;
+            inode.stamp(ExtInodeTimes::CTIME, fs.now_time());
             fs.write_inode(&mut inode).map_err(wrap_report)?;
         }
 
@@ -302,6 +402,7 @@ impl<'a> FloppyDisk<'a> for ExtFacadeFloppyDisk {
                 mode &= !0o777;
                 mode |= perm.0 & 0o777;
                 inode.1.i_mode = mode;
+                inode.stamp(ExtInodeTimes::CTIME, fs.now_time());
                 fs.write_inode(&mut inode).map_err(wrap_report)
             }
             Err(err) => Err(wrap_report(err)),
@@ -362,8 +463,9 @@ impl FloppyDiskUnixExt for ExtFacadeFloppyDisk {
     async fn chown<P: Into<PathBuf> + Send>(&self, path: P, uid: u32, gid: u32) -> Result<()> {
         let fs = self.fs.write().await;
         let mut inode = fs.find_inode(path.into()).map_err(wrap_report)?;
-        inode.1.i_uid = uid as u16;
-        inode.1.i_gid = gid as u16;
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+        inode.stamp(ExtInodeTimes::CTIME, fs.now_time());
         fs.write_inode(&mut inode).map_err(wrap_report)
     }
 }
@@ -408,18 +510,23 @@ impl<'a> FloppyMetadata<'a, ExtFacadeFloppyDisk> for ExtFacadeMetadata {
         self.inode.atime().map_err(wrap_report)
     }
 
+    /// Like [`std::fs::Metadata::created`], fails with
+    /// [`std::io::ErrorKind::Unsupported`] if the inode is too small to
+    /// record a creation time.
     fn created(&self) -> Result<SystemTime> {
-        self.inode.ctime().map_err(wrap_report)
+        self.inode
+            .crtime()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Unsupported, err))
     }
 }
 
 impl FloppyUnixMetadata for ExtFacadeMetadata {
     fn uid(&self) -> Result<u32> {
-        Ok(self.inode.0 .1.i_uid as u32)
+        Ok(self.inode.uid())
     }
 
     fn gid(&self) -> Result<u32> {
-        Ok(self.inode.0 .1.i_gid as u32)
+        Ok(self.inode.gid())
     }
 }
 
@@ -520,6 +627,7 @@ impl FloppyDirBuilder for ExtFacadeDirBuilder<'_> {
         if let Some(mode) = self.mode {
             let mut inode = fs.find_inode(path).unwrap();
             inode.1.i_mode |= mode as u16;
+            inode.stamp(ExtInodeTimes::CTIME, fs.now_time());
             fs.write_inode(&mut inode).map_err(wrap_report)?;
         }
 
@@ -708,9 +816,11 @@ impl<'a> FloppyFile<'a, ExtFacadeFloppyDisk> for ExtFacadeFile<'a> {
     async fn set_len(&mut self, size: u64) -> Result<()> {
         let fs = self.facade.fs.write().await;
         let mut inode = fs.get_inode(&self.file).map_err(wrap_report)?;
-        // TODO: Support 64-bit inodes properly!
         inode.1.i_size = size as u32;
-        fs.write_inode(&mut inode).map_err(wrap_report)?;
+        inode.1.i_size_high = (size >> 32) as u32;
+        inode.stamp(ExtInodeTimes::MTIME | ExtInodeTimes::CTIME, fs.now_time());
+        fs.write_file_inode(&self.file, &mut inode)
+            .map_err(wrap_report)?;
         Ok(())
     }
 
@@ -734,7 +844,9 @@ impl<'a> FloppyFile<'a, ExtFacadeFloppyDisk> for ExtFacadeFile<'a> {
         let mut inode = fs.get_inode(&self.file).map_err(wrap_report)?;
         debug!("file: set mode {:X}", perm.0);
         inode.1.i_mode = (inode.1.i_mode & 0o70000) | perm.0;
-        fs.write_inode(&mut inode).map_err(wrap_report)?;
+        inode.stamp(ExtInodeTimes::CTIME, fs.now_time());
+        fs.write_file_inode(&self.file, &mut inode)
+            .map_err(wrap_report)?;
         Ok(())
     }
 
@@ -967,6 +1079,76 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_in_memory_facades_work() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+
+        {
+            let facade = ExtFacadeFloppyDisk::create_in_memory(&image, None)?;
+            facade.write("/hello.txt", "hello flail!").await?;
+        }
+
+        let reopened = ExtMemoryImage::from(image.to_vec());
+        let facade = ExtFacadeFloppyDisk::open_in_memory(&reopened)?;
+        assert_eq!("hello flail!", facade.read_to_string("/hello.txt").await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_facade_xattrs_work() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let facade = ExtFacadeFloppyDisk::create_in_memory(
+            &image,
+            Some(ExtMkfsOptions::new().inode_size(256)),
+        )?;
+        facade.write("/hello.txt", "hello flail!").await?;
+
+        facade
+            .set_xattr("/hello.txt", "user.greeting", b"hi")
+            .await?;
+        assert_eq!(
+            vec!["user.greeting".to_string()],
+            facade.list_xattrs("/hello.txt").await?
+        );
+        assert_eq!(
+            Some(b"hi".to_vec()),
+            facade.get_xattr("/hello.txt", "user.greeting").await?
+        );
+        facade.remove_xattr("/hello.txt", "user.greeting").await?;
+        assert!(facade.list_xattrs("/hello.txt").await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_facade_creation_times_work() -> Result<()> {
+        for inode_size in [128, 256] {
+            let image = ExtMemoryImage::new(16 * 1024 * 1024);
+            let facade = ExtFacadeFloppyDisk::create_in_memory(
+                &image,
+                Some(ExtMkfsOptions::new().inode_size(inode_size)),
+            )?;
+            let before = SystemTime::now() - std::time::Duration::from_secs(60);
+            facade.write("/hello.txt", "hello flail!").await?;
+
+            let metadata = facade.metadata("/hello.txt").await?;
+            assert!(metadata.modified()? > before);
+            match metadata.created() {
+                Ok(created) => {
+                    assert_eq!(256, inode_size);
+                    assert!(created > before);
+                }
+                Err(err) => {
+                    assert_eq!(128, inode_size);
+                    assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_async_write_large_copy() -> Result<()> {
         {
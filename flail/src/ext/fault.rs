@@ -0,0 +1,403 @@
+use std::ops::Range;
+use std::sync::Mutex;
+
+use super::*;
+
+/// Which operations an [`IoFaults`] rule applies to.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum IoFaultOp {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl IoFaultOp {
+    fn covers(&self, op: IoFaultOp) -> bool {
+        *self == IoFaultOp::ReadWrite || *self == op
+    }
+}
+
+#[derive(Clone, Debug)]
+struct RangeFault {
+    range: Range<u64>,
+    op: IoFaultOp,
+    errno: i32,
+}
+
+#[derive(Clone, Debug)]
+struct TornWrite {
+    nth: u64,
+    keep: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct FaultState {
+    reads: u64,
+    writes: u64,
+    dropped_writes: u64,
+    fail_read: Option<(u64, i32)>,
+    fail_write: Option<(u64, i32)>,
+    ranges: Vec<RangeFault>,
+    torn_write: Option<TornWrite>,
+    power_cut_at: Option<u64>,
+}
+
+/// The faults a [`FaultyIoBackend`] injects, for testing how flail copes
+/// with a failing device.
+///
+/// Clones share the same state, so keep one around to arm faults and read
+/// counters while a filesystem uses the backend. Reads and writes are
+/// counted from 1, and `fail_nth_*` and `tear_nth_write` count from when
+/// they're called. Failures are returned as `std::io::Error`s with an errno,
+/// which libe2fs passes back up to flail.
+#[derive(Clone, Debug, Default)]
+pub struct IoFaults(Arc<Mutex<FaultState>>);
+
+impl IoFaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the `n`th read from now with `EIO`, once.
+    pub fn fail_nth_read(&self, n: u64) -> &Self {
+        let mut state = self.0.lock().unwrap();
+        state.fail_read = Some((state.reads + n, libc::EIO));
+        self
+    }
+
+    /// Fails the `n`th write from now with `EIO`, once. Nothing is written.
+    pub fn fail_nth_write(&self, n: u64) -> &Self {
+        let mut state = self.0.lock().unwrap();
+        state.fail_write = Some((state.writes + n, libc::EIO));
+        self
+    }
+
+    /// Fails every `op` touching the bytes in `range` with `errno`, such as
+    /// `libc::ENOSPC` or `libc::EIO`, until [`Self::clear`] is called.
+    pub fn fail_range(&self, range: Range<u64>, op: IoFaultOp, errno: i32) -> &Self {
+        self.0
+            .lock()
+            .unwrap()
+            .ranges
+            .push(RangeFault { range, op, errno });
+        self
+    }
+
+    /// Tears the `n`th write from now: only its first `keep` bytes land
+    /// before it fails with `EIO`.
+    pub fn tear_nth_write(&self, n: u64, keep: usize) -> &Self {
+        let mut state = self.0.lock().unwrap();
+        state.torn_write = Some(TornWrite {
+            nth: state.writes + n,
+            keep,
+        });
+        self
+    }
+
+    /// Cuts the power now. Every write from here on claims to succeed, but
+    /// is dropped, as if the machine died before it reached the disk.
+    pub fn power_cut(&self) -> &Self {
+        self.power_cut_after(0)
+    }
+
+    /// Cuts the power after `n` more writes have landed.
+    pub fn power_cut_after(&self, n: u64) -> &Self {
+        let mut state = self.0.lock().unwrap();
+        state.power_cut_at = Some(state.writes + n);
+        self
+    }
+
+    /// Disarms every fault and restores the power. Counters are kept.
+    pub fn clear(&self) -> &Self {
+        let mut state = self.0.lock().unwrap();
+        *state = FaultState {
+            reads: state.reads,
+            writes: state.writes,
+            dropped_writes: state.dropped_writes,
+            ..Default::default()
+        };
+        self
+    }
+
+    /// How many reads have been attempted, including failed ones.
+    pub fn reads(&self) -> u64 {
+        self.0.lock().unwrap().reads
+    }
+
+    /// How many writes have been attempted, including failed and dropped
+    /// ones.
+    pub fn writes(&self) -> u64 {
+        self.0.lock().unwrap().writes
+    }
+
+    /// How many writes were dropped since the power was cut.
+    pub fn dropped_writes(&self) -> u64 {
+        self.0.lock().unwrap().dropped_writes
+    }
+
+    pub fn is_power_cut(&self) -> bool {
+        let state = self.0.lock().unwrap();
+        matches!(state.power_cut_at, Some(at) if state.writes >= at)
+    }
+}
+
+impl FaultState {
+    fn range_fault(&self, op: IoFaultOp, offset: u64, len: u64) -> Option<i32> {
+        let end = offset + len;
+        self.ranges
+            .iter()
+            .find(|fault| {
+                fault.op.covers(op) && fault.range.start < end && offset < fault.range.end
+            })
+            .map(|fault| fault.errno)
+    }
+}
+
+fn fault(errno: i32) -> eyre::Report {
+    std::io::Error::from_raw_os_error(errno).into()
+}
+
+/// Wraps another [`IoBackend`], failing reads and writes as its
+/// [`IoFaults`] says.
+pub struct FaultyIoBackend<B: IoBackend> {
+    inner: B,
+    faults: IoFaults,
+}
+
+impl<B: IoBackend> FaultyIoBackend<B> {
+    pub fn new(inner: B, faults: IoFaults) -> Self {
+        Self { inner, faults }
+    }
+
+    pub fn faults(&self) -> &IoFaults {
+        &self.faults
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: IoBackend> IoBackend for FaultyIoBackend<B> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        {
+            let mut state = self.faults.0.lock().unwrap();
+            state.reads += 1;
+            if let Some((nth, errno)) = state.fail_read {
+                if state.reads == nth {
+                    state.fail_read = None;
+                    debug!("fault: failing read #{nth} at {offset}");
+                    return Err(fault(errno));
+                }
+            }
+            if let Some(errno) = state.range_fault(IoFaultOp::Read, offset, buf.len() as u64) {
+                debug!("fault: failing read of {} bytes at {offset}", buf.len());
+                return Err(fault(errno));
+            }
+        }
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut state = self.faults.0.lock().unwrap();
+        state.writes += 1;
+        if matches!(state.power_cut_at, Some(at) if state.writes > at) {
+            state.dropped_writes += 1;
+            return Ok(());
+        }
+        if let Some((nth, errno)) = state.fail_write {
+            if state.writes == nth {
+                state.fail_write = None;
+                debug!("fault: failing write #{nth} at {offset}");
+                return Err(fault(errno));
+            }
+        }
+        if let Some(errno) = state.range_fault(IoFaultOp::Write, offset, buf.len() as u64) {
+            debug!("fault: failing write of {} bytes at {offset}", buf.len());
+            return Err(fault(errno));
+        }
+        if let Some(torn) = state.torn_write.clone() {
+            if state.writes == torn.nth {
+                state.torn_write = None;
+                drop(state);
+                debug!(
+                    "fault: tearing write of {} bytes at {offset} after {} bytes",
+                    buf.len(),
+                    torn.keep
+                );
+                self.inner
+                    .write_at(offset, &buf[..torn.keep.min(buf.len())])?;
+                return Err(fault(libc::EIO));
+            }
+        }
+        drop(state);
+        self.inner.write_at(offset, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.faults.is_power_cut() {
+            return Ok(());
+        }
+        self.inner.flush()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.faults.is_power_cut() {
+            return Ok(());
+        }
+        if let Some(errno) =
+            self.faults
+                .0
+                .lock()
+                .unwrap()
+                .range_fault(IoFaultOp::Write, offset, len)
+        {
+            return Err(fault(errno));
+        }
+        self.inner.discard(offset, len)
+    }
+
+    fn zeroout(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.faults.is_power_cut() {
+            return Ok(());
+        }
+        if let Some(errno) =
+            self.faults
+                .0
+                .lock()
+                .unwrap()
+                .range_fault(IoFaultOp::Write, offset, len)
+        {
+            return Err(fault(errno));
+        }
+        self.inner.zeroout(offset, len)
+    }
+
+    fn set_blksize(&mut self, block_size: u32) -> Result<()> {
+        self.inner.set_blksize(block_size)
+    }
+
+    fn set_option(&mut self, key: &str, value: &str) -> Result<()> {
+        self.inner.set_option(key, value)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.inner.set_len(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    fn faulty_filesystem() -> Result<(ExtMemoryImage, IoFaults, ExtFilesystem)> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        ExtFilesystem::create_in_memory(&image, None)?;
+
+        let faults = IoFaults::new();
+        let fs = ExtFilesystem::open_with_io(
+            FaultyIoBackend::new(image.clone(), faults.clone()),
+            ExtOpenOptions::new()
+                .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        Ok((image, faults, fs))
+    }
+
+    fn assert_image_fsck_clean(image: &ExtMemoryImage) -> Result<()> {
+        let temp = TempDir::new()?;
+        let path = temp.path_view().join("image.ext4");
+        fs::write(&path, image.to_vec())?;
+        assert_fsck_clean(&path)
+    }
+
+    #[test]
+    pub fn test_failing_reads_surface_as_errors() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        ExtFilesystem::create_in_memory(&image, None)?;
+
+        let faults = IoFaults::new();
+        faults.fail_nth_read(1);
+        let err = ExtFilesystem::open_with_io(
+            FaultyIoBackend::new(image.clone(), faults.clone()),
+            ExtOpenOptions::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExtError>(),
+            Some(ExtError::EIO)
+        ));
+        assert_eq!(1, faults.reads());
+
+        // the fault only fires once
+        ExtFilesystem::open_with_io(FaultyIoBackend::new(image, faults), ExtOpenOptions::new())?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_failing_writes_surface_as_errors() -> Result<()> {
+        let (image, faults, fs) = faulty_filesystem()?;
+        let before = image.to_vec();
+
+        faults.fail_range(0..image.len(), IoFaultOp::Write, libc::ENOSPC);
+        let err = fs
+            .write_to_file("/test.txt", "hello flail".as_bytes())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExtError>(),
+            Some(ExtError::ENOSPC)
+        ));
+
+        // dropping still fails to write, but mustn't panic or leave anything
+        // half-written behind
+        drop(fs);
+        assert_eq!(before, image.to_vec());
+        assert_image_fsck_clean(&image)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_torn_writes_surface_as_errors() -> Result<()> {
+        let (image, faults, fs) = faulty_filesystem()?;
+
+        faults.tear_nth_write(1, 512);
+        let err = fs
+            .write_to_file("/test.txt", "hello flail".as_bytes())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ExtError>(),
+            Some(ExtError::EIO)
+        ));
+
+        faults.clear();
+        drop(fs);
+        let fs = ExtFilesystem::open_in_memory(&image, None)?;
+        drop(fs);
+        assert_image_fsck_clean(&image)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_power_cuts_drop_writes() -> Result<()> {
+        let (image, faults, fs) = faulty_filesystem()?;
+        let before = image.to_vec();
+
+        faults.power_cut();
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        drop(fs);
+        assert!(faults.dropped_writes() > 0);
+        assert_eq!(before, image.to_vec());
+
+        let fs = ExtFilesystem::open_in_memory(&image, None)?;
+        assert!(fs.find_inode("/test.txt").is_err());
+        drop(fs);
+        assert_image_fsck_clean(&image)?;
+
+        Ok(())
+    }
+}
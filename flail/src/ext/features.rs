@@ -0,0 +1,345 @@
+use super::*;
+
+bitflags! {
+    /// Features a kernel that doesn't know them can still read and write.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ExtCompatFeatures: u32 {
+        const DIR_PREALLOC = libe2fs_sys::EXT2_FEATURE_COMPAT_DIR_PREALLOC;
+        const IMAGIC_INODES = libe2fs_sys::EXT2_FEATURE_COMPAT_IMAGIC_INODES;
+        const HAS_JOURNAL = libe2fs_sys::EXT3_FEATURE_COMPAT_HAS_JOURNAL;
+        const EXT_ATTR = libe2fs_sys::EXT2_FEATURE_COMPAT_EXT_ATTR;
+        const RESIZE_INODE = libe2fs_sys::EXT2_FEATURE_COMPAT_RESIZE_INODE;
+        const DIR_INDEX = libe2fs_sys::EXT2_FEATURE_COMPAT_DIR_INDEX;
+        const LAZY_BG = libe2fs_sys::EXT2_FEATURE_COMPAT_LAZY_BG;
+        const EXCLUDE_BITMAP = libe2fs_sys::EXT2_FEATURE_COMPAT_EXCLUDE_BITMAP;
+        const SPARSE_SUPER2 = libe2fs_sys::EXT4_FEATURE_COMPAT_SPARSE_SUPER2;
+        const FAST_COMMIT = libe2fs_sys::EXT4_FEATURE_COMPAT_FAST_COMMIT;
+        const STABLE_INODES = libe2fs_sys::EXT4_FEATURE_COMPAT_STABLE_INODES;
+        const ORPHAN_FILE = libe2fs_sys::EXT4_FEATURE_COMPAT_ORPHAN_FILE;
+    }
+
+    /// Features a kernel must understand to mount the filesystem at all.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ExtIncompatFeatures: u32 {
+        const COMPRESSION = libe2fs_sys::EXT2_FEATURE_INCOMPAT_COMPRESSION;
+        const FILETYPE = libe2fs_sys::EXT2_FEATURE_INCOMPAT_FILETYPE;
+        const RECOVER = libe2fs_sys::EXT3_FEATURE_INCOMPAT_RECOVER;
+        const JOURNAL_DEV = libe2fs_sys::EXT3_FEATURE_INCOMPAT_JOURNAL_DEV;
+        const META_BG = libe2fs_sys::EXT2_FEATURE_INCOMPAT_META_BG;
+        const EXTENTS = libe2fs_sys::EXT3_FEATURE_INCOMPAT_EXTENTS;
+        const BIT64 = libe2fs_sys::EXT4_FEATURE_INCOMPAT_64BIT;
+        const MMP = libe2fs_sys::EXT4_FEATURE_INCOMPAT_MMP;
+        const FLEX_BG = libe2fs_sys::EXT4_FEATURE_INCOMPAT_FLEX_BG;
+        const EA_INODE = libe2fs_sys::EXT4_FEATURE_INCOMPAT_EA_INODE;
+        const DIRDATA = libe2fs_sys::EXT4_FEATURE_INCOMPAT_DIRDATA;
+        const CSUM_SEED = libe2fs_sys::EXT4_FEATURE_INCOMPAT_CSUM_SEED;
+        const LARGEDIR = libe2fs_sys::EXT4_FEATURE_INCOMPAT_LARGEDIR;
+        const INLINE_DATA = libe2fs_sys::EXT4_FEATURE_INCOMPAT_INLINE_DATA;
+        const ENCRYPT = libe2fs_sys::EXT4_FEATURE_INCOMPAT_ENCRYPT;
+        const CASEFOLD = libe2fs_sys::EXT4_FEATURE_INCOMPAT_CASEFOLD;
+    }
+
+    /// Features a kernel must understand to mount the filesystem read-write.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ExtRoCompatFeatures: u32 {
+        const SPARSE_SUPER = libe2fs_sys::EXT2_FEATURE_RO_COMPAT_SPARSE_SUPER;
+        const LARGE_FILE = libe2fs_sys::EXT2_FEATURE_RO_COMPAT_LARGE_FILE;
+        const HUGE_FILE = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_HUGE_FILE;
+        const GDT_CSUM = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_GDT_CSUM;
+        const DIR_NLINK = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_DIR_NLINK;
+        const EXTRA_ISIZE = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE;
+        const HAS_SNAPSHOT = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_HAS_SNAPSHOT;
+        const QUOTA = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_QUOTA;
+        const BIGALLOC = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_BIGALLOC;
+        const METADATA_CSUM = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_METADATA_CSUM;
+        const REPLICA = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_REPLICA;
+        const READONLY = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_READONLY;
+        const PROJECT = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_PROJECT;
+        const SHARED_BLOCKS = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_SHARED_BLOCKS;
+        const VERITY = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_VERITY;
+        const ORPHAN_PRESENT = libe2fs_sys::EXT4_FEATURE_RO_COMPAT_ORPHAN_PRESENT;
+    }
+}
+
+/// The full set of features of a filesystem, as stored in its superblock.
+/// Bits flail doesn't know about are kept, so that they can be reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ExtFeatures {
+    pub compat: ExtCompatFeatures,
+    pub incompat: ExtIncompatFeatures,
+    pub ro_compat: ExtRoCompatFeatures,
+}
+
+impl Default for ExtFeatures {
+    /// What [`ExtFilesystem::create`] turns on unless told otherwise.
+    fn default() -> Self {
+        Self {
+            // needed to carry xattrs over from the host when populating
+            compat: ExtCompatFeatures::EXT_ATTR,
+            incompat: ExtIncompatFeatures::BIT64 | ExtIncompatFeatures::EXTENTS,
+            ro_compat: ExtRoCompatFeatures::LARGE_FILE
+                | ExtRoCompatFeatures::HUGE_FILE
+                | ExtRoCompatFeatures::DIR_NLINK,
+        }
+    }
+}
+
+impl ExtFeatures {
+    pub fn empty() -> Self {
+        Self {
+            compat: ExtCompatFeatures::empty(),
+            incompat: ExtIncompatFeatures::empty(),
+            ro_compat: ExtRoCompatFeatures::empty(),
+        }
+    }
+
+    pub fn from_superblock(superblock: &libe2fs_sys::ext2_super_block) -> Self {
+        Self {
+            compat: ExtCompatFeatures::from_bits_retain(superblock.s_feature_compat),
+            incompat: ExtIncompatFeatures::from_bits_retain(superblock.s_feature_incompat),
+            ro_compat: ExtRoCompatFeatures::from_bits_retain(superblock.s_feature_ro_compat),
+        }
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.compat.insert(other.compat);
+        self.incompat.insert(other.incompat);
+        self.ro_compat.insert(other.ro_compat);
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.compat.remove(other.compat);
+        self.incompat.remove(other.incompat);
+        self.ro_compat.remove(other.ro_compat);
+    }
+
+    /// Applies a list of feature edits in the style of mke2fs' `-O`, eg.
+    /// `has_journal,^resize_inode`. Names are separated by commas or
+    /// whitespace, and a leading `^` turns a feature off instead of on.
+    pub fn edit(&mut self, edits: &str) -> Result<()> {
+        let (enable, disable) = Self::parse_edits(edits)?;
+        self.remove(disable);
+        self.insert(enable);
+        Ok(())
+    }
+
+    /// Splits a list of feature edits into the features it turns on and the
+    /// ones it turns off, see [`Self::edit`].
+    pub(crate) fn parse_edits(edits: &str) -> Result<(Self, Self)> {
+        let mut enable = Self::empty();
+        let mut disable = Self::empty();
+        for edit in edits
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|edit| !edit.is_empty())
+        {
+            let (name, on) = match edit.strip_prefix(['^', '-']) {
+                Some(name) => (name, false),
+                None => (edit.strip_prefix('+').unwrap_or(edit), true),
+            };
+            let feature =
+                Self::named(name).ok_or_else(|| eyre!("unknown filesystem feature {name:?}"))?;
+            if on {
+                disable.remove(feature);
+                enable.insert(feature);
+            } else {
+                enable.remove(feature);
+                disable.insert(feature);
+            }
+        }
+        Ok((enable, disable))
+    }
+
+    /// Looks a single feature up by the name e2fsprogs gives it.
+    pub fn named(name: &str) -> Option<Self> {
+        let mut out = Self::empty();
+        match name.to_ascii_lowercase().as_str() {
+            "dir_prealloc" => out.compat = ExtCompatFeatures::DIR_PREALLOC,
+            "imagic_inodes" => out.compat = ExtCompatFeatures::IMAGIC_INODES,
+            "has_journal" => out.compat = ExtCompatFeatures::HAS_JOURNAL,
+            "ext_attr" => out.compat = ExtCompatFeatures::EXT_ATTR,
+            "resize_inode" => out.compat = ExtCompatFeatures::RESIZE_INODE,
+            "dir_index" => out.compat = ExtCompatFeatures::DIR_INDEX,
+            "lazy_bg" => out.compat = ExtCompatFeatures::LAZY_BG,
+            "snapshot_bitmap" => out.compat = ExtCompatFeatures::EXCLUDE_BITMAP,
+            "sparse_super2" => out.compat = ExtCompatFeatures::SPARSE_SUPER2,
+            "fast_commit" => out.compat = ExtCompatFeatures::FAST_COMMIT,
+            "stable_inodes" => out.compat = ExtCompatFeatures::STABLE_INODES,
+            "orphan_file" => out.compat = ExtCompatFeatures::ORPHAN_FILE,
+
+            "compression" => out.incompat = ExtIncompatFeatures::COMPRESSION,
+            "filetype" => out.incompat = ExtIncompatFeatures::FILETYPE,
+            "needs_recovery" => out.incompat = ExtIncompatFeatures::RECOVER,
+            "journal_dev" => out.incompat = ExtIncompatFeatures::JOURNAL_DEV,
+            "meta_bg" => out.incompat = ExtIncompatFeatures::META_BG,
+            "extent" | "extents" => out.incompat = ExtIncompatFeatures::EXTENTS,
+            "64bit" => out.incompat = ExtIncompatFeatures::BIT64,
+            "mmp" => out.incompat = ExtIncompatFeatures::MMP,
+            "flex_bg" => out.incompat = ExtIncompatFeatures::FLEX_BG,
+            "ea_inode" => out.incompat = ExtIncompatFeatures::EA_INODE,
+            "dirdata" => out.incompat = ExtIncompatFeatures::DIRDATA,
+            "metadata_csum_seed" => out.incompat = ExtIncompatFeatures::CSUM_SEED,
+            "large_dir" => out.incompat = ExtIncompatFeatures::LARGEDIR,
+            "inline_data" => out.incompat = ExtIncompatFeatures::INLINE_DATA,
+            "encrypt" => out.incompat = ExtIncompatFeatures::ENCRYPT,
+            "casefold" | "fname_encoding" => out.incompat = ExtIncompatFeatures::CASEFOLD,
+
+            "sparse_super" => out.ro_compat = ExtRoCompatFeatures::SPARSE_SUPER,
+            "large_file" => out.ro_compat = ExtRoCompatFeatures::LARGE_FILE,
+            "huge_file" => out.ro_compat = ExtRoCompatFeatures::HUGE_FILE,
+            "uninit_bg" | "gdt_csum" => out.ro_compat = ExtRoCompatFeatures::GDT_CSUM,
+            "dir_nlink" => out.ro_compat = ExtRoCompatFeatures::DIR_NLINK,
+            "extra_isize" => out.ro_compat = ExtRoCompatFeatures::EXTRA_ISIZE,
+            "snapshot" => out.ro_compat = ExtRoCompatFeatures::HAS_SNAPSHOT,
+            "quota" => out.ro_compat = ExtRoCompatFeatures::QUOTA,
+            "bigalloc" => out.ro_compat = ExtRoCompatFeatures::BIGALLOC,
+            "metadata_csum" => out.ro_compat = ExtRoCompatFeatures::METADATA_CSUM,
+            "replica" => out.ro_compat = ExtRoCompatFeatures::REPLICA,
+            "read-only" => out.ro_compat = ExtRoCompatFeatures::READONLY,
+            "project" => out.ro_compat = ExtRoCompatFeatures::PROJECT,
+            "shared_blocks" => out.ro_compat = ExtRoCompatFeatures::SHARED_BLOCKS,
+            "verity" => out.ro_compat = ExtRoCompatFeatures::VERITY,
+            "orphan_present" => out.ro_compat = ExtRoCompatFeatures::ORPHAN_PRESENT,
+            _ => return None,
+        }
+        Some(out)
+    }
+
+    pub fn union(&self, other: Self) -> Self {
+        Self {
+            compat: self.compat.union(other.compat),
+            incompat: self.incompat.union(other.incompat),
+            ro_compat: self.ro_compat.union(other.ro_compat),
+        }
+    }
+
+    /// The features in `self` that aren't in `other`.
+    pub fn difference(&self, other: Self) -> Self {
+        Self {
+            compat: self.compat.difference(other.compat),
+            incompat: self.incompat.difference(other.incompat),
+            ro_compat: self.ro_compat.difference(other.ro_compat),
+        }
+    }
+
+    /// Whether every feature in `other` is also in `self`.
+    pub fn contains(&self, other: Self) -> bool {
+        self.compat.contains(other.compat)
+            && self.incompat.contains(other.incompat)
+            && self.ro_compat.contains(other.ro_compat)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.compat.is_empty() && self.incompat.is_empty() && self.ro_compat.is_empty()
+    }
+
+    /// Features that flail, or the parts of libe2fs it relies on, doesn't
+    /// keep up to date when writing. Writing to a filesystem with any of
+    /// these may corrupt it, or leave it needing fsck. Bits flail doesn't
+    /// know about are always included.
+    pub fn unsupported(&self) -> Self {
+        Self {
+            compat: self.compat.difference(ExtCompatFeatures::all()),
+            incompat: self.incompat.difference(ExtIncompatFeatures::all())
+                | self.incompat.intersection(
+                    ExtIncompatFeatures::COMPRESSION
+                        | ExtIncompatFeatures::RECOVER
+                        | ExtIncompatFeatures::JOURNAL_DEV
+                        | ExtIncompatFeatures::MMP
+                        | ExtIncompatFeatures::EA_INODE
+                        | ExtIncompatFeatures::DIRDATA
+                        | ExtIncompatFeatures::ENCRYPT
+                        | ExtIncompatFeatures::CASEFOLD,
+                ),
+            ro_compat: self.ro_compat.difference(ExtRoCompatFeatures::all())
+                | self.ro_compat.intersection(
+                    ExtRoCompatFeatures::HAS_SNAPSHOT
+                        | ExtRoCompatFeatures::QUOTA
+                        | ExtRoCompatFeatures::BIGALLOC
+                        | ExtRoCompatFeatures::REPLICA
+                        | ExtRoCompatFeatures::READONLY
+                        | ExtRoCompatFeatures::PROJECT
+                        | ExtRoCompatFeatures::SHARED_BLOCKS
+                        | ExtRoCompatFeatures::VERITY
+                        | ExtRoCompatFeatures::ORPHAN_PRESENT,
+                ),
+        }
+    }
+
+    /// Rejects combinations of features that can't exist on a valid
+    /// filesystem with the given block and group descriptor size.
+    pub fn validate(&self, block_size: u32, desc_size: u16) -> Result<()> {
+        if self.incompat.contains(ExtIncompatFeatures::META_BG)
+            && self.compat.contains(ExtCompatFeatures::RESIZE_INODE)
+        {
+            return Err(eyre!("meta_bg and resize_inode can't be used together"));
+        }
+
+        if self.incompat.contains(ExtIncompatFeatures::BIT64)
+            && (desc_size < libe2fs_sys::EXT2_MIN_DESC_SIZE_64BIT as u16
+                || !desc_size.is_power_of_two()
+                || desc_size as u32 > libe2fs_sys::EXT2_MAX_DESC_SIZE
+                || desc_size as u32 > block_size)
+        {
+            return Err(eyre!(
+                "64bit needs a group descriptor size that's a power of two between {} and {}, not {desc_size}",
+                libe2fs_sys::EXT2_MIN_DESC_SIZE_64BIT,
+                libe2fs_sys::EXT2_MAX_DESC_SIZE
+            ));
+        }
+
+        if self.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM)
+            && self.ro_compat.contains(ExtRoCompatFeatures::GDT_CSUM)
+        {
+            return Err(eyre!(
+                "metadata_csum supersedes gdt_csum, they can't be used together"
+            ));
+        }
+
+        if self.incompat.contains(ExtIncompatFeatures::CSUM_SEED)
+            && !self.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM)
+        {
+            return Err(eyre!("metadata_csum_seed requires metadata_csum"));
+        }
+
+        if self.ro_compat.contains(ExtRoCompatFeatures::BIGALLOC)
+            && !self.incompat.contains(ExtIncompatFeatures::EXTENTS)
+        {
+            return Err(eyre!("bigalloc requires extents"));
+        }
+
+        // block maps can't address past 2^32 blocks
+        if self.incompat.contains(ExtIncompatFeatures::BIT64)
+            && !self.incompat.contains(ExtIncompatFeatures::EXTENTS)
+        {
+            return Err(eyre!("64bit requires extents"));
+        }
+
+        Ok(())
+    }
+}
+
+impl ExtFilesystem {
+    /// The features this filesystem uses.
+    pub fn features(&self) -> ExtFeatures {
+        let fs = *self.0.read().unwrap();
+        ExtFeatures::from_superblock(unsafe { &*(*fs).super_ })
+    }
+
+    /// The features this filesystem uses that flail can't safely write, see
+    /// [`ExtFeatures::unsupported`].
+    pub fn unsupported_features(&self) -> ExtFeatures {
+        self.features().unsupported()
+    }
+
+    pub(crate) fn report_features(&self) {
+        let features = self.features();
+        debug!("{:?} uses features {features:?}", self.1);
+        let unsupported = features.unsupported();
+        if !unsupported.is_empty() {
+            warn!(
+                "{:?} uses features flail can't safely write: {unsupported:?}",
+                self.1
+            );
+        }
+    }
+}
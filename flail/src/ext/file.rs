@@ -15,7 +15,7 @@ impl Drop for ExtFile {
             let res =
                 unsafe { libe2fs_sys::ext2fs_file_close(file as *mut libe2fs_sys::ext2_file) };
             if res != 0 {
-                panic!("{:#?}", Err::<(), ExtError>((res as u32).into()));
+                error!("failed to close file on drop: {:?}", report::<()>(res));
             }
             debug!("dropped!");
         }
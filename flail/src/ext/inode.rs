@@ -1,11 +1,107 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use super::*;
 
+/// The low bits of a `*_extra` timestamp field extend the seconds past 2038,
+/// the rest are nanoseconds.
+const EXT4_EPOCH_BITS: u32 = 2;
+const EXT4_EPOCH_MASK: u32 = (1 << EXT4_EPOCH_BITS) - 1;
+
+/// The latest time the extra epoch bits can reach, in 2446.
+const EXT4_EXTRA_TIMESTAMP_MAX: i64 = i32::MAX as i64 + ((EXT4_EPOCH_MASK as i64) << 32);
+
+/// Where the fields past the 128-byte `ext2_inode` start.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const I_CTIME_EXTRA: usize = 0x84;
+const I_MTIME_EXTRA: usize = 0x88;
+const I_ATIME_EXTRA: usize = 0x8c;
+const I_CRTIME: usize = 0x90;
+const I_CRTIME_EXTRA: usize = 0x94;
+
+bitflags! {
+    /// Which of an inode's timestamps an operation changes.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub(crate) struct ExtInodeTimes: u8 {
+        const ATIME = 1 << 0;
+        const MTIME = 1 << 1;
+        const CTIME = 1 << 2;
+        const CRTIME = 1 << 3;
+    }
+}
+
+/// An inode, read in full from disk.
+///
+/// The last field is the filesystem's block size if it has `huge_file`,
+/// since that changes what `i_blocks` counts.
 #[derive(Copy, Clone)]
-pub struct ExtInode(pub(crate) u32, pub(crate) libe2fs_sys::ext2_inode);
+pub struct ExtInode(
+    pub(crate) u32,
+    pub(crate) libe2fs_sys::ext2_inode_large,
+    pub(crate) Option<u32>,
+);
 
 impl ExtInode {
+    /// Reads inode `inum` from disk, including whatever fits of the large
+    /// inode.
+    pub(crate) unsafe fn read(fs: libe2fs_sys::ext2_filsys, inum: u32) -> Result<Self> {
+        let mut inode: libe2fs_sys::ext2_inode_large = std::mem::zeroed();
+        let err = libe2fs_sys::ext2fs_read_inode_full(
+            fs,
+            inum,
+            &mut inode as *mut _ as *mut libe2fs_sys::ext2_inode,
+            inode_buf_size(fs) as i32,
+        );
+        if err != 0 {
+            return report(err);
+        }
+        Ok(Self(inum, inode, huge_file_block_size(fs)))
+    }
+
+    /// Builds an inode that isn't on disk yet from a small `ext2_inode`,
+    /// with everything past it zeroed.
+    pub(crate) unsafe fn from_small(
+        fs: libe2fs_sys::ext2_filsys,
+        inum: u32,
+        small: &libe2fs_sys::ext2_inode,
+    ) -> Self {
+        let mut inode = Self(inum, std::mem::zeroed(), huge_file_block_size(fs));
+        std::ptr::copy_nonoverlapping(small, inode.as_small_mut(), 1);
+        inode
+    }
+
+    /// Builds an inode from a small `ext2_inode`, such as the copy an open
+    /// file keeps, with everything past it read from disk.
+    pub(crate) unsafe fn with_small(
+        fs: libe2fs_sys::ext2_filsys,
+        inum: u32,
+        small: &libe2fs_sys::ext2_inode,
+    ) -> Result<Self> {
+        let mut inode = Self::read(fs, inum)?;
+        std::ptr::copy_nonoverlapping(small, inode.as_small_mut(), 1);
+        Ok(inode)
+    }
+
+    /// Writes this inode back to disk, including whatever fits of the large
+    /// inode.
+    pub(crate) unsafe fn write(&mut self, fs: libe2fs_sys::ext2_filsys) -> Result<()> {
+        let err = libe2fs_sys::ext2fs_write_inode_full(
+            fs,
+            self.0,
+            self.as_small_mut(),
+            inode_buf_size(fs) as i32,
+        );
+        if err != 0 {
+            report(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The start of the inode, for libe2fs functions taking an `ext2_inode`.
+    pub(crate) fn as_small_mut(&mut self) -> *mut libe2fs_sys::ext2_inode {
+        &mut self.1 as *mut _ as *mut libe2fs_sys::ext2_inode
+    }
+
     pub fn num(&self) -> u32 {
         self.0
     }
@@ -43,29 +139,197 @@ impl ExtInode {
     }
 
     pub fn size(&self) -> u64 {
-        // TODO: This is wrong for 64-bit inodes...? What's the right containing struct? large inode?
-        self.1.i_size as u64
+        self.1.i_size as u64 | (self.1.i_size_high as u64) << 32
+    }
+
+    /// The space used by this inode in 512-byte units, like `st_blocks`.
+    pub fn blocks(&self) -> u64 {
+        let Some(block_size) = self.2 else {
+            return self.1.i_blocks as u64;
+        };
+        let blocks_hi = unsafe { self.1.osd2.linux2.l_i_blocks_hi };
+        let blocks = self.1.i_blocks as u64 | (blocks_hi as u64) << 32;
+        if self.1.i_flags & libe2fs_sys::EXT4_HUGE_FILE_FL != 0 {
+            blocks * (block_size as u64 / 512)
+        } else {
+            blocks
+        }
+    }
+
+    /// The owner's full 32-bit uid, including `l_i_uid_high`.
+    pub fn uid(&self) -> u32 {
+        let uid_high = unsafe { self.1.osd2.linux2.l_i_uid_high };
+        self.1.i_uid as u32 | (uid_high as u32) << 16
+    }
+
+    /// The owner's full 32-bit gid, including `l_i_gid_high`.
+    pub fn gid(&self) -> u32 {
+        let gid_high = unsafe { self.1.osd2.linux2.l_i_gid_high };
+        self.1.i_gid as u32 | (gid_high as u32) << 16
+    }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        self.1.i_uid = uid as u16;
+        unsafe { self.1.osd2.linux2.l_i_uid_high = (uid >> 16) as u16 };
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        self.1.i_gid = gid as u16;
+        unsafe { self.1.osd2.linux2.l_i_gid_high = (gid >> 16) as u16 };
+    }
+
+    pub fn links_count(&self) -> u16 {
+        self.1.i_links_count
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.1.i_generation
     }
 
     pub fn atime(&self) -> Result<SystemTime> {
-        let time = self.1.i_atime;
-        Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(time as u64))
+        Ok(self.time(self.1.i_atime, I_ATIME_EXTRA, self.1.i_atime_extra))
     }
 
     pub fn ctime(&self) -> Result<SystemTime> {
-        let time = self.1.i_ctime;
-        Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(time as u64))
+        Ok(self.time(self.1.i_ctime, I_CTIME_EXTRA, self.1.i_ctime_extra))
     }
 
     pub fn mtime(&self) -> Result<SystemTime> {
-        let time = self.1.i_mtime;
-        Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(time as u64))
+        Ok(self.time(self.1.i_mtime, I_MTIME_EXTRA, self.1.i_mtime_extra))
     }
 
     pub fn dtime(&self) -> Result<SystemTime> {
         let time = self.1.i_dtime;
-        Ok(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(time as u64))
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(time as u64))
+    }
+
+    /// When this inode was created. Fails if its inode is too small to
+    /// record that, such as on ext2 and ext3 filesystems with 128-byte
+    /// inodes.
+    pub fn crtime(&self) -> Result<SystemTime> {
+        if !self.has_extra(I_CRTIME) {
+            return Err(eyre!("inode {} has no creation time", self.0));
+        }
+        Ok(self.time(self.1.i_crtime, I_CRTIME_EXTRA, self.1.i_crtime_extra))
+    }
+
+    /// Sets the access time, down to the nanosecond if the inode has room
+    /// for it.
+    pub fn set_atime(&mut self, time: SystemTime) {
+        let (seconds, extra) = self.encode_time(time, I_ATIME_EXTRA);
+        self.1.i_atime = seconds;
+        self.1.i_atime_extra = extra;
     }
+
+    /// Sets the modification time, down to the nanosecond if the inode has
+    /// room for it.
+    pub fn set_mtime(&mut self, time: SystemTime) {
+        let (seconds, extra) = self.encode_time(time, I_MTIME_EXTRA);
+        self.1.i_mtime = seconds;
+        self.1.i_mtime_extra = extra;
+    }
+
+    /// Sets the change time, down to the nanosecond if the inode has room
+    /// for it.
+    pub fn set_ctime(&mut self, time: SystemTime) {
+        let (seconds, extra) = self.encode_time(time, I_CTIME_EXTRA);
+        self.1.i_ctime = seconds;
+        self.1.i_ctime_extra = extra;
+    }
+
+    /// Sets the creation time. Does nothing if the inode is too small to
+    /// record it.
+    pub fn set_crtime(&mut self, time: SystemTime) {
+        if !self.has_extra(I_CRTIME) {
+            return;
+        }
+        let (seconds, extra) = self.encode_time(time, I_CRTIME_EXTRA);
+        self.1.i_crtime = seconds;
+        self.1.i_crtime_extra = extra;
+    }
+
+    /// Sets each of `times` to `now`.
+    pub(crate) fn stamp(&mut self, times: ExtInodeTimes, now: SystemTime) {
+        if times.contains(ExtInodeTimes::ATIME) {
+            self.set_atime(now);
+        }
+        if times.contains(ExtInodeTimes::MTIME) {
+            self.set_mtime(now);
+        }
+        if times.contains(ExtInodeTimes::CTIME) {
+            self.set_ctime(now);
+        }
+        if times.contains(ExtInodeTimes::CRTIME) {
+            self.set_crtime(now);
+        }
+    }
+
+    /// Whether the 32-bit field at `offset` is inside `i_extra_isize`.
+    fn has_extra(&self, offset: usize) -> bool {
+        GOOD_OLD_INODE_SIZE + self.1.i_extra_isize as usize >= offset + 4
+    }
+
+    /// Decodes a timestamp the way the kernel does: the seconds are signed,
+    /// unless the `*_extra` field at `extra_offset` is present to extend
+    /// them.
+    fn time(&self, seconds: u32, extra_offset: usize, extra: u32) -> SystemTime {
+        let mut seconds = seconds as i32 as i64;
+        let mut nanos = 0;
+        if self.has_extra(extra_offset) {
+            seconds += ((extra & EXT4_EPOCH_MASK) as i64) << 32;
+            nanos = extra >> EXT4_EPOCH_BITS;
+        }
+        let time = if seconds < 0 {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+        } else {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64)
+        };
+        time + Duration::from_nanos(nanos as u64)
+    }
+
+    /// Encodes a timestamp the way the kernel does, clamping it to what fits
+    /// with or without the `*_extra` field at `extra_offset`.
+    fn encode_time(&self, time: SystemTime, extra_offset: usize) -> (u32, u32) {
+        let (seconds, nanos) = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => (
+                i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+                since.subsec_nanos(),
+            ),
+            Err(err) => {
+                let before = err.duration();
+                let seconds = -i64::try_from(before.as_secs()).unwrap_or(i64::MAX);
+                match before.subsec_nanos() {
+                    0 => (seconds, 0),
+                    nanos => (seconds - 1, 1_000_000_000 - nanos),
+                }
+            }
+        };
+
+        if !self.has_extra(extra_offset) {
+            let seconds = seconds.clamp(i32::MIN as i64, i32::MAX as i64);
+            return (seconds as u32, 0);
+        }
+        let seconds = seconds.clamp(i32::MIN as i64, EXT4_EXTRA_TIMESTAMP_MAX);
+        let epoch = ((seconds - seconds as i32 as i64) >> 32) as u32 & EXT4_EPOCH_MASK;
+        (seconds as u32, epoch | nanos << EXT4_EPOCH_BITS)
+    }
+}
+
+/// How much of an `ext2_inode_large` is on disk for `fs`.
+pub(crate) unsafe fn inode_buf_size(fs: libe2fs_sys::ext2_filsys) -> usize {
+    let superblock = (*fs).super_;
+    let inode_size = if (*superblock).s_rev_level == libe2fs_sys::EXT2_GOOD_OLD_REV {
+        GOOD_OLD_INODE_SIZE
+    } else {
+        (*superblock).s_inode_size as usize
+    };
+    inode_size.min(std::mem::size_of::<libe2fs_sys::ext2_inode_large>())
+}
+
+unsafe fn huge_file_block_size(fs: libe2fs_sys::ext2_filsys) -> Option<u32> {
+    let huge_file =
+        (*(*fs).super_).s_feature_ro_compat & libe2fs_sys::EXT4_FEATURE_RO_COMPAT_HUGE_FILE != 0;
+    huge_file.then(|| (*fs).blocksize)
 }
 
 // We don't implement Drop on the bitmaps because that fucks up a number of
@@ -88,3 +352,73 @@ impl ExtBitmap for ExtInodeBitmap {
             || bitmap.magic == libe2fs_sys::EXT2_ET_MAGIC_BLOCK_BITMAP64.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_full_inodes_are_read() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let fs =
+            ExtFilesystem::create_in_memory(&image, Some(ExtMkfsOptions::new().inode_size(256)))?;
+        let before = SystemTime::now() - Duration::from_secs(60);
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+
+        let mut inode = fs.find_inode("/test.txt")?;
+        assert_eq!(11, inode.size());
+        assert_eq!(1, inode.links_count());
+        assert!(inode.blocks() > 0);
+        assert!(inode.crtime()? > before);
+
+        // the kernel stores the low 32 bits of the seconds, and in the epoch
+        // bits how far the sign-extended low bits are off from the real time
+        let encode = |seconds: i64, nanos: u32| {
+            let epoch = ((seconds - seconds as i32 as i64) >> 32) as u32 & EXT4_EPOCH_MASK;
+            (seconds as u32, epoch | (nanos << EXT4_EPOCH_BITS))
+        };
+
+        // a time past 2038 and one before 1970, with nanoseconds
+        let mtime = 4_102_444_800i64;
+        let atime = -1_000_000_000i64;
+        (inode.1.i_mtime, inode.1.i_mtime_extra) = encode(mtime, 123_456_789);
+        (inode.1.i_atime, inode.1.i_atime_extra) = encode(atime, 5);
+        assert_eq!(1, inode.1.i_mtime_extra & EXT4_EPOCH_MASK);
+        assert_eq!(0, inode.1.i_atime_extra & EXT4_EPOCH_MASK);
+        inode.1.i_size_high = 1;
+        fs.write_inode(&mut inode)?;
+
+        let inode = fs.find_inode("/test.txt")?;
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::new(mtime as u64, 123_456_789),
+            inode.mtime()?
+        );
+        assert_eq!(
+            SystemTime::UNIX_EPOCH - Duration::from_secs(atime.unsigned_abs())
+                + Duration::from_nanos(5),
+            inode.atime()?
+        );
+        assert_eq!((1 << 32) + 11, inode.size());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_32bit_owners_work() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let fs = ExtFilesystem::create_in_memory(&image, None)?;
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+
+        let mut inode = fs.find_inode("/test.txt")?;
+        inode.set_uid(165_536);
+        inode.set_gid(0x1234_5678);
+        fs.write_inode(&mut inode)?;
+
+        let inode = fs.find_inode("/test.txt")?;
+        assert_eq!(165_536, inode.uid());
+        assert_eq!(0x1234_5678, inode.gid());
+        assert_eq!(165_536 & 0xffff, inode.1.i_uid as u32);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,9 @@
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
 use super::*;
 
 pub struct IoChannel(pub(crate) libe2fs_sys::io_channel);
@@ -53,6 +59,16 @@ impl IoChannel {
         unsafe { (*self.0).refcount }
     }
 
+    /// How many bytes this channel has read and written so far.
+    pub fn stats(&self) -> Result<IoStats> {
+        unsafe {
+            let Some(get_stats_fn) = (*(*self.0).manager).get_stats else {
+                return Err(eyre!("this io channel doesn't keep stats"));
+            };
+            read_stats(get_stats_fn, self.0)
+        }
+    }
+
     pub fn flags(&self) -> i32 {
         unsafe { (*self.0).flags }
     }
@@ -94,6 +110,13 @@ impl IoChannel {
 pub struct IoManager(pub(crate) Arc<RwLock<libe2fs_sys::struct_io_manager>>);
 
 impl IoManager {
+    /// The manager to hand to libe2fs. The manager lives as long as the
+    /// `IoManager` does, so the pointer stays valid after the lock is
+    /// released, and libe2fs may call back into us without deadlocking.
+    pub(crate) fn as_ptr(&self) -> *mut libe2fs_sys::struct_io_manager {
+        &mut *self.0.write().unwrap()
+    }
+
     pub fn name(&self) -> Result<String> {
         unsafe {
             let io_manager = (*self.0).read().unwrap();
@@ -209,13 +232,7 @@ impl IoManager {
         self.with_io_manager_manual(|io_manager| {
             // SAFETY: can never be None because otherwise libe2fs is broken
             let get_stats_fn = io_manager.get_stats.unwrap();
-            let io_stats = std::ptr::null_mut();
-            let res = unsafe { get_stats_fn(io_channel.0, io_stats) };
-            if res == 0 {
-                Ok(IoStats(unsafe { **io_stats }))
-            } else {
-                report(res)
-            }
+            unsafe { read_stats(get_stats_fn, io_channel.0) }
         })
     }
 
@@ -302,6 +319,27 @@ impl IoManager {
     }
 }
 
+/// Calls an io manager's `get_stats`, which hands back a pointer to stats
+/// owned by the channel.
+unsafe fn read_stats(
+    get_stats_fn: unsafe extern "C" fn(
+        libe2fs_sys::io_channel,
+        *mut libe2fs_sys::io_stats,
+    ) -> libe2fs_sys::errcode_t,
+    io_channel: libe2fs_sys::io_channel,
+) -> Result<IoStats> {
+    let mut io_stats: libe2fs_sys::io_stats = std::ptr::null_mut();
+    let res = get_stats_fn(io_channel, &mut io_stats);
+    if res != 0 {
+        return report(res);
+    }
+    if io_stats.is_null() {
+        return Err(eyre!("io channel returned no stats"));
+    }
+    Ok(IoStats(*io_stats))
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct IoStats(libe2fs_sys::struct_io_stats);
 
 impl IoStats {
@@ -321,3 +359,400 @@ impl IoStats {
         self.0.bytes_written
     }
 }
+
+/// A block device implemented in Rust. Hand one to
+/// [`ExtFilesystem::create_with_io`] or [`ExtFilesystem::open_with_io`] and
+/// libe2fs does all of its io through it instead of through a file.
+///
+/// flail bridges the backend into a libe2fs `io_manager`. Its `read_blk64`,
+/// `write_blk64` and `write_byte` calls become [`Self::read_at`] and
+/// [`Self::write_at`], with block numbers turned into byte offsets using the
+/// block size from the latest `set_blksize`. The `offset` io option is
+/// handled by flail, so backends work with [`ExtImageWindow`]s as well.
+pub trait IoBackend: Send {
+    /// Fills `buf` with the bytes starting at `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes all of `buf` starting at `offset`.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Tells the backend that `len` bytes at `offset` no longer hold
+    /// anything. What reading them returns afterwards is up to the backend.
+    fn discard(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Zeroes `len` bytes at `offset`. By default this writes zeroes over
+    /// them.
+    fn zeroout(&mut self, offset: u64, len: u64) -> Result<()> {
+        let zeroes = vec![0; len.min(1024 * 1024) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(zeroes.len() as u64);
+            self.write_at(offset + done, &zeroes[..chunk as usize])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Called when libe2fs changes the block size it addresses the device
+    /// in. Offsets passed to the backend are always in bytes, so most
+    /// backends can ignore this.
+    fn set_blksize(&mut self, _block_size: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handles an io option other than `offset`, as passed to libe2fs in
+    /// `key=value` form.
+    fn set_option(&mut self, key: &str, _value: &str) -> Result<()> {
+        Err(eyre!("unsupported io option {key:?}"))
+    }
+
+    /// Grows or shrinks the device to `size` bytes, for
+    /// [`ExtFilesystem::resize`].
+    fn set_len(&mut self, _size: u64) -> Result<()> {
+        Err(eyre!("this io backend can't be resized"))
+    }
+}
+
+pub(crate) type SharedIoBackend = Arc<Mutex<dyn IoBackend>>;
+
+lazy_static! {
+    /// The io_manager that bridges libe2fs to [`IoBackend`]s. It must never
+    /// be locked while libe2fs runs, as opening a channel looks it up.
+    pub(crate) static ref BACKEND_IO_MANAGER: IoManager = IoManager(Arc::new(RwLock::new(
+        libe2fs_sys::struct_io_manager {
+            magic: libe2fs_sys::EXT2_ET_MAGIC_IO_MANAGER as i64,
+            name: BACKEND_IO_MANAGER_NAME.as_ptr(),
+            open: Some(backend_open),
+            close: Some(backend_close),
+            set_blksize: Some(backend_set_blksize),
+            read_blk: Some(backend_read_blk64),
+            write_blk: Some(backend_write_blk64),
+            flush: Some(backend_flush),
+            write_byte: Some(backend_write_byte),
+            set_option: Some(backend_set_option),
+            get_stats: Some(backend_get_stats),
+            read_blk64: Some(backend_read_blk64),
+            write_blk64: Some(backend_write_blk64),
+            discard: Some(backend_discard),
+            zeroout: Some(backend_zeroout),
+            ..unsafe { std::mem::zeroed() }
+        }
+    )));
+
+    /// Backends waiting to be opened by libe2fs, by device name.
+    static ref PENDING_BACKENDS: Mutex<HashMap<String, SharedIoBackend>> =
+        Mutex::new(HashMap::new());
+}
+
+// SAFETY: io managers are never changed after they're built, and only
+// point at 'static data.
+unsafe impl Send for IoManager {}
+unsafe impl Sync for IoManager {}
+
+static BACKEND_IO_MANAGER_NAME: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"flail backend io manager\0") };
+
+static NEXT_BACKEND_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Makes a backend openable by libe2fs under a unique device name for as
+/// long as the registration lives. Once open, the io channel holds on to
+/// the backend by itself.
+pub(crate) struct IoBackendRegistration(PathBuf);
+
+impl IoBackendRegistration {
+    pub(crate) fn new<B: IoBackend + 'static>(backend: B) -> Self {
+        let name = format!(
+            "flail-io-backend-{}",
+            NEXT_BACKEND_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        PENDING_BACKENDS
+            .lock()
+            .unwrap()
+            .insert(name.clone(), Arc::new(Mutex::new(backend)));
+        Self(name.into())
+    }
+
+    pub(crate) fn name(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for IoBackendRegistration {
+    fn drop(&mut self) {
+        PENDING_BACKENDS
+            .lock()
+            .unwrap()
+            .remove(&*self.0.to_string_lossy());
+    }
+}
+
+/// The backend behind an io channel, if it was opened by
+/// [`BACKEND_IO_MANAGER`], directly or through io tracing.
+pub(crate) fn backend_of(io: libe2fs_sys::io_channel) -> Option<SharedIoBackend> {
+    unsafe {
+        if let Some(traced) = traced_channel(io) {
+            return backend_of(traced);
+        }
+        if io.is_null() || (*io).manager != BACKEND_IO_MANAGER.as_ptr() {
+            return None;
+        }
+        Some(
+            (*((*io).private_data as *mut BackendChannel))
+                .backend
+                .clone(),
+        )
+    }
+}
+
+struct BackendChannel {
+    backend: SharedIoBackend,
+    offset: u64,
+    stats: libe2fs_sys::struct_io_stats,
+}
+
+/// Runs the body of an io manager callback. Unwinding into libe2fs is
+/// undefined behaviour, so panics become `EIO` instead.
+fn catch_panics(f: impl FnOnce() -> libe2fs_sys::errcode_t) -> libe2fs_sys::errcode_t {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        error!("io backend callback panicked");
+        libc::EIO as libe2fs_sys::errcode_t
+    })
+}
+
+/// Runs `f` against the backend of `io`, turning errors into libe2fs error
+/// codes. IO errors keep their errno, anything else becomes `fallback`. A
+/// backend that panicked before is poisoned and fails with `EIO`.
+unsafe fn with_backend(
+    io: libe2fs_sys::io_channel,
+    fallback: u32,
+    f: impl FnOnce(&mut dyn IoBackend, &mut BackendChannel) -> Result<()>,
+) -> libe2fs_sys::errcode_t {
+    let channel = &mut *((*io).private_data as *mut BackendChannel);
+    let backend = channel.backend.clone();
+    let Ok(mut backend) = backend.lock() else {
+        error!("io backend is poisoned by an earlier panic");
+        return libc::EIO as libe2fs_sys::errcode_t;
+    };
+    // catching the panic while the lock is held keeps it from poisoning
+    let result = catch_unwind(AssertUnwindSafe(|| f(&mut *backend, channel)))
+        .unwrap_or_else(|_| Err(eyre!("io backend panicked")));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            debug!("io backend failed: {err:?}");
+            match err
+                .downcast_ref::<std::io::Error>()
+                .and_then(|err| err.raw_os_error())
+            {
+                Some(errno) => errno as libe2fs_sys::errcode_t,
+                None => fallback as libe2fs_sys::errcode_t,
+            }
+        }
+    }
+}
+
+/// The byte offset and length of `count` blocks at `block`. A negative
+/// count is a length in bytes, as everywhere in libe2fs.
+pub(crate) unsafe fn byte_range(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: c_int,
+) -> (u64, usize) {
+    let block_size = (*io).block_size as u64;
+    let len = if count < 0 {
+        -(count as i64) as usize
+    } else {
+        count as usize * block_size as usize
+    };
+    (block * block_size, len)
+}
+
+unsafe extern "C" fn backend_open(
+    name: *const c_char,
+    flags: c_int,
+    channel: *mut libe2fs_sys::io_channel,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let name = CStr::from_ptr(name);
+        let Ok(pending) = PENDING_BACKENDS.lock() else {
+            error!("pending io backends are poisoned by an earlier panic");
+            return libc::EIO as libe2fs_sys::errcode_t;
+        };
+        let Some(backend) = pending.get(&*name.to_string_lossy()).cloned() else {
+            return libe2fs_sys::EXT2_ET_BAD_DEVICE_NAME as libe2fs_sys::errcode_t;
+        };
+        drop(pending);
+
+        let io: libe2fs_sys::io_channel = Box::into_raw(Box::new(std::mem::zeroed()));
+        (*io).magic = libe2fs_sys::EXT2_ET_MAGIC_IO_CHANNEL as i64;
+        (*io).manager = BACKEND_IO_MANAGER.as_ptr();
+        (*io).name = name.to_owned().into_raw();
+        (*io).block_size = 1024;
+        (*io).refcount = 1;
+        (*io).flags = flags;
+        (*io).private_data = Box::into_raw(Box::new(BackendChannel {
+            backend,
+            offset: 0,
+            stats: libe2fs_sys::struct_io_stats {
+                num_fields: 2,
+                reserved: 0,
+                bytes_read: 0,
+                bytes_written: 0,
+            },
+        })) as *mut _;
+        *channel = io;
+        0
+    })
+}
+
+unsafe extern "C" fn backend_close(io: libe2fs_sys::io_channel) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        // ext2fs_dup_handle shares the channel, so only the last close frees it
+        (*io).refcount -= 1;
+        if (*io).refcount > 0 {
+            return 0;
+        }
+        let err = with_backend(io, libc::EIO as u32, |backend, _| backend.flush());
+        drop(Box::from_raw((*io).private_data as *mut BackendChannel));
+        drop(CString::from_raw((*io).name));
+        drop(Box::from_raw(io));
+        err
+    })
+}
+
+unsafe extern "C" fn backend_set_blksize(
+    io: libe2fs_sys::io_channel,
+    block_size: c_int,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        (*io).block_size = block_size;
+        with_backend(io, libc::EINVAL as u32, |backend, _| {
+            backend.set_blksize(block_size as u32)
+        })
+    })
+}
+
+unsafe extern "C" fn backend_read_blk64(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: c_int,
+    data: *mut c_void,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let (offset, len) = byte_range(io, block, count);
+        let buf = std::slice::from_raw_parts_mut(data as *mut u8, len);
+        with_backend(io, libe2fs_sys::EXT2_ET_SHORT_READ, |backend, channel| {
+            backend.read_at(channel.offset + offset, buf)?;
+            channel.stats.bytes_read += len as u64;
+            Ok(())
+        })
+    })
+}
+
+unsafe extern "C" fn backend_write_blk64(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: c_int,
+    data: *const c_void,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let (offset, len) = byte_range(io, block, count);
+        let buf = std::slice::from_raw_parts(data as *const u8, len);
+        with_backend(io, libe2fs_sys::EXT2_ET_SHORT_WRITE, |backend, channel| {
+            backend.write_at(channel.offset + offset, buf)?;
+            channel.stats.bytes_written += buf.len() as u64;
+            Ok(())
+        })
+    })
+}
+
+unsafe extern "C" fn backend_write_byte(
+    io: libe2fs_sys::io_channel,
+    offset: u64,
+    size: c_int,
+    data: *const c_void,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let buf = std::slice::from_raw_parts(data as *const u8, size as usize);
+        with_backend(io, libe2fs_sys::EXT2_ET_SHORT_WRITE, |backend, channel| {
+            backend.write_at(channel.offset + offset, buf)?;
+            channel.stats.bytes_written += buf.len() as u64;
+            Ok(())
+        })
+    })
+}
+
+unsafe extern "C" fn backend_flush(io: libe2fs_sys::io_channel) -> libe2fs_sys::errcode_t {
+    catch_panics(|| with_backend(io, libc::EIO as u32, |backend, _| backend.flush()))
+}
+
+unsafe extern "C" fn backend_set_option(
+    io: libe2fs_sys::io_channel,
+    option: *const c_char,
+    arg: *const c_char,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let option = CStr::from_ptr(option).to_string_lossy();
+        let arg = if arg.is_null() {
+            "".into()
+        } else {
+            CStr::from_ptr(arg).to_string_lossy()
+        };
+        with_backend(
+            io,
+            libe2fs_sys::EXT2_ET_INVALID_ARGUMENT,
+            |backend, channel| match option.as_ref() {
+                "offset" => {
+                    channel.offset = arg.parse()?;
+                    Ok(())
+                }
+                _ => backend.set_option(&option, &arg),
+            },
+        )
+    })
+}
+
+unsafe extern "C" fn backend_get_stats(
+    io: libe2fs_sys::io_channel,
+    stats: *mut libe2fs_sys::io_stats,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let channel = &mut *((*io).private_data as *mut BackendChannel);
+        if !stats.is_null() {
+            *stats = &mut channel.stats;
+        }
+        0
+    })
+}
+
+unsafe extern "C" fn backend_discard(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: u64,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let block_size = (*io).block_size as u64;
+        with_backend(io, libc::EIO as u32, |backend, channel| {
+            backend.discard(channel.offset + block * block_size, count * block_size)
+        })
+    })
+}
+
+unsafe extern "C" fn backend_zeroout(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: u64,
+) -> libe2fs_sys::errcode_t {
+    catch_panics(|| {
+        let block_size = (*io).block_size as u64;
+        with_backend(io, libc::EIO as u32, |backend, channel| {
+            backend.zeroout(channel.offset + block * block_size, count * block_size)
+        })
+    })
+}
@@ -0,0 +1,121 @@
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+use super::*;
+
+/// A disk image held entirely in memory, for building filesystems without
+/// touching the disk.
+///
+/// Clones share the same bytes, so keep one around while a filesystem uses
+/// another, and take the finished image out of it with [`Self::to_vec`] or
+/// [`Self::to_bytes`]. libe2fs buffers some metadata until the filesystem is
+/// flushed, so drop the filesystem, or call
+/// [`ExtFilesystem::write_bitmaps`] and [`ExtFilesystem::flush`] first.
+#[derive(Clone, Debug, Default)]
+pub struct ExtMemoryImage(Arc<Mutex<BytesMut>>);
+
+impl ExtMemoryImage {
+    /// A zeroed image of `size` bytes.
+    pub fn new(size: u64) -> Self {
+        Self::from(BytesMut::zeroed(size as usize))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.0.lock().unwrap().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.lock().unwrap().to_vec()
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl From<BytesMut> for ExtMemoryImage {
+    fn from(bytes: BytesMut) -> Self {
+        Self(Arc::new(Mutex::new(bytes)))
+    }
+}
+
+impl From<Vec<u8>> for ExtMemoryImage {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::from(BytesMut::from(&bytes[..]))
+    }
+}
+
+impl From<&[u8]> for ExtMemoryImage {
+    fn from(bytes: &[u8]) -> Self {
+        Self::from(BytesMut::from(bytes))
+    }
+}
+
+impl IoBackend for ExtMemoryImage {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let bytes = self.0.lock().unwrap();
+        let range = checked_range(bytes.len(), offset, buf.len())?;
+        buf.copy_from_slice(&bytes[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut bytes = self.0.lock().unwrap();
+        let range = checked_range(bytes.len(), offset, buf.len())?;
+        bytes[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.zeroout(offset, len)
+    }
+
+    fn zeroout(&mut self, offset: u64, len: u64) -> Result<()> {
+        let mut bytes = self.0.lock().unwrap();
+        let range = checked_range(bytes.len(), offset, len as usize)?;
+        bytes[range].fill(0);
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.0.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+/// The range of `len` bytes at `offset`, if it's inside an image of `size`
+/// bytes. Like a real device, the image doesn't grow on its own.
+fn checked_range(size: usize, offset: u64, len: usize) -> Result<std::ops::Range<usize>> {
+    let start = offset as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= size => Ok(start..end),
+        _ => Err(eyre!(
+            "{len} bytes at offset {offset} are past the end of the {size} byte image"
+        )),
+    }
+}
+
+impl ExtFilesystem {
+    /// Creates a filesystem filling all of `image`.
+    pub fn create_in_memory(
+        image: &ExtMemoryImage,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        Self::create_with_io(image.clone(), image.len(), options)
+    }
+
+    /// Opens the filesystem in `image`, by default for reading and writing.
+    pub fn open_in_memory(
+        image: &ExtMemoryImage,
+        flags: Option<ExtFilesystemOpenFlags>,
+    ) -> Result<Self> {
+        let flags =
+            flags.unwrap_or(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW);
+        Self::open_with_io(image.clone(), ExtOpenOptions::new().flags(flags))
+    }
+}
@@ -0,0 +1,427 @@
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use super::*;
+
+/// Options controlling the geometry and identity of a filesystem created via
+/// [`ExtFilesystem::create`]. The defaults are 1k blocks, one inode per 8k,
+/// 5% reserved blocks, the label `flail`, a random UUID, and no journal.
+///
+/// See [`ExtMkfsOptions::reproducible`] for building byte-identical images.
+#[derive(Clone, Debug)]
+pub struct ExtMkfsOptions {
+    pub(crate) block_size: u32,
+    pub(crate) inode_size: Option<u16>,
+    pub(crate) inodes: ExtInodeAllocation,
+    pub(crate) reserved_percent: f64,
+    pub(crate) label: String,
+    pub(crate) uuid: Option<Uuid>,
+    pub(crate) hash_seed: Option<Uuid>,
+    pub(crate) clamp_time: Option<SystemTime>,
+    pub(crate) blocks_per_group: Option<u32>,
+    pub(crate) first_inode: Option<u32>,
+    pub(crate) journal: Option<ExtJournalSize>,
+    pub(crate) features: ExtFeatures,
+    pub(crate) desc_size: Option<u16>,
+    pub(crate) reserved_gdt_blocks: Option<u16>,
+}
+
+/// How many inodes a new filesystem gets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtInodeAllocation {
+    /// One inode for every `n` bytes of filesystem.
+    Ratio(u64),
+    /// Exactly `n` inodes, rounded up by libext2fs to fill the inode tables.
+    Count(u32),
+}
+
+/// How big the internal journal of a new filesystem is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtJournalSize {
+    /// Let libe2fs size the journal based on the size of the filesystem, the
+    /// same way mke2fs does.
+    Default,
+    /// A journal of exactly `n` filesystem blocks.
+    Blocks(u32),
+}
+
+impl ExtJournalSize {
+    pub const MIN_BLOCKS: u32 = 1_024;
+    pub const MAX_BLOCKS: u32 = 10_240_000;
+}
+
+impl Default for ExtMkfsOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 1_024,
+            inode_size: None,
+            inodes: ExtInodeAllocation::Ratio(8_192),
+            reserved_percent: 5.0,
+            label: "flail".into(),
+            uuid: None,
+            hash_seed: None,
+            clamp_time: None,
+            blocks_per_group: None,
+            first_inode: None,
+            journal: None,
+            features: ExtFeatures::default(),
+            desc_size: None,
+            reserved_gdt_blocks: None,
+        }
+    }
+}
+
+impl ExtMkfsOptions {
+    pub const VALID_BLOCK_SIZES: [u32; 4] = [1_024, 2_048, 4_096, 65_536];
+    /// libe2fs' EXT2_MAX_BLOCKS_PER_GROUP, which is what 64k blocks run into.
+    pub const MAX_BLOCKS_PER_GROUP: u32 = (1 << 16) - 8;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn inode_size(mut self, inode_size: u16) -> Self {
+        self.inode_size = Some(inode_size);
+        self
+    }
+
+    pub fn inode_ratio(mut self, bytes_per_inode: u64) -> Self {
+        self.inodes = ExtInodeAllocation::Ratio(bytes_per_inode);
+        self
+    }
+
+    pub fn inode_count(mut self, inodes: u32) -> Self {
+        self.inodes = ExtInodeAllocation::Count(inodes);
+        self
+    }
+
+    pub fn reserved_percent(mut self, percent: f64) -> Self {
+        self.reserved_percent = percent;
+        self
+    }
+
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    pub fn uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// Seeds the htree directory hash. Left zeroed if unset.
+    pub fn hash_seed(mut self, hash_seed: Uuid) -> Self {
+        self.hash_seed = Some(hash_seed);
+        self
+    }
+
+    /// Uses `time` as "now" for every superblock and inode timestamp written
+    /// through the new filesystem, and clamps anything newer to it, in the
+    /// spirit of `SOURCE_DATE_EPOCH`. See [`ExtFilesystem::set_clamp_time`].
+    pub fn clamp_time(mut self, time: SystemTime) -> Self {
+        self.clamp_time = Some(time);
+        self
+    }
+
+    /// Pins everything that would otherwise differ between two builds of the
+    /// same input: the uuid, the hash seed, and the clock. Combined with
+    /// [`ExtFilesystem::populate_from_dir`], which walks the host tree in a
+    /// sorted order so that inodes and blocks are always allocated the same
+    /// way, the same input tree always produces the same image bytes.
+    pub fn reproducible(self, uuid: Uuid, hash_seed: Uuid, clamp_time: SystemTime) -> Self {
+        self.uuid(uuid).hash_seed(hash_seed).clamp_time(clamp_time)
+    }
+
+    pub fn blocks_per_group(mut self, blocks_per_group: u32) -> Self {
+        self.blocks_per_group = Some(blocks_per_group);
+        self
+    }
+
+    pub fn first_inode(mut self, first_inode: u32) -> Self {
+        self.first_inode = Some(first_inode);
+        self
+    }
+
+    /// Creates an internal journal (inode 8), turning the new filesystem into
+    /// ext3/ext4 proper.
+    pub fn journal(mut self, size: ExtJournalSize) -> Self {
+        self.journal = Some(size);
+        self
+    }
+
+    /// Checksums all metadata (superblock, group descriptors, bitmaps, inodes,
+    /// extent blocks and directory blocks) with crc32c.
+    pub fn metadata_csum(mut self, metadata_csum: bool) -> Self {
+        self.features
+            .ro_compat
+            .set(ExtRoCompatFeatures::METADATA_CSUM, metadata_csum);
+        self
+    }
+
+    /// Stores the checksum seed in the superblock, so that the uuid can be
+    /// changed later without rewriting every checksum. Requires
+    /// `metadata_csum`.
+    pub fn metadata_csum_seed(mut self, metadata_csum_seed: bool) -> Self {
+        self.features
+            .incompat
+            .set(ExtIncompatFeatures::CSUM_SEED, metadata_csum_seed);
+        self
+    }
+
+    /// Reserves group descriptor blocks behind a resize inode (inode 7), so
+    /// that [`ExtFilesystem::resize`] can later grow the filesystem past what
+    /// its current descriptor blocks can describe. libe2fs reserves enough
+    /// to grow the filesystem 1024 times over, see
+    /// [`Self::reserved_gdt_blocks`] to pick the amount yourself.
+    pub fn resize_inode(mut self, resize_inode: bool) -> Self {
+        self.features
+            .compat
+            .set(ExtCompatFeatures::RESIZE_INODE, resize_inode);
+        self
+    }
+
+    /// Reserves exactly `blocks` group descriptor blocks. Implies
+    /// `resize_inode`.
+    pub fn reserved_gdt_blocks(mut self, blocks: u16) -> Self {
+        self.features.compat.insert(ExtCompatFeatures::RESIZE_INODE);
+        self.reserved_gdt_blocks = Some(blocks);
+        self
+    }
+
+    /// Replaces the whole feature set, which defaults to
+    /// [`ExtFeatures::default`]. Setting `has_journal` is the same as asking
+    /// for a [`ExtJournalSize::Default`] journal.
+    pub fn features(mut self, features: ExtFeatures) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// The size of a group descriptor. Only meaningful with `64bit`, where
+    /// it defaults to 64 bytes.
+    pub fn desc_size(mut self, desc_size: u16) -> Self {
+        self.desc_size = Some(desc_size);
+        self
+    }
+
+    /// Applies a profile on top of these options, see [`ExtProfiles`] for
+    /// combining several of them.
+    pub fn profile(self, profile: &ExtProfile) -> Self {
+        profile.apply(self)
+    }
+
+    /// Checks the options against each other and against the limits ext2/3/4
+    /// puts on them, so that we fail before touching the disk.
+    pub fn validate(&self) -> Result<()> {
+        if !Self::VALID_BLOCK_SIZES.contains(&self.block_size) {
+            return Err(eyre!(
+                "invalid block size {}, must be one of {:?}",
+                self.block_size,
+                Self::VALID_BLOCK_SIZES
+            ));
+        }
+
+        if let Some(inode_size) = self.inode_size {
+            if inode_size < libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16
+                || !inode_size.is_power_of_two()
+                || inode_size as u32 > self.block_size
+            {
+                return Err(eyre!(
+                    "invalid inode size {inode_size}, must be a power of two between {} and the block size",
+                    libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE
+                ));
+            }
+        }
+
+        match self.inodes {
+            ExtInodeAllocation::Ratio(ratio) => {
+                if ratio < self.block_size as u64 || ratio > 64 * 1_024 * 1_024 {
+                    return Err(eyre!(
+                        "invalid inode ratio {ratio}, must be between the block size and 64MiB"
+                    ));
+                }
+            }
+            ExtInodeAllocation::Count(count) => {
+                if count < libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO {
+                    return Err(eyre!(
+                        "invalid inode count {count}, must be at least {}",
+                        libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO
+                    ));
+                }
+            }
+        }
+
+        if !(0.0..=50.0).contains(&self.reserved_percent) {
+            return Err(eyre!(
+                "invalid reserved block percentage {}, must be between 0 and 50",
+                self.reserved_percent
+            ));
+        }
+
+        if self.label.len() > libe2fs_sys::EXT2_LABEL_LEN as usize {
+            return Err(eyre!(
+                "volume label {:?} is longer than {} bytes",
+                self.label,
+                libe2fs_sys::EXT2_LABEL_LEN
+            ));
+        }
+
+        if let Some(blocks_per_group) = self.blocks_per_group {
+            // a group's block bitmap is a single block
+            let max = (self.block_size * 8).min(Self::MAX_BLOCKS_PER_GROUP);
+            if blocks_per_group < 256 || blocks_per_group % 8 != 0 || blocks_per_group > max {
+                return Err(eyre!(
+                    "invalid blocks per group {blocks_per_group}, must be a multiple of 8 between 256 and {max}"
+                ));
+            }
+        }
+
+        if let Some(first_inode) = self.first_inode {
+            if first_inode < libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO {
+                return Err(eyre!(
+                    "invalid first inode {first_inode}, must be at least {}",
+                    libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO
+                ));
+            }
+        }
+
+        if let Some(ExtJournalSize::Blocks(blocks)) = self.journal {
+            if !(ExtJournalSize::MIN_BLOCKS..=ExtJournalSize::MAX_BLOCKS).contains(&blocks) {
+                return Err(eyre!(
+                    "invalid journal size {blocks}, must be between {} and {} blocks",
+                    ExtJournalSize::MIN_BLOCKS,
+                    ExtJournalSize::MAX_BLOCKS
+                ));
+            }
+        }
+
+        if let Some(clamp_time) = self.clamp_time {
+            clamp_time.duration_since(SystemTime::UNIX_EPOCH)?;
+        }
+
+        if let Some(blocks) = self.reserved_gdt_blocks {
+            // the resize inode maps them all through a single dind block
+            if blocks as u32 > self.block_size / 4 {
+                return Err(eyre!(
+                    "invalid reserved gdt block count {blocks}, must be at most {}",
+                    self.block_size / 4
+                ));
+            }
+        }
+
+        self.features
+            .validate(self.block_size, self.group_desc_size())?;
+        let unsupported = self.features.unsupported();
+        if !unsupported.is_empty() {
+            return Err(eyre!(
+                "flail can't create filesystems with these features: {unsupported:?}"
+            ));
+        }
+
+        // flail can write to these, but mkfs doesn't lay out the fast commit
+        // area or the orphan file they need
+        let unbuildable = self
+            .features
+            .compat
+            .intersection(ExtCompatFeatures::FAST_COMMIT | ExtCompatFeatures::ORPHAN_FILE);
+        if !unbuildable.is_empty() {
+            return Err(eyre!(
+                "flail can't create filesystems with these features: {unbuildable:?}"
+            ));
+        }
+
+        if self
+            .features
+            .incompat
+            .contains(ExtIncompatFeatures::INLINE_DATA)
+            && self
+                .inode_size
+                .unwrap_or(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16)
+                <= libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16
+        {
+            return Err(eyre!("inline_data needs inodes bigger than 128 bytes"));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn log_block_size(&self) -> u32 {
+        self.block_size.trailing_zeros() - libe2fs_sys::EXT2_MIN_BLOCK_LOG_SIZE
+    }
+
+    pub(crate) fn blocks_count(&self, size_bytes: u64) -> u64 {
+        size_bytes / self.block_size as u64
+    }
+
+    pub(crate) fn inodes_count(&self, size_bytes: u64) -> Result<u32> {
+        match self.inodes {
+            ExtInodeAllocation::Ratio(ratio) => {
+                let blocks_count = self.blocks_count(size_bytes);
+                Ok((blocks_count * self.block_size as u64 / ratio).try_into()?)
+            }
+            ExtInodeAllocation::Count(count) => Ok(count),
+        }
+    }
+
+    pub(crate) fn reserved_blocks_count(&self, size_bytes: u64) -> u64 {
+        (self.blocks_count(size_bytes) as f64 * self.reserved_percent / 100.0) as u64
+    }
+
+    /// The number of reserved gdt blocks a filesystem of `blocks` blocks and
+    /// `groups` groups gets, following libe2fs' calc_reserved_gdt_blocks.
+    pub(crate) fn reserved_gdt_blocks_for(&self, blocks: u64, groups: u64) -> u64 {
+        if !self
+            .features
+            .compat
+            .contains(ExtCompatFeatures::RESIZE_INODE)
+        {
+            return 0;
+        }
+        if let Some(reserved) = self.reserved_gdt_blocks {
+            return reserved as u64;
+        }
+
+        let block_size = self.block_size as u64;
+        let blocks_per_group = self
+            .blocks_per_group
+            .map(u64::from)
+            .unwrap_or(block_size * 8);
+        let descs_per_block = block_size / self.group_desc_size() as u64;
+        let max_blocks = (blocks * 1_024).min(u32::MAX as u64);
+        let max_groups = max_blocks.div_ceil(blocks_per_group);
+        max_groups
+            .div_ceil(descs_per_block)
+            .saturating_sub(groups.div_ceil(descs_per_block))
+            .min(block_size / 4)
+    }
+
+    pub(crate) fn group_desc_size(&self) -> u16 {
+        if self.features.incompat.contains(ExtIncompatFeatures::BIT64) {
+            self.desc_size
+                .unwrap_or(libe2fs_sys::EXT2_MIN_DESC_SIZE_64BIT as u16)
+        } else {
+            libe2fs_sys::EXT2_MIN_DESC_SIZE as u16
+        }
+    }
+
+    pub(crate) fn journal_size(&self) -> Option<ExtJournalSize> {
+        self.journal.or(self
+            .features
+            .compat
+            .contains(ExtCompatFeatures::HAS_JOURNAL)
+            .then_some(ExtJournalSize::Default))
+    }
+
+    pub(crate) fn volume_name(&self) -> [u8; libe2fs_sys::EXT2_LABEL_LEN as usize] {
+        let mut out = [0; libe2fs_sys::EXT2_LABEL_LEN as usize];
+        let label = self.label.as_bytes();
+        out[..label.len()].copy_from_slice(label);
+        out
+    }
+}
@@ -13,20 +13,47 @@ use std::sync::{Arc, RwLock};
 use std::time::SystemTime;
 
 use self::block::*;
+use self::features::*;
 use self::file::*;
 use self::inode::*;
 use self::io::*;
+use self::memory::*;
 use self::messages::*;
+use self::mkfs::*;
+use self::open::*;
+use self::populate::*;
+use self::profile::*;
+use self::trace::*;
 
+pub mod acl;
 pub mod block;
 pub mod facade;
+pub mod fault;
+pub mod features;
 pub mod file;
 pub mod inode;
 pub mod io;
+pub mod memory;
 pub mod messages;
+pub mod mkfs;
+pub mod open;
+pub mod populate;
+pub mod profile;
+pub mod qcow2;
+pub mod readonly;
+pub mod resize;
+pub mod sparse;
+pub mod times;
+pub mod trace;
+pub mod tune;
+pub mod xattr;
 
 #[derive(Debug, Clone)]
-pub struct ExtFilesystem(Arc<RwLock<libe2fs_sys::ext2_filsys>>, PathBuf);
+pub struct ExtFilesystem(
+    Arc<RwLock<libe2fs_sys::ext2_filsys>>,
+    PathBuf,
+    ExtImageWindow,
+);
 // SAFETY: I promise I'm doing my best here :sob:
 // All accesses to the ext2_filsys pointer are through an RwLock, and then
 // libe2fs does its own locking internally if it's compiled w/ support.
@@ -46,7 +73,14 @@ impl ExtFilesystem {
     pub const ROOT_INODE: u32 = libe2fs_sys::EXT2_ROOT_INO;
     pub const LPF_INODE: u32 = 11;
 
-    pub fn create<P: Into<PathBuf>>(path: P, size_bytes: u64) -> Result<Self> {
+    pub fn create<P: Into<PathBuf>>(
+        path: P,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        options.validate()?;
+
         // create file of size_bytes at path
         let path = path.into();
         debug!(
@@ -62,42 +96,122 @@ impl ExtFilesystem {
             .open(&path)?;
         file.set_len(size_bytes)?;
 
+        Self::create_in(
+            path,
+            &DEFAULT_IO_MANAGER,
+            ExtImageWindow::default(),
+            size_bytes,
+            options,
+        )
+    }
+
+    /// Creates a filesystem of `size_bytes` bytes starting `offset` bytes
+    /// into an existing file, eg. in a partition of a disk image. Nothing
+    /// outside of that window is touched.
+    pub fn create_at<P: Into<PathBuf>>(
+        path: P,
+        offset: u64,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        options.validate()?;
+
+        let path = path.into();
+        debug!("creating ext filesystem at {path:?} offset {offset} of size {size_bytes}");
+        let len = std::fs::metadata(&path)?.len();
+        if offset
+            .checked_add(size_bytes)
+            .filter(|end| *end <= len)
+            .is_none()
+        {
+            return Err(eyre!(
+                "{path:?} is {len} bytes, too small for {size_bytes} bytes at offset {offset}"
+            ));
+        }
+
+        let window = ExtImageWindow {
+            offset,
+            length: Some(size_bytes),
+        };
+        Self::create_in(path, &DEFAULT_IO_MANAGER, window, size_bytes, options)
+    }
+
+    /// Creates a filesystem of `size_bytes` bytes on a block device
+    /// implemented in Rust, see [`IoBackend`].
+    pub fn create_with_io<B: IoBackend + 'static>(
+        backend: B,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        options.validate()?;
+
+        let registration = IoBackendRegistration::new(backend);
+        debug!(
+            "creating ext filesystem on {:?} of size {size_bytes}",
+            registration.name()
+        );
+        Self::create_in(
+            registration.name().to_path_buf(),
+            &BACKEND_IO_MANAGER,
+            ExtImageWindow::default(),
+            size_bytes,
+            options,
+        )
+    }
+
+    fn create_in(
+        path: PathBuf,
+        io_manager: &IoManager,
+        window: ExtImageWindow,
+        size_bytes: u64,
+        options: ExtMkfsOptions,
+    ) -> Result<Self> {
+        let features = options.features;
+
         // initialise superblock
         debug!("initialising superblock...");
         let (err, fs) = unsafe {
             let mut fs = MaybeUninit::uninit();
-            let block_size = 1_024;
-            let inode_ratio = 8_192;
-            let blocks_count = size_bytes / block_size;
+            let blocks_count = options.blocks_count(size_bytes);
+            let r_blocks_count = options.reserved_blocks_count(size_bytes);
             let path = CString::new(path.to_string_lossy().as_bytes())?;
 
-            // hardware sector sizes
-            let mut lsector_size = 0;
-            let mut psector_size = 0;
-            let err = libe2fs_sys::ext2fs_get_device_sectsize(path.as_ptr(), &mut lsector_size);
-            if err != 0 {
-                return report(err);
-            }
-            let err =
-                libe2fs_sys::ext2fs_get_device_phys_sectsize(path.as_ptr(), &mut psector_size);
-            if err != 0 {
-                return report(err);
+            // hardware sector sizes, which io backends have no device to ask
+            // about
+            if std::ptr::eq(io_manager, &*DEFAULT_IO_MANAGER) {
+                let mut lsector_size = 0;
+                let mut psector_size = 0;
+                let err = libe2fs_sys::ext2fs_get_device_sectsize(path.as_ptr(), &mut lsector_size);
+                if err != 0 {
+                    return report(err);
+                }
+                let err =
+                    libe2fs_sys::ext2fs_get_device_phys_sectsize(path.as_ptr(), &mut psector_size);
+                if err != 0 {
+                    return report(err);
+                }
             }
 
             let mut superblock = libe2fs_sys::ext2_super_block {
                 s_rev_level: 1,
-                s_log_block_size: 0,
-                // TODO: validate
-                s_blocks_per_group: 0,
+                s_log_block_size: options.log_block_size(),
+                // 0 lets libe2fs pick the largest group that fits a bitmap block
+                s_blocks_per_group: options.blocks_per_group.unwrap_or(0),
                 s_blocks_count: blocks_count as u32,
                 s_blocks_count_hi: (blocks_count >> 32) as u32,
                 s_first_meta_bg: 0,
                 s_log_cluster_size: 0,
-                s_desc_size: libe2fs_sys::EXT2_MIN_DESC_SIZE_64BIT as u16,
-                // we don't use old inode size because it's old
-                s_inode_size: 0,
-                s_inodes_count: (blocks_count * block_size / inode_ratio).try_into()?,
-                s_r_blocks_count: 5,
+                s_desc_size: if features.incompat.contains(ExtIncompatFeatures::BIT64) {
+                    options.group_desc_size()
+                } else {
+                    0
+                },
+                // 0 lets libe2fs pick its default inode size
+                s_inode_size: options.inode_size.unwrap_or(0),
+                s_inodes_count: options.inodes_count(size_bytes)?,
+                s_r_blocks_count: r_blocks_count as u32,
 
                 s_algorithm_usage_bitmap: 0,
                 s_backup_bgs: [0, 0],
@@ -119,12 +233,13 @@ impl ExtFilesystem {
                 s_encryption_level: 0,
                 s_error_count: 0,
                 s_errors: 0,
-                s_feature_compat: 0,
-                s_feature_incompat: libe2fs_sys::EXT4_FEATURE_INCOMPAT_64BIT
-                    | libe2fs_sys::EXT3_FEATURE_INCOMPAT_EXTENTS,
-                s_feature_ro_compat: libe2fs_sys::EXT2_FEATURE_RO_COMPAT_LARGE_FILE
-                    | libe2fs_sys::EXT4_FEATURE_RO_COMPAT_HUGE_FILE
-                    | libe2fs_sys::EXT4_FEATURE_RO_COMPAT_DIR_NLINK,
+                // has_journal gets set once the journal actually exists
+                s_feature_compat: features
+                    .compat
+                    .difference(ExtCompatFeatures::HAS_JOURNAL)
+                    .bits(),
+                s_feature_incompat: features.incompat.bits(),
+                s_feature_ro_compat: features.ro_compat.bits(),
                 s_first_data_block: 0,
                 s_first_error_block: 0,
                 s_first_error_errcode: 0,
@@ -133,7 +248,7 @@ impl ExtFilesystem {
                 s_first_error_line: 0,
                 s_first_error_time: 0,
                 s_first_error_time_hi: 0,
-                s_first_ino: 0,
+                s_first_ino: options.first_inode.unwrap_or(0),
                 s_flags: 0,
                 s_free_blocks_count: 0,
                 s_free_blocks_hi: 0,
@@ -158,7 +273,12 @@ impl ExtFilesystem {
                 s_journal_uuid: [0; 16],
                 s_lastcheck: 0,
                 s_lastcheck_hi: 0,
-                s_log_groups_per_flex: 0,
+                // 16 groups per flex group, like mke2fs
+                s_log_groups_per_flex: if features.incompat.contains(ExtIncompatFeatures::FLEX_BG) {
+                    4
+                } else {
+                    0
+                },
                 s_max_mnt_count: 0,
                 s_mmp_block: 0,
                 s_mmp_update_interval: 0,
@@ -177,11 +297,12 @@ impl ExtFilesystem {
                 s_orphan_file_inum: 0,
                 s_overhead_clusters: 0,
                 s_prj_quota_inum: 0,
-                s_r_blocks_count_hi: 0,
+                s_r_blocks_count_hi: (r_blocks_count >> 32) as u32,
                 s_raid_stride: 0,
                 s_raid_stripe_width: 0,
                 s_reserved: [0; 94],
-                s_reserved_gdt_blocks: 0,
+                // 0 lets libe2fs reserve enough to grow the fs 1024 times over
+                s_reserved_gdt_blocks: options.reserved_gdt_blocks.unwrap_or(0),
                 s_reserved_pad: 0,
                 s_snapshot_id: 0,
                 s_snapshot_inum: 0,
@@ -195,8 +316,6 @@ impl ExtFilesystem {
                 s_wtime: 0,
                 s_wtime_hi: 0,
             };
-            let io_manager = DEFAULT_IO_MANAGER.clone().0;
-            let mut io_manager = io_manager.write().unwrap();
             let err = libe2fs_sys::ext2fs_initialize(
                 path.as_ptr(),
                 (libe2fs_sys::EXT2_FLAG_EXCLUSIVE
@@ -204,7 +323,7 @@ impl ExtFilesystem {
                     | libe2fs_sys::EXT2_FLAG_SKIP_MMP
                     | libe2fs_sys::EXT2_FLAG_RW) as i32,
                 &mut superblock as *mut _,
-                &mut *io_manager,
+                io_manager.as_ptr(),
                 fs.as_mut_ptr(),
             );
             (err, fs)
@@ -216,16 +335,50 @@ impl ExtFilesystem {
 
         let fs: libe2fs_sys::ext2_filsys = unsafe { fs.assume_init() };
 
+        // nothing has been written yet, so moving the io channel over to the
+        // window here is enough to keep every write inside of it
+        if let Some(io_options) = window.io_options()? {
+            let err = unsafe { libe2fs_sys::io_channel_set_options((*fs).io, io_options.as_ptr()) };
+            if err != 0 {
+                return report(err);
+            }
+        }
+
         // we skip journals for now
         // TODO: support journaling
 
         debug!("updating superblock accounting...");
         unsafe { *(*fs).super_ }.s_kbytes_written = 1;
 
-        debug!("generating uuid...");
-        let uuid = Uuid::new_v4();
-        let uuid = uuid.as_bytes();
-        unsafe { *(*fs).super_ }.s_uuid = *uuid;
+        debug!("setting uuid...");
+        let uuid = options.uuid.unwrap_or_else(Uuid::new_v4);
+        unsafe { (*(*fs).super_).s_uuid = *uuid.as_bytes() };
+
+        if let Some(hash_seed) = options.hash_seed {
+            debug!("setting hash seed...");
+            let hash_seed = hash_seed.as_bytes();
+            unsafe {
+                let superblock = (*fs).super_;
+                for (i, word) in (*superblock).s_hash_seed.iter_mut().enumerate() {
+                    // the words go to disk little-endian, so the uuid bytes land as they are
+                    *word = u32::from_le_bytes(hash_seed[i * 4..i * 4 + 4].try_into()?);
+                }
+                (*superblock).s_def_hash_version = libe2fs_sys::EXT2_HASH_HALF_MD4 as u8;
+            }
+        }
+
+        if let Some(clamp_time) = options.clamp_time {
+            debug!("clamping time to {clamp_time:?}...");
+            let time = clamp_time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+            unsafe {
+                (*fs).now = time as libe2fs_sys::time_t;
+                let superblock = (*fs).super_;
+                (*superblock).s_mkfs_time = time as u32;
+                (*superblock).s_mkfs_time_hi = (time >> 32) as u8;
+                (*superblock).s_lastcheck = time as u32;
+                (*superblock).s_lastcheck_hi = (time >> 32) as u8;
+            }
+        }
 
         // TODO: support setting periodic fsck
 
@@ -234,27 +387,24 @@ impl ExtFilesystem {
         unsafe { *(*fs).super_ }.s_creator_os = creatoros;
 
         debug!("setting volume label...");
-        unsafe { *(*fs).super_ }.s_volume_name = [
-            'f'.try_into()?,
-            'l'.try_into()?,
-            'a'.try_into()?,
-            'i'.try_into()?,
-            'l'.try_into()?,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-        ];
+        unsafe { (*(*fs).super_).s_volume_name = options.volume_name() };
 
         debug!("setting checksum...");
-        unsafe { *(*fs).super_ }.s_checksum_type = libe2fs_sys::EXT2_CRC32C_CHKSUM as u8;
+        unsafe {
+            let superblock = (*fs).super_;
+            (*superblock).s_checksum_type = libe2fs_sys::EXT2_CRC32C_CHKSUM as u8;
+            if features.incompat.contains(ExtIncompatFeatures::CSUM_SEED) {
+                // the seed is pinned to the uuid we're created with, so that
+                // the uuid can later change without rewriting every checksum
+                (*superblock).s_checksum_seed = libe2fs_sys::ext2fs_crc32c_le(
+                    !0,
+                    (*superblock).s_uuid.as_ptr(),
+                    (*superblock).s_uuid.len(),
+                );
+            }
+            // must happen after the uuid is set, as the seed is derived from it
+            libe2fs_sys::ext2fs_init_csum_seed(fs);
+        }
 
         debug!("allocating group tables...");
         let err = unsafe { libe2fs_sys::ext2fs_allocate_tables(fs) };
@@ -340,13 +490,108 @@ impl ExtFilesystem {
             }
         }
 
+        if features.compat.contains(ExtCompatFeatures::RESIZE_INODE) {
+            debug!("creating resize inode...");
+            let err = unsafe { libe2fs_sys::ext2fs_create_resize_inode(fs) };
+            if err != 0 {
+                return report(err);
+            }
+        }
+
+        if let Some(journal) = options.journal_size() {
+            Self::create_journal(fs, journal)?;
+        }
+
+        debug!("checksumming group descriptors...");
+        let err = unsafe { libe2fs_sys::ext2fs_set_gdt_csum(fs) };
+        if err != 0 {
+            return report(err);
+        }
+
         debug!("flushing!");
         let err = unsafe { libe2fs_sys::ext2fs_flush(fs) };
         if err != 0 {
             return report(err);
         }
 
-        Ok(Self(Arc::new(RwLock::new(fs)), path))
+        let out = Self(Arc::new(RwLock::new(fs)), path, window);
+        out.report_features();
+        Ok(out)
+    }
+
+    fn create_journal(fs: libe2fs_sys::ext2_filsys, size: ExtJournalSize) -> Result<()> {
+        debug!("creating journal...");
+        let mut params = MaybeUninit::<libe2fs_sys::ext2fs_journal_params>::uninit();
+        let err = unsafe { libe2fs_sys::ext2fs_get_journal_params(params.as_mut_ptr(), fs) };
+        if err != 0 {
+            return report(err);
+        }
+        let mut params = unsafe { params.assume_init() };
+        if let ExtJournalSize::Blocks(blocks) = size {
+            params.num_journal_blocks = blocks;
+        }
+        debug!("journal is {} blocks", params.num_journal_blocks);
+
+        // This takes care of the rest of the superblock for us: it sets
+        // has_journal, points s_journal_inum at EXT2_JOURNAL_INO, and copies
+        // the journal inode's block map into s_jnl_blocks as a backup.
+        let err = unsafe {
+            libe2fs_sys::ext2fs_add_journal_inode3(
+                fs,
+                &mut params,
+                // let libe2fs pick the goal block, which is the middle of the fs
+                !0,
+                0,
+            )
+        };
+        if err != 0 {
+            return report(err);
+        }
+
+        Ok(())
+    }
+
+    /// Makes every timestamp flail or libe2fs writes through this handle use
+    /// `time` instead of the system clock, and clamps timestamps copied from
+    /// elsewhere (eg. the host, when populating) so that none are newer than
+    /// it. `None` goes back to the system clock.
+    pub fn set_clamp_time(&self, time: Option<SystemTime>) -> Result<()> {
+        let time = match time {
+            Some(time) => time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs(),
+            None => 0,
+        };
+        let fs = *self.0.write().unwrap();
+        unsafe { (*fs).now = time as libe2fs_sys::time_t };
+        Ok(())
+    }
+
+    pub fn clamp_time(&self) -> Option<SystemTime> {
+        let fs = *self.0.read().unwrap();
+        match unsafe { (*fs).now } {
+            0 => None,
+            now => Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(now as u64)),
+        }
+    }
+
+    /// The current time, honouring [`Self::set_clamp_time`].
+    pub(crate) fn now_time(&self) -> SystemTime {
+        self.clamp_time().unwrap_or_else(SystemTime::now)
+    }
+
+    /// The current time in seconds, honouring [`Self::set_clamp_time`].
+    pub(crate) fn now(&self) -> u64 {
+        self.now_time()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Clamps `time` so that it isn't newer than [`Self::set_clamp_time`].
+    pub(crate) fn clamp(&self, time: SystemTime) -> SystemTime {
+        match self.clamp_time() {
+            Some(clamp_time) => time.min(clamp_time),
+            None => time,
+        }
     }
 
     pub fn open<P: Into<PathBuf> + std::fmt::Debug>(
@@ -354,7 +599,25 @@ impl ExtFilesystem {
         block_size: Option<u32>,
         flags: Option<ExtFilesystemOpenFlags>,
     ) -> Result<Self> {
-        // assumes flags=0, superblock=0,
+        let mut options = ExtOpenOptions::new();
+        if let Some(block_size) = block_size {
+            options = options.block_size(block_size);
+        }
+        if let Some(flags) = flags {
+            options = options.flags(flags);
+        }
+        Self::open_with_options(name, options)
+    }
+
+    /// Opens a filesystem that may live somewhere inside a larger image, eg.
+    /// in a partition of a whole disk image. Everything, from finding the
+    /// superblock to flushing on drop, stays within the window given by the
+    /// options' offset and length.
+    pub fn open_with_options<P: Into<PathBuf> + std::fmt::Debug>(
+        name: P,
+        options: ExtOpenOptions,
+    ) -> Result<Self> {
+        // assumes superblock=0,
         // from openfs.c:
         /*
          *  Note: if superblock is non-zero, block-size must also be non-zero.
@@ -371,22 +634,60 @@ impl ExtFilesystem {
          *				filesystems)
          */
 
-        let mut fs = MaybeUninit::uninit();
         let name = name.into().canonicalize()?;
+        Self::open_in(name, &DEFAULT_IO_MANAGER, options)
+    }
+
+    /// Opens a filesystem on a block device implemented in Rust, see
+    /// [`IoBackend`].
+    pub fn open_with_io<B: IoBackend + 'static>(
+        backend: B,
+        options: ExtOpenOptions,
+    ) -> Result<Self> {
+        let registration = IoBackendRegistration::new(backend);
+        Self::open_in(
+            registration.name().to_path_buf(),
+            &BACKEND_IO_MANAGER,
+            options,
+        )
+    }
+
+    fn open_in(name: PathBuf, io_manager: &IoManager, options: ExtOpenOptions) -> Result<Self> {
+        let mut fs = MaybeUninit::uninit();
+        let window = options.window;
+        let io_options = window.io_options()?;
         let (err, fs) = unsafe {
             debug!("preparing to open ext filesystem...");
             debug!("input = {name:#?}");
-            debug!("opening ext filesystem at '{name:?}'");
+            debug!("opening ext filesystem at '{name:?}' in {window:?}");
             let name = CString::new(name.to_string_lossy().as_bytes())?;
-            let io_manager = DEFAULT_IO_MANAGER.clone().0;
-            let mut io_manager = io_manager.write().unwrap();
+            let (_undo_guard, io_manager) = match &options.undo_file {
+                Some(undo_file) => {
+                    let (guard, io_manager) =
+                        crate::undo::undo_io_manager(io_manager.as_ptr(), undo_file)?;
+                    (Some(guard), io_manager)
+                }
+                None => (None, io_manager.as_ptr()),
+            };
+            // tracing goes on top, so it sees the io the filesystem does
+            // rather than what the undo file adds to it
+            let (_trace_guard, io_manager) = if options.trace_io {
+                let (guard, io_manager) = tracing_io_manager(io_manager, IoTrace::default());
+                (Some(guard), io_manager)
+            } else {
+                (None, io_manager)
+            };
             debug!("got io manager");
-            let err = libe2fs_sys::ext2fs_open(
+            let err = libe2fs_sys::ext2fs_open2(
                 name.as_ptr(),
-                flags.unwrap_or(ExtFilesystemOpenFlags::OPEN_64BIT).bits(),
+                io_options
+                    .as_ref()
+                    .map(|io_options| io_options.as_ptr())
+                    .unwrap_or(std::ptr::null()),
+                options.flags.bits(),
                 0,
-                block_size.unwrap_or(0),
-                &mut *io_manager,
+                options.block_size.unwrap_or(0),
+                io_manager,
                 fs.as_mut_ptr(),
             );
             (err, fs)
@@ -394,12 +695,21 @@ impl ExtFilesystem {
 
         if err == 0 {
             let fs = unsafe { fs.assume_init() };
-            let out = Self(Arc::new(RwLock::new(fs)), name);
+            let size = unsafe { libe2fs_sys::ext2fs_blocks_count((*fs).super_) }
+                * unsafe { (*fs).blocksize } as u64;
+            if let Err(err) = window.check_fits(size) {
+                unsafe { libe2fs_sys::ext2fs_free(fs) };
+                return Err(err);
+            }
+
+            let out = Self(Arc::new(RwLock::new(fs)), name, window);
             debug!("@ starting setup @");
+            out.report_features();
             out.read_bitmaps()?;
 
+            // read-only opens must leave the image exactly as it was
             let lpf_inode = out.read_inode(Self::LPF_INODE);
-            if lpf_inode.is_err() {
+            if lpf_inode.is_err() && !out.is_read_only() {
                 debug!("creating missing /lost+found...");
                 out.mkdir("/", "lost+found")?;
             }
@@ -412,6 +722,35 @@ impl ExtFilesystem {
         }
     }
 
+    /// Where in its backing file this filesystem lives.
+    pub fn window(&self) -> ExtImageWindow {
+        self.2
+    }
+
+    /// Whether the filesystem was opened without
+    /// [`ExtFilesystemOpenFlags::OPEN_RW`], in which case nothing is ever
+    /// written to it.
+    pub fn is_read_only(&self) -> bool {
+        let fs = *self.0.read().unwrap();
+        unsafe { (*fs).flags & libe2fs_sys::EXT2_FLAG_RW as i32 == 0 }
+    }
+
+    /// Fails with `EROFS` if the filesystem is read-only.
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            Err(ExtError::EROFS.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How many bytes the filesystem has read and written since it was
+    /// opened.
+    pub fn io_stats(&self) -> Result<IoStats> {
+        let fs = *self.0.read().unwrap();
+        IoChannel(unsafe { (*fs).io }).stats()
+    }
+
     pub fn iterate_dir<F, P: Into<PathBuf>>(&self, dir: P, mut f: F) -> Result<()>
     where
         F: FnMut(
@@ -456,19 +795,7 @@ impl ExtFilesystem {
 
     pub fn read_inode(&self, inode: u32) -> Result<ExtInode> {
         debug!("reading inode {inode}...");
-        let mut inode_ptr = MaybeUninit::uninit();
-        let err = unsafe {
-            libe2fs_sys::ext2fs_read_inode(
-                self.0.read().unwrap().as_mut().unwrap(),
-                inode,
-                inode_ptr.as_mut_ptr(),
-            )
-        };
-        if err == 0 {
-            Ok(unsafe { ExtInode(inode, *inode_ptr.assume_init_mut()) })
-        } else {
-            report(err)
-        }
+        unsafe { ExtInode::read(*self.0.read().unwrap(), inode) }
     }
 
     pub fn find_inode<P: Into<PathBuf>>(&self, path: P) -> Result<ExtInode> {
@@ -574,6 +901,11 @@ impl ExtFilesystem {
     }
 
     pub fn open_file(&self, inode: u32, flags: Option<ExtFileOpenFlags>) -> Result<ExtFile> {
+        if flags.is_some_and(|flags| {
+            flags.intersects(ExtFileOpenFlags::WRITE | ExtFileOpenFlags::CREATE)
+        }) {
+            self.check_writable()?;
+        }
         let mut file = MaybeUninit::uninit();
         let err = unsafe {
             libe2fs_sys::ext2fs_file_open2(
@@ -612,8 +944,21 @@ impl ExtFilesystem {
         if inode.is_null() {
             Err(ExtError::ENOENT.into())
         } else {
-            Ok(ExtInode(inode_num, unsafe { *inode }))
+            unsafe { ExtInode::with_small(*self.0.read().unwrap(), inode_num, &*inode) }
+        }
+    }
+
+    /// Writes `inode` for the open `file`, updating the copy the file keeps
+    /// too, so that closing the file doesn't undo the change.
+    pub(crate) fn write_file_inode(&self, file: &ExtFile, inode: &mut ExtInode) -> Result<()> {
+        self.write_inode(inode)?;
+        unsafe {
+            let cached = libe2fs_sys::ext2fs_file_get_inode(file.0);
+            if !cached.is_null() {
+                std::ptr::copy_nonoverlapping(inode.as_small_mut(), cached, 1);
+            }
         }
+        Ok(())
     }
 
     pub fn get_inode_number(&self, file: &ExtFile) -> Result<u32> {
@@ -649,6 +994,7 @@ impl ExtFilesystem {
     }
 
     pub fn write_file(&self, file: &ExtFile, buf: &[u8]) -> Result<usize> {
+        self.check_writable()?;
         let mut written = MaybeUninit::uninit();
         debug!("attempting to write {} bytes to {file:?}", buf.len());
         let ext_file = file.0 as *mut libe2fs_sys::real_ext2_file;
@@ -667,20 +1013,11 @@ impl ExtFilesystem {
 
         let written = unsafe { written.assume_init() };
 
-        // update the true size of the inode
-        unsafe {
-            let mut inode = self.read_inode((*ext_file).ino)?;
-            debug!("final inode size: {}", inode.1.i_size);
-            let err = libe2fs_sys::ext2fs_write_inode(
-                self.0.read().unwrap().as_mut().unwrap(),
-                (*ext_file).ino,
-                &mut inode.1,
-            );
-
-            if err != 0 {
-                return report(err);
-            }
-        }
+        // the file's own copy of the inode has its true size
+        let mut inode = self.get_inode(file)?;
+        debug!("final inode size: {}", inode.size());
+        inode.stamp(ExtInodeTimes::MTIME | ExtInodeTimes::CTIME, self.now_time());
+        self.write_file_inode(file, &mut inode)?;
 
         let err =
             unsafe { libe2fs_sys::ext2fs_file_flush(ext_file as *mut libe2fs_sys::ext2_file) };
@@ -695,6 +1032,7 @@ impl ExtFilesystem {
     }
 
     pub fn flush_file(&self, file: &ExtFile) -> Result<()> {
+        self.check_writable()?;
         let err = unsafe { libe2fs_sys::ext2fs_file_flush(file.0) };
         if err == 0 {
             Ok(())
@@ -704,8 +1042,13 @@ impl ExtFilesystem {
     }
 
     pub fn new_inode(&self, dir: u32, mode: u16) -> Result<ExtInode> {
+        self.check_writable()?;
         let mut inode = MaybeUninit::uninit();
         let fs = *self.0.read().unwrap();
+        let has_extents = self
+            .features()
+            .incompat
+            .contains(ExtIncompatFeatures::EXTENTS);
 
         debug!("creating new inode in dir {dir} with mode {mode}");
         let err = unsafe {
@@ -723,7 +1066,7 @@ impl ExtFilesystem {
             // let mut inode = self.read_inode(inum)?;
             debug!("created inode: {inum}");
             // once we have the inode, set its mode to be a file
-            let mut inode = libe2fs_sys::ext2_inode {
+            let inode = libe2fs_sys::ext2_inode {
                 i_mode: mode | libe2fs_sys::LINUX_S_IFREG as u16,
                 i_uid: 0,
                 i_size: 0,
@@ -734,8 +1077,13 @@ impl ExtFilesystem {
                 i_gid: 0,
                 i_links_count: 0,
                 i_blocks: unsafe { (*fs).blocksize / 512 },
-                // set extents flag, since we like modern ext4 features
-                i_flags: libe2fs_sys::EXT4_EXTENTS_FL,
+                // set extents flag, since we like modern ext4 features,
+                // unless the filesystem is too old to have them
+                i_flags: if has_extents {
+                    libe2fs_sys::EXT4_EXTENTS_FL
+                } else {
+                    0
+                },
                 osd1: libe2fs_sys::ext2_inode__bindgen_ty_1 {
                     linux1: libe2fs_sys::ext2_inode__bindgen_ty_1__bindgen_ty_1 { l_i_version: 0 },
                 },
@@ -755,10 +1103,10 @@ impl ExtFilesystem {
                     },
                 },
             };
+            let mut inode = unsafe { ExtInode::from_small(fs, inum, &inode) };
 
             unsafe {
-                let err =
-                    libe2fs_sys::ext2fs_iblk_set(fs, &mut inode as *mut libe2fs_sys::ext2_inode, 1);
+                let err = libe2fs_sys::ext2fs_iblk_set(fs, inode.as_small_mut(), 1);
                 if err != 0 {
                     return report(err);
                 }
@@ -768,29 +1116,38 @@ impl ExtFilesystem {
             debug!("attaching data block...");
             // find the next free block and set it on the inode. this value
             // will be written to the blocks bitmap later.
-            let data_block = self.new_block(&mut ExtInode(inum, inode))?;
+            let data_block = self.new_block(&mut inode)?;
             debug!("data block: {data_block}");
             // TODO: support directories later with ext2fs_new_dir_block!
 
             // now that we know what our data block is, we need to add it to
-            // the inode's extents tree.
-            debug!("adding data block to extents tree...");
-
-            unsafe {
-                let mut handle = MaybeUninit::uninit();
-                let err =
-                    libe2fs_sys::ext2fs_extent_open2(fs, inum, &mut inode, handle.as_mut_ptr());
-                if err != 0 {
-                    return report(err);
-                }
-                let err =
-                    libe2fs_sys::ext2fs_extent_set_bmap(handle.assume_init(), 0, data_block, 0);
-                if err != 0 {
-                    return report(err);
+            // the inode's extents tree, or its block map without extents.
+            if has_extents {
+                debug!("adding data block to extents tree...");
+                unsafe {
+                    let mut handle = MaybeUninit::uninit();
+                    let err = libe2fs_sys::ext2fs_extent_open2(
+                        fs,
+                        inum,
+                        inode.as_small_mut(),
+                        handle.as_mut_ptr(),
+                    );
+                    if err != 0 {
+                        return report(err);
+                    }
+                    let handle = handle.assume_init();
+                    let err = libe2fs_sys::ext2fs_extent_set_bmap(handle, 0, data_block, 0);
+                    libe2fs_sys::ext2fs_extent_free(handle);
+                    if err != 0 {
+                        return report(err);
+                    }
                 }
+            } else {
+                debug!("adding data block to block map...");
+                inode.1.i_block[0] = data_block as u32;
             }
 
-            debug!("uses {} 512b-i_blocks", inode.i_blocks);
+            debug!("uses {} 512b-i_blocks", inode.blocks());
 
             // flush inode to disk!
             debug!("writing new inode...");
@@ -801,10 +1158,15 @@ impl ExtFilesystem {
                 libe2fs_sys::ext2fs_block_alloc_stats2(fs, data_block, 1);
             }
 
-            let err = unsafe { libe2fs_sys::ext2fs_write_new_inode(fs, inum, &mut inode) };
+            let err =
+                unsafe { libe2fs_sys::ext2fs_write_new_inode(fs, inum, inode.as_small_mut()) };
             if err == 0 {
+                // libe2fs fills in the large inode's fields as it writes it
+                let mut inode = self.read_inode(inum)?;
+                inode.stamp(ExtInodeTimes::all(), self.now_time());
+                self.write_inode(&mut inode)?;
                 self.flush()?;
-                Ok(ExtInode(inum, inode))
+                Ok(inode)
             } else {
                 report(err)
             }
@@ -814,6 +1176,7 @@ impl ExtFilesystem {
     }
 
     pub fn new_block(&self, inode: &mut ExtInode) -> Result<u64> {
+        self.check_writable()?;
         let mut block = MaybeUninit::uninit();
         let fs = *self.0.read().unwrap();
         let err = unsafe {
@@ -875,6 +1238,7 @@ impl ExtFilesystem {
     }
 
     pub fn mkdir<P: Into<PathBuf>, S: Into<String>>(&self, parent: P, name: S) -> Result<()> {
+        self.check_writable()?;
         let parent = parent.into();
         let name = name.into();
         debug!(
@@ -898,6 +1262,9 @@ impl ExtFilesystem {
             )
         };
         if err == 0 {
+            let inum = self.lookup_inode(parent_inode.0, &name)?;
+            self.stamp_inode(inum, ExtInodeTimes::all())?;
+            self.stamp_dir_changed(parent_inode.0)?;
             self.flush()?;
             debug!("mkdir: success");
             Ok(())
@@ -917,6 +1284,7 @@ impl ExtFilesystem {
     }
 
     pub fn write_bitmaps(&self) -> Result<()> {
+        self.check_writable()?;
         let err = unsafe {
             // libe2fs_sys::ext2fs_write_bitmaps(self.0.read().unwrap().as_mut().unwrap())
             let fs = *self.0.write().unwrap();
@@ -945,6 +1313,7 @@ impl ExtFilesystem {
     }
 
     pub fn flush(&self) -> Result<()> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         unsafe {
             (*fs).flags |= (libe2fs_sys::EXT2_FLAG_DIRTY | libe2fs_sys::EXT2_FLAG_CHANGED) as i32;
@@ -959,6 +1328,7 @@ impl ExtFilesystem {
     }
 
     pub fn touch<P: Into<PathBuf>>(&self, path: P, mode: u16) -> Result<ExtFile> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         let path = path.into();
 
@@ -997,15 +1367,12 @@ impl ExtFilesystem {
 
             let fs = *self.0.write().unwrap();
             let mut inode = self.get_inode(&ExtFile(file, ExtFileState::Open))?;
-            debug!("inode size: {}", inode.1.i_size);
+            debug!("inode size: {}", inode.size());
 
             inode.1.i_links_count = 1;
 
             // write this inode
-            let err = libe2fs_sys::ext2fs_write_inode(fs, inum, &mut inode.1);
-            if err != 0 {
-                return report(err);
-            }
+            inode.write(fs)?;
             debug!("wrote inode");
 
             // link the inode into the fs hierarchy!
@@ -1023,6 +1390,7 @@ impl ExtFilesystem {
             if err != 0 {
                 return report(err);
             }
+            self.stamp_dir_changed(parent_inum)?;
         }
 
         self.flush()?;
@@ -1033,6 +1401,7 @@ impl ExtFilesystem {
     }
 
     pub fn write_to_file<P: Into<PathBuf>>(&self, path: P, buf: &[u8]) -> Result<usize> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         let path = path.into();
 
@@ -1079,6 +1448,8 @@ impl ExtFilesystem {
             )
         };
         if err != 0 {
+            // don't leak the file, the write error is the one worth reporting
+            unsafe { libe2fs_sys::ext2fs_file_close(file) };
             return report(err);
         }
 
@@ -1087,15 +1458,13 @@ impl ExtFilesystem {
             let mut inode = self.get_inode(&ExtFile(file, ExtFileState::Open))?;
             // libe2fs_sys::ext2fs_file_close(file as *mut libe2fs_sys::ext2_file);
             // debug!("closed file");
-            debug!("inode size: {}", inode.1.i_size);
+            debug!("inode size: {}", inode.size());
 
             inode.1.i_links_count = 1;
+            inode.stamp(ExtInodeTimes::MTIME | ExtInodeTimes::CTIME, self.now_time());
 
             // write this inode
-            let err = libe2fs_sys::ext2fs_write_inode(fs, inum, &mut inode.1);
-            if err != 0 {
-                return report(err);
-            }
+            inode.write(fs)?;
             debug!("wrote inode");
 
             // link the inode into the fs hierarchy!
@@ -1113,6 +1482,7 @@ impl ExtFilesystem {
             if err != 0 {
                 return report(err);
             }
+            self.stamp_dir_changed(parent_inum)?;
         }
 
         self.flush()?;
@@ -1121,6 +1491,7 @@ impl ExtFilesystem {
     }
 
     pub fn unlink<P: Into<PathBuf>>(&self, path: P) -> Result<()> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         let path = path.into();
         let file_name = path
@@ -1147,10 +1518,12 @@ impl ExtFilesystem {
             return report(err);
         }
 
-        Ok(())
+        self.stamp_inode(inode.0, ExtInodeTimes::CTIME)?;
+        self.stamp_dir_changed(parent_inum)
     }
 
     pub fn link<P: Into<PathBuf>>(&self, path: P, new_path: P) -> Result<()> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         let path = path.into();
         let new_path = new_path.into();
@@ -1179,15 +1552,14 @@ impl ExtFilesystem {
         }
 
         inode.1.i_links_count += 1;
-        let err = unsafe { libe2fs_sys::ext2fs_write_inode(fs, inode.0, &mut inode.1) };
-        if err != 0 {
-            return report(err);
-        }
+        inode.stamp(ExtInodeTimes::CTIME, self.now_time());
+        unsafe { inode.write(fs)? };
 
-        Ok(())
+        self.stamp_dir_changed(new_parent_inum)
     }
 
     pub fn delete<P: Into<PathBuf>>(&self, path: P) -> Result<()> {
+        self.check_writable()?;
         let fs = *self.0.write().unwrap();
         let path = path.into();
         let file_name = path
@@ -1215,23 +1587,17 @@ impl ExtFilesystem {
         }
 
         inode.1.i_links_count -= 1;
-        inode.1.i_dtime = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+        inode.1.i_dtime = self.now() as u32;
 
-        let err = unsafe { libe2fs_sys::ext2fs_write_inode(fs, inode.0, &mut inode.1) };
-        if err != 0 {
-            return report(err);
-        }
+        unsafe { inode.write(fs)? };
 
         // obliterate any remaining blocks
-        if unsafe { libe2fs_sys::ext2fs_inode_has_valid_blocks2(fs, &mut inode.1 as *mut _) != 0 } {
+        if unsafe { libe2fs_sys::ext2fs_inode_has_valid_blocks2(fs, inode.as_small_mut()) != 0 } {
             let err = unsafe {
                 libe2fs_sys::ext2fs_punch(
                     fs,
                     inode.0,
-                    &mut inode.1 as *mut _,
+                    inode.as_small_mut(),
                     std::ptr::null_mut(),
                     0,
                     u64::MAX,
@@ -1247,25 +1613,15 @@ impl ExtFilesystem {
             libe2fs_sys::ext2fs_inode_alloc_stats2(fs, inode.0, -1, 0);
         }
 
+        self.stamp_dir_changed(parent_inum)?;
         self.flush()?;
 
         Ok(())
     }
 
     pub fn write_inode(&self, inode: &mut ExtInode) -> Result<()> {
-        let err = unsafe {
-            libe2fs_sys::ext2fs_write_inode(
-                self.0.read().unwrap().as_mut().unwrap(),
-                inode.0,
-                &mut inode.1,
-            )
-        };
-
-        if err != 0 {
-            report(err)
-        } else {
-            Ok(())
-        }
+        self.check_writable()?;
+        unsafe { inode.write(*self.0.read().unwrap()) }
     }
 
     pub fn symlink<P1: AsRef<Path>, P2: AsRef<Path>>(
@@ -1275,6 +1631,7 @@ impl ExtFilesystem {
         symlink_name: P1,
         symlink_target_path: P2,
     ) -> Result<()> {
+        self.check_writable()?;
         let symlink_name = symlink_name.as_ref();
         let symlink_target_path = symlink_target_path.as_ref();
 
@@ -1288,17 +1645,22 @@ impl ExtFilesystem {
         let symlink_name =
             CString::new(symlink_name.as_os_str().to_string_lossy().to_string()).unwrap();
 
-        unsafe {
+        let err = unsafe {
             libe2fs_sys::ext2fs_symlink(
                 self.0.read().unwrap().as_mut().unwrap(),
                 symlink_parent_dir.0,
                 symlink_inode.map(|i| i.0).unwrap_or(0),
                 symlink_name.as_ptr(),
                 symlink_target_path.as_ptr(),
-            );
+            )
         };
+        if err != 0 {
+            return report(err);
+        }
 
-        Ok(())
+        let inum = self.lookup_inode(symlink_parent_dir.0, &symlink_name)?;
+        self.stamp_inode(inum, ExtInodeTimes::all())?;
+        self.stamp_dir_changed(symlink_parent_dir.0)
     }
 
     pub fn seek(&self, file: &ExtFile, offset: u64, direction: i32) -> Result<()> {
@@ -1328,13 +1690,25 @@ impl ExtFilesystem {
 impl Drop for ExtFilesystem {
     fn drop(&mut self) {
         unsafe {
+            if self.is_read_only() {
+                debug!("drop: read-only, freeing fs without writing anything...");
+                libe2fs_sys::ext2fs_free(*self.0.write().unwrap());
+                return;
+            }
+
+            // drop can't return errors, and panicking here would take the
+            // whole program down over a failing disk, so log them instead
             debug!("drop: writing bitmaps...");
-            self.write_bitmaps().unwrap();
+            if let Err(err) = self.write_bitmaps() {
+                error!("drop: failed to write bitmaps: {err:?}");
+            }
             let fs = self.0.write().unwrap();
             debug!("closing fs...");
             let err = libe2fs_sys::ext2fs_close(fs.as_mut().unwrap());
             if err != 0 {
-                Err::<(), ExtError>(ExtError::from(err as u32)).unwrap();
+                error!("drop: failed to close fs: {:?}", report::<()>(err));
+                // ext2fs_close only frees the fs once it's flushed
+                libe2fs_sys::ext2fs_free(*fs);
             }
         }
     }
@@ -1421,12 +1795,13 @@ bitflags! {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::fs;
     use std::path::Path;
 
     use pretty_assertions::{assert_eq, assert_ne};
 
+    use super::tune::*;
     use super::*;
 
     use eyre::Result;
@@ -1487,6 +1862,18 @@ mod tests {
         }
     }
 
+    pub fn assert_fsck_clean<P: AsRef<Path>>(path: P) -> Result<()> {
+        let fsck = std::process::Command::new("fsck.ext4")
+            .arg("-f")
+            .arg("-n")
+            .arg(path.as_ref())
+            .spawn()?
+            .wait()?;
+
+        assert!(fsck.success());
+        Ok(())
+    }
+
     #[test]
     pub fn test_reading_directories_works() -> Result<()> {
         let fs = ExtFilesystem::open(
@@ -1682,7 +2069,7 @@ mod tests {
 
         // create 16M fs image
         {
-            let fs = ExtFilesystem::create(&img, 16 * 1024 * 1024)?;
+            let fs = ExtFilesystem::create(&img, 16 * 1024 * 1024, None)?;
             let data = "hello flail";
 
             debug!("write data: '{data}'");
@@ -1722,4 +2109,971 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_making_new_fs_with_options_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+        let uuid = Uuid::parse_str("a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d")?;
+
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                64 * 1024 * 1024,
+                Some(
+                    ExtMkfsOptions::new()
+                        .block_size(4_096)
+                        .inode_size(256)
+                        .inode_ratio(16_384)
+                        .reserved_percent(1.0)
+                        .label("rootfs")
+                        .uuid(uuid),
+                ),
+            )?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let superblock = unsafe { *(**fs.0.read().unwrap()).super_ };
+        assert_eq!(4_096, 1_024 << superblock.s_log_block_size);
+        assert_eq!(256, superblock.s_inode_size);
+        assert_eq!(64 * 1024 * 1024 / 16_384, superblock.s_inodes_count);
+        assert_eq!(16_384 / 100, superblock.s_r_blocks_count);
+        assert_eq!(*uuid.as_bytes(), superblock.s_uuid);
+        assert_eq!(b"rootfs", &superblock.s_volume_name[..6]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_making_new_fs_with_journal_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                32 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().journal(ExtJournalSize::Blocks(2_048))),
+            )?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let superblock = unsafe { *(**fs.0.read().unwrap()).super_ };
+        assert_ne!(
+            0,
+            superblock.s_feature_compat & libe2fs_sys::EXT3_FEATURE_COMPAT_HAS_JOURNAL
+        );
+        assert_eq!(libe2fs_sys::EXT2_JOURNAL_INO, superblock.s_journal_inum);
+        assert_eq!(
+            libe2fs_sys::EXT3_JNL_BACKUP_BLOCKS as u8,
+            superblock.s_jnl_backup_type
+        );
+        let journal = fs.read_inode(libe2fs_sys::EXT2_JOURNAL_INO)?;
+        assert_eq!(2_048 * 1_024, journal.size());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_profiles_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let profiles = ExtProfiles::builtin();
+
+        // big enough to need indirect blocks without extents
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        for (name, profile) in [
+            ("ext2", &["ext2"][..]),
+            ("ext3", &["ext3"][..]),
+            ("ext4", &["ext4", "small"][..]),
+        ] {
+            let img = temp.path_view().join(format!("{name}.img"));
+            {
+                let fs = ExtFilesystem::create(
+                    &img,
+                    32 * 1024 * 1024,
+                    Some(profiles.options(profile)?),
+                )?;
+                fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+                fs.write_to_file("/big.bin", &data)?;
+            }
+            assert_fsck_clean(&img)?;
+
+            let fs = ExtFilesystem::open(
+                &img,
+                None,
+                Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+            )?;
+            let has_extents = fs
+                .features()
+                .incompat
+                .contains(ExtIncompatFeatures::EXTENTS);
+            let inode = fs.find_inode("/big.bin")?;
+            assert_eq!(
+                has_extents,
+                inode.1.i_flags & libe2fs_sys::EXT4_EXTENTS_FL != 0
+            );
+            let mut buf = vec![0u8; inode.size() as usize];
+            fs.read_file(&fs.open_file(inode.num(), None)?, &mut buf)?;
+            assert_eq!(data, buf);
+        }
+
+        let ext2 = ExtFilesystem::open(
+            temp.path_view().join("ext2.img"),
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?
+        .features();
+        assert!(!ext2.incompat.contains(ExtIncompatFeatures::EXTENTS));
+        assert!(!ext2.compat.contains(ExtCompatFeatures::HAS_JOURNAL));
+        assert!(ext2.ro_compat.contains(ExtRoCompatFeatures::SPARSE_SUPER));
+
+        let ext4 = ExtFilesystem::open(
+            temp.path_view().join("ext4.img"),
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?
+        .features();
+        assert!(ext4.compat.contains(ExtCompatFeatures::HAS_JOURNAL));
+        assert!(ext4.incompat.contains(ExtIncompatFeatures::FLEX_BG));
+        assert!(ext4.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM));
+
+        let profiles = ExtProfiles::parse(
+            r#"
+            [defaults]
+                inode_size = 256
+
+            [fs_types]
+                # roots that are mostly tiny files
+                initramfs = {
+                    base_features = ext_attr,filetype
+                    features = ^filetype,dir_index
+                    blocksize = 1024
+                    inode_ratio = 2048
+                }
+            "#,
+        )?;
+        let options = profiles.options(&["initramfs"])?;
+        assert_eq!(1_024, options.block_size);
+        assert_eq!(Some(256), options.inode_size);
+        assert_eq!(ExtInodeAllocation::Ratio(2_048), options.inodes);
+        assert_eq!(
+            ExtCompatFeatures::EXT_ATTR | ExtCompatFeatures::DIR_INDEX,
+            options.features.compat
+        );
+        assert!(options.features.incompat.is_empty());
+        assert!(profiles.get("ext4").is_some());
+
+        assert!(profiles.options(&["nope"]).is_err());
+        assert!(ExtProfiles::parse("[fs_types]\n broken = {\n features = nope\n }").is_err());
+        assert!(ExtProfiles::parse("[fs_types]\n unclosed = {").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_metadata_csum_survives_mutation() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+
+        ExtFilesystem::create(
+            &img,
+            32 * 1024 * 1024,
+            Some(
+                ExtMkfsOptions::new()
+                    .inode_size(256)
+                    .metadata_csum(true)
+                    .metadata_csum_seed(true)
+                    .journal(ExtJournalSize::Default),
+            ),
+        )?;
+        assert_fsck_clean(&img)?;
+
+        let mutations: Vec<Box<dyn Fn(&ExtFilesystem) -> Result<()>>> = vec![
+            Box::new(|fs| fs.mkdir("/", "foo")),
+            Box::new(|fs| fs.write_to_file("/foo/bar.txt", b"hello flail").map(|_| ())),
+            Box::new(|fs| fs.write_to_file("/big.bin", &[42u8; 20_000]).map(|_| ())),
+            Box::new(|fs| fs.delete("/big.bin")),
+            Box::new(|fs| fs.link("/foo/bar.txt", "/bar.txt")),
+        ];
+
+        for mutation in mutations {
+            {
+                let fs = ExtFilesystem::open(
+                    &img,
+                    None,
+                    Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+                )?;
+                mutation(&fs)?;
+            }
+            assert_fsck_clean(&img)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_populating_from_dir_works() -> Result<()> {
+        let source = TempDir::new()?;
+        let root = source.path_view();
+        fs::create_dir_all(root.join("etc/conf.d"))?;
+        fs::create_dir_all(root.join("proc/self"))?;
+        fs::write(root.join("etc/hostname"), "flail\n")?;
+        fs::write(root.join("etc/conf.d/junk.pyc"), "junk")?;
+        let mut sparse = vec![0u8; 64 * 1024];
+        sparse[40_000] = 1;
+        fs::write(root.join("sparse.bin"), &sparse)?;
+        fs::hard_link(root.join("etc/hostname"), root.join("hostname"))?;
+        std::os::unix::fs::symlink("etc/hostname", root.join("hostname.link"))?;
+        let fifo = CString::new(root.join("fifo").as_os_str().as_bytes())?;
+        assert_eq!(0, unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) });
+        fs::set_permissions(
+            root.join("etc/hostname"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o640),
+        )?;
+        // mkfs already made a lost+found, which gets merged into
+        fs::create_dir_all(root.join("lost+found"))?;
+        fs::write(root.join("lost+found/kept"), "kept")?;
+
+        let hostname_path = CString::new(root.join("etc/hostname").as_os_str().as_bytes())?;
+        let times = [
+            libc::timespec {
+                tv_sec: 1_500_000_000,
+                tv_nsec: 0,
+            },
+            libc::timespec {
+                tv_sec: 1_600_000_000,
+                tv_nsec: 500_000_000,
+            },
+        ];
+        assert_eq!(0, unsafe {
+            libc::utimensat(libc::AT_FDCWD, hostname_path.as_ptr(), times.as_ptr(), 0)
+        });
+        // the host filesystem may not do user xattrs
+        let key = CString::new("user.flail")?;
+        let has_xattrs = unsafe {
+            libc::lsetxattr(
+                hostname_path.as_ptr(),
+                key.as_ptr(),
+                b"yes".as_ptr() as *const ::std::ffi::c_void,
+                3,
+                0,
+            )
+        } == 0;
+        // making device nodes needs root
+        let null = CString::new(root.join("null").as_os_str().as_bytes())?;
+        let has_devices =
+            unsafe { libc::mknod(null.as_ptr(), libc::S_IFCHR | 0o666, libc::makedev(1, 3)) } == 0;
+
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+        {
+            ExtFilesystem::create_from_dir(
+                &img,
+                16 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().inode_size(256)),
+                &root,
+                Some(
+                    ExtPopulateOptions::new()
+                        .owner(100_000, 100_000)
+                        .exclude("/proc/*")
+                        .exclude("*.pyc"),
+                ),
+            )?;
+        }
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+
+        let hostname = fs.find_inode("/etc/hostname")?;
+        assert_eq!(0o640, hostname.mode() & 0o7777);
+        assert_eq!(2, hostname.links_count());
+        assert_eq!(100_000, hostname.uid());
+        assert_eq!(100_000, hostname.gid());
+        assert_eq!(hostname.num(), fs.find_inode("/hostname")?.num());
+        let mut buf = vec![0u8; hostname.size() as usize];
+        fs.read_file(&fs.open_file(hostname.num(), None)?, &mut buf)?;
+        assert_eq!(b"flail\n", buf.as_slice());
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000),
+            hostname.atime()?
+        );
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 500_000_000),
+            hostname.mtime()?
+        );
+        if has_xattrs {
+            assert_eq!(
+                Some(b"yes".to_vec()),
+                fs.get_xattr("/etc/hostname", "user.flail")?
+            );
+        }
+        for path in ["/", "/etc", "/hostname.link", "/fifo"] {
+            let inode = fs.find_inode(path)?;
+            assert_eq!((100_000, 100_000), (inode.uid(), inode.gid()));
+        }
+
+        let lost_and_found = fs.find_inode("/lost+found")?;
+        assert_eq!(libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO, lost_and_found.num());
+        assert!(fs.find_inode("/lost+found/kept")?.is_file());
+
+        if has_devices {
+            let null = fs.find_inode("/null")?;
+            assert!(null.is_char_device());
+            assert_eq!(0o666, null.mode() & 0o7777);
+            assert_eq!(256 + 3, null.1.i_block[0]);
+        }
+
+        let sparse_inode = fs.find_inode("/sparse.bin")?;
+        assert_eq!(64 * 1024, sparse_inode.size());
+        let mut buf = vec![0u8; sparse_inode.size() as usize];
+        fs.read_file(&fs.open_file(sparse_inode.num(), None)?, &mut buf)?;
+        assert_eq!(sparse, buf);
+
+        assert!(fs.find_inode("/hostname.link")?.is_symlink());
+        assert!(fs.find_inode("/fifo")?.is_fifo());
+        assert!(fs.find_inode("/proc")?.is_dir());
+        assert!(fs.find_inode("/proc/self").is_err());
+        assert!(fs.find_inode("/etc/conf.d/junk.pyc").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_reproducible_builds_are_byte_identical() -> Result<()> {
+        let source = TempDir::new()?;
+        let root = source.path_view();
+        fs::create_dir_all(root.join("usr/bin"))?;
+        fs::write(root.join("usr/bin/hello"), "#!/bin/sh\necho hello\n")?;
+        fs::write(root.join("README"), [7u8; 10_000])?;
+        std::os::unix::fs::symlink("usr/bin/hello", root.join("hello"))?;
+
+        let temp = TempDir::new()?;
+        let hash_seed = Uuid::parse_str("0e1f2a3b-4c5d-4a7b-8c9d-a1b2c3d4e5f6")?;
+        let options = ExtMkfsOptions::new()
+            .inode_size(256)
+            .metadata_csum(true)
+            .journal(ExtJournalSize::Default)
+            .reproducible(
+                Uuid::parse_str("a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d")?,
+                hash_seed,
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000),
+            );
+
+        let mut images = vec![];
+        for name in ["a.img", "b.img"] {
+            let img = temp.path_view().join(name);
+            {
+                let fs = ExtFilesystem::create_from_dir(
+                    &img,
+                    16 * 1024 * 1024,
+                    Some(options.clone()),
+                    &root,
+                    None,
+                )?;
+                fs.delete("/README")?;
+            }
+            assert_fsck_clean(&img)?;
+            images.push(fs::read(&img)?);
+        }
+
+        assert!(images[0] == images[1], "images differ");
+        // s_hash_seed holds the uuid's bytes as they are, whatever the host
+        assert_eq!(hash_seed.as_bytes(), &images[0][1_024 + 0xec..1_024 + 0xfc]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_invalid_mkfs_options_are_rejected() -> Result<()> {
+        assert!(ExtMkfsOptions::new().block_size(3_000).validate().is_err());
+        assert!(ExtMkfsOptions::new().inode_size(100).validate().is_err());
+        assert!(ExtMkfsOptions::new().inode_ratio(512).validate().is_err());
+        assert!(ExtMkfsOptions::new()
+            .reserved_percent(75.0)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .label("this label is far too long")
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .blocks_per_group(1_000)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .block_size(1_024)
+            .blocks_per_group(8_200)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .block_size(65_536)
+            .blocks_per_group(65_536)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .block_size(65_536)
+            .blocks_per_group(ExtMkfsOptions::MAX_BLOCKS_PER_GROUP)
+            .validate()
+            .is_ok());
+        assert!(ExtMkfsOptions::new()
+            .block_size(4_096)
+            .blocks_per_group(32_768)
+            .validate()
+            .is_ok());
+        assert!(ExtMkfsOptions::new().first_inode(5).validate().is_err());
+        assert!(ExtMkfsOptions::new()
+            .journal(ExtJournalSize::Blocks(16))
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new().block_size(65_536).validate().is_ok());
+
+        let meta_bg = ExtFeatures {
+            incompat: ExtFeatures::default().incompat | ExtIncompatFeatures::META_BG,
+            ..ExtFeatures::default()
+        };
+        assert!(ExtMkfsOptions::new()
+            .features(meta_bg)
+            .resize_inode(true)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new().features(meta_bg).validate().is_ok());
+        assert!(ExtMkfsOptions::new().desc_size(32).validate().is_err());
+        assert!(ExtMkfsOptions::new().desc_size(128).validate().is_ok());
+        assert!(ExtMkfsOptions::new()
+            .metadata_csum_seed(true)
+            .validate()
+            .is_err());
+        let unsupported = ExtFeatures {
+            ro_compat: ExtFeatures::default().ro_compat | ExtRoCompatFeatures::QUOTA,
+            ..ExtFeatures::default()
+        };
+        assert!(ExtMkfsOptions::new()
+            .features(unsupported)
+            .validate()
+            .is_err());
+        let no_extents = ExtFeatures {
+            incompat: ExtIncompatFeatures::BIT64,
+            ..ExtFeatures::default()
+        };
+        assert!(ExtMkfsOptions::new()
+            .features(no_extents)
+            .validate()
+            .is_err());
+        let orphan_file = ExtFeatures {
+            compat: ExtFeatures::default().compat | ExtCompatFeatures::ORPHAN_FILE,
+            ..ExtFeatures::default()
+        };
+        assert!(ExtMkfsOptions::new()
+            .features(orphan_file)
+            .validate()
+            .is_err());
+        let inline_data = ExtFeatures {
+            incompat: ExtFeatures::default().incompat | ExtIncompatFeatures::INLINE_DATA,
+            ..ExtFeatures::default()
+        };
+        assert!(ExtMkfsOptions::new()
+            .features(inline_data)
+            .validate()
+            .is_err());
+        assert!(ExtMkfsOptions::new()
+            .features(inline_data)
+            .inode_size(256)
+            .validate()
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_features_are_reported() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                16 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().metadata_csum(true)),
+            )?;
+            let features = fs.features();
+            assert!(features.contains(ExtFeatures::default()));
+            assert!(features
+                .ro_compat
+                .contains(ExtRoCompatFeatures::METADATA_CSUM));
+            assert!(fs.unsupported_features().is_empty());
+        }
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        assert!(fs
+            .features()
+            .ro_compat
+            .contains(ExtRoCompatFeatures::METADATA_CSUM));
+        assert!(fs.unsupported_features().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_tuning_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+        let open = || {
+            ExtFilesystem::open(
+                &img,
+                None,
+                Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+            )
+        };
+
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                32 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().journal(ExtJournalSize::Default)),
+            )?;
+            fs.mkdir("/", "dir")?;
+            for i in 0..40 {
+                fs.write_to_file(format!("/dir/file-{i}"), "hello flail".as_bytes())?;
+            }
+        }
+
+        let uuid = Uuid::new_v4();
+        open()?.tune(
+            ExtTuneOptions::new()
+                .label("tuned")
+                .uuid(uuid)
+                .max_mount_count(30)
+                .check_interval(std::time::Duration::from_secs(7 * 24 * 60 * 60))
+                .errors(ExtErrorBehaviour::RemountReadOnly)
+                .default_mount_options(
+                    ExtDefaultMountOptions::ACL | ExtDefaultMountOptions::USER_XATTR,
+                )
+                .reserved_percent(10.0)
+                .reserved_uid(1_000)
+                .reserved_gid(1_000)
+                .enable_features(ExtFeatures {
+                    compat: ExtCompatFeatures::DIR_INDEX,
+                    incompat: ExtIncompatFeatures::empty(),
+                    ro_compat: ExtRoCompatFeatures::METADATA_CSUM,
+                }),
+        )?;
+        assert_fsck_clean(&img)?;
+
+        {
+            let fs = open()?;
+            let superblock = unsafe { *(**fs.0.read().unwrap()).super_ };
+            assert_eq!(b"tuned\0", &superblock.s_volume_name[..6]);
+            assert_eq!(*uuid.as_bytes(), superblock.s_uuid);
+            assert_eq!(30, superblock.s_max_mnt_count);
+            assert_eq!(7 * 24 * 60 * 60, superblock.s_checkinterval);
+            assert_eq!(libe2fs_sys::EXT2_ERRORS_RO as u16, superblock.s_errors);
+            assert_eq!(
+                libe2fs_sys::EXT2_DEFM_ACL | libe2fs_sys::EXT2_DEFM_XATTR_USER,
+                superblock.s_default_mount_opts
+            );
+            assert_eq!(1_000, superblock.s_def_resuid);
+            assert_eq!(1_000, superblock.s_def_resgid);
+            assert_eq!(superblock.s_blocks_count / 10, superblock.s_r_blocks_count);
+            assert!(fs
+                .features()
+                .ro_compat
+                .contains(ExtRoCompatFeatures::METADATA_CSUM));
+
+            let file = fs.open_file(fs.find_inode("/dir/file-39")?.0, None)?;
+            let mut buf = [0u8; 11];
+            fs.read_file(&file, &mut buf)?;
+            assert_eq!(b"hello flail", &buf);
+        }
+
+        // every checksum is seeded from the uuid
+        open()?.tune(ExtTuneOptions::new().uuid(Uuid::new_v4()))?;
+        assert_fsck_clean(&img)?;
+
+        let no_csum = ExtFeatures {
+            compat: ExtCompatFeatures::empty(),
+            incompat: ExtIncompatFeatures::empty(),
+            ro_compat: ExtRoCompatFeatures::METADATA_CSUM,
+        };
+        open()?.tune(ExtTuneOptions::new().disable_features(no_csum))?;
+        assert_fsck_clean(&img)?;
+        assert!(open()?
+            .features()
+            .ro_compat
+            .contains(ExtRoCompatFeatures::GDT_CSUM));
+
+        let fs = open()?;
+        let extents = ExtFeatures {
+            compat: ExtCompatFeatures::empty(),
+            incompat: ExtIncompatFeatures::EXTENTS,
+            ro_compat: ExtRoCompatFeatures::empty(),
+        };
+        assert!(fs
+            .tune(ExtTuneOptions::new().disable_features(extents))
+            .is_err());
+        let bigalloc = ExtFeatures {
+            compat: ExtCompatFeatures::empty(),
+            incompat: ExtIncompatFeatures::empty(),
+            ro_compat: ExtRoCompatFeatures::BIGALLOC,
+        };
+        assert!(fs
+            .tune(ExtTuneOptions::new().enable_features(bigalloc))
+            .is_err());
+        assert!(fs
+            .tune(ExtTuneOptions::new().label("this label is far too long"))
+            .is_err());
+        assert!(fs
+            .tune(ExtTuneOptions::new().reserved_uid(100_000))
+            .is_err());
+
+        Ok(())
+    }
+
+    struct VecBackend(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl IoBackend for VecBackend {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+            let data = self.0.lock().unwrap();
+            let start = offset as usize;
+            let src = data
+                .get(start..start + buf.len())
+                .ok_or_else(|| eyre!("read past the end"))?;
+            buf.copy_from_slice(src);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+            let mut data = self.0.lock().unwrap();
+            let start = offset as usize;
+            data.get_mut(start..start + buf.len())
+                .ok_or_else(|| eyre!("write past the end"))?
+                .copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn set_len(&mut self, size: u64) -> Result<()> {
+            self.0.lock().unwrap().resize(size as usize, 0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_io_backends_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let size = 16 * 1024 * 1024;
+        let data = Arc::new(std::sync::Mutex::new(vec![0; size as usize]));
+
+        {
+            let fs = ExtFilesystem::create_with_io(VecBackend(data.clone()), size, None)?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+            fs.resize(2 * size)?;
+        }
+        assert_eq!(2 * size, data.lock().unwrap().len() as u64);
+
+        let image = temp.path_view().join("backend.img");
+        fs::write(&image, &*data.lock().unwrap())?;
+        assert_fsck_clean(&image)?;
+
+        let fs = ExtFilesystem::open_with_io(
+            VecBackend(data.clone()),
+            ExtOpenOptions::new()
+                .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let file = fs.open_file(fs.find_inode("/test.txt")?.0, None)?;
+        let mut buf = [0u8; 11];
+        fs.read_file(&file, &mut buf)?;
+        assert_eq!(b"hello flail", &buf);
+
+        Ok(())
+    }
+
+    struct PanickingBackend;
+
+    impl IoBackend for PanickingBackend {
+        fn read_at(&mut self, _offset: u64, _buf: &mut [u8]) -> Result<()> {
+            panic!("backend exploded");
+        }
+
+        fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> Result<()> {
+            panic!("backend exploded");
+        }
+    }
+
+    #[test]
+    pub fn test_panicking_backends_fail_cleanly() -> Result<()> {
+        // the panic has to come back as an error rather than unwind into C
+        assert!(ExtFilesystem::open_with_io(PanickingBackend, ExtOpenOptions::new()).is_err());
+        assert!(ExtFilesystem::create_with_io(PanickingBackend, 16 * 1024 * 1024, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_duplicated_backend_handles_close_cleanly() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        ExtFilesystem::create_in_memory(&image, None)?;
+
+        // with and without a tracing channel over the backend
+        for trace_io in [false, true] {
+            let fs = ExtFilesystem::open_with_io(
+                image.clone(),
+                ExtOpenOptions::new().trace_io(trace_io),
+            )?;
+            let raw = *fs.0.read().unwrap();
+            let mut dup = MaybeUninit::uninit();
+            let err = unsafe { libe2fs_sys::ext2fs_dup_handle(raw, dup.as_mut_ptr()) };
+            assert_eq!(0, err);
+            let dup = unsafe { dup.assume_init() };
+            assert_eq!(2, unsafe { (*(*raw).io).refcount });
+
+            // closing the duplicate must leave the channel usable
+            unsafe { libe2fs_sys::ext2fs_free(dup) };
+            assert_eq!(1, unsafe { (*(*raw).io).refcount });
+            assert!(fs.find_inode("/lost+found")?.is_dir());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_in_memory_filesystems_work() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+
+        {
+            let fs = ExtFilesystem::create_in_memory(&image, None)?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+
+        let exported = image.to_vec();
+        assert_eq!(16 * 1024 * 1024, exported.len());
+
+        // a copy of the image is a separate filesystem
+        let copy = ExtMemoryImage::from(exported.clone());
+        {
+            let fs = ExtFilesystem::open_in_memory(&copy, None)?;
+            fs.write_to_file("/other.txt", "hello again".as_bytes())?;
+        }
+        assert_eq!(exported, image.to_vec());
+
+        let fs = ExtFilesystem::open_in_memory(&copy, None)?;
+        for (path, expected) in [
+            ("/test.txt", b"hello flail"),
+            ("/other.txt", b"hello again"),
+        ] {
+            let file = fs.open_file(fs.find_inode(path)?.0, None)?;
+            let mut buf = [0u8; 11];
+            fs.read_file(&file, &mut buf)?;
+            assert_eq!(expected, &buf);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_opening_at_an_offset_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let part = temp.path_view().join("part.img");
+        let disk = temp.path_view().join("disk.img");
+        let size = 16 * 1024 * 1024;
+        let offset = 1024 * 1024;
+
+        ExtFilesystem::create(&part, size, None)?;
+        let mut image = vec![0xaa; offset as usize];
+        image.extend(fs::read(&part)?);
+        image.extend(vec![0xbb; 1024 * 1024]);
+        fs::write(&disk, &image)?;
+
+        let options = ExtOpenOptions::new()
+            .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW)
+            .offset(offset)
+            .length(size);
+        {
+            let fs = ExtFilesystem::open_with_options(&disk, options.clone())?;
+            assert_eq!(offset, fs.window().offset());
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+            // the window is full, so there's nowhere to grow
+            assert!(fs.resize(2 * size).is_err());
+        }
+
+        let image = fs::read(&disk)?;
+        assert_eq!(offset + size + 1024 * 1024, image.len() as u64);
+        assert!(image[..offset as usize].iter().all(|b| *b == 0xaa));
+        assert!(image[(offset + size) as usize..].iter().all(|b| *b == 0xbb));
+        fs::write(&part, &image[offset as usize..(offset + size) as usize])?;
+        assert_fsck_clean(&part)?;
+
+        let fs = ExtFilesystem::open_with_options(&disk, options.clone())?;
+        let file = fs.open_file(fs.find_inode("/test.txt")?.0, None)?;
+        let mut buf = [0u8; 11];
+        fs.read_file(&file, &mut buf)?;
+        assert_eq!(b"hello flail", &buf);
+
+        drop(fs);
+
+        // without a length, the window runs into the data after it
+        let fs = ExtFilesystem::open_with_options(
+            &disk,
+            ExtOpenOptions::new()
+                .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW)
+                .offset(offset),
+        )?;
+        assert!(fs.resize(size / 2).is_err());
+        assert!(fs.resize(2 * size).is_err());
+        assert!(fs.shrink_to_fit().is_err());
+        drop(fs);
+        let image = fs::read(&disk)?;
+        assert_eq!(offset + size + 1024 * 1024, image.len() as u64);
+        assert!(image[(offset + size) as usize..].iter().all(|b| *b == 0xbb));
+
+        assert!(ExtFilesystem::open_with_options(&disk, options.length(size / 2)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_estimating_and_shrinking_to_fit_works() -> Result<()> {
+        let source = TempDir::new()?;
+        let root = source.path_view();
+        fs::create_dir_all(root.join("usr/share/doc"))?;
+        for i in 0..50 {
+            fs::write(
+                root.join(format!("usr/share/doc/file-{i}")),
+                [i as u8; 3_000],
+            )?;
+        }
+        let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("usr/big.bin"), &big)?;
+
+        let options = ExtMkfsOptions::new()
+            .inode_size(256)
+            .metadata_csum(true)
+            .journal(ExtJournalSize::Blocks(1_024));
+        let estimate = ExtFilesystem::estimate_size(&root, Some(options.clone()), None)?;
+        assert!(estimate.data_blocks() >= 300 + 50 * 3);
+        assert!(estimate.size_bytes() < 32 * 1024 * 1024);
+
+        let temp = TempDir::new()?;
+        let estimated = temp.path_view().join("estimated.img");
+        {
+            ExtFilesystem::create_from_dir(
+                &estimated,
+                estimate.size_bytes(),
+                Some(options.clone()),
+                &root,
+                None,
+            )?;
+        }
+        assert_fsck_clean(&estimated)?;
+
+        let img = temp.path_view().join("shrunk.img");
+        let size = {
+            let fs =
+                ExtFilesystem::create_from_dir(&img, 32 * 1024 * 1024, Some(options), &root, None)?;
+            fs.shrink_to_fit()?
+        };
+        assert!(size < 32 * 1024 * 1024);
+        assert_eq!(size, fs::metadata(&img)?.len());
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let inode = fs.find_inode("/usr/big.bin")?;
+        let mut buf = vec![0u8; inode.size() as usize];
+        fs.read_file(&fs.open_file(inode.num(), None)?, &mut buf)?;
+        assert_eq!(big, buf);
+        assert!(fs.minimum_size()? <= size);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_resizing_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+        let data: Vec<u8> = (0..2_000_000u32).map(|i| (i % 253) as u8).collect();
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                16 * 1024 * 1024,
+                Some(
+                    ExtMkfsOptions::new()
+                        .inode_size(256)
+                        .metadata_csum(true)
+                        .resize_inode(true)
+                        .journal(ExtJournalSize::Blocks(1_024)),
+                ),
+            )?;
+            fs.mkdir("/", "small")?;
+            fs.write_to_file("/small/hello", b"hello flail")?;
+
+            // past 16 groups, which needs a second descriptor block
+            fs.resize(200 * 1024 * 1024)?;
+            fs.write_to_file("/big.bin", &data)?;
+        }
+        assert_eq!(200 * 1024 * 1024, fs::metadata(&img)?.len());
+        assert_fsck_clean(&img)?;
+
+        {
+            let fs = ExtFilesystem::open(
+                &img,
+                None,
+                Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+            )?;
+            fs.resize(12 * 1024 * 1024)?;
+        }
+        assert_eq!(12 * 1024 * 1024, fs::metadata(&img)?.len());
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtFilesystem::open(
+            &img,
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let inode = fs.find_inode("/big.bin")?;
+        let mut buf = vec![0u8; inode.size() as usize];
+        fs.read_file(&fs.open_file(inode.num(), None)?, &mut buf)?;
+        assert_eq!(data, buf);
+        let inode = fs.find_inode("/small/hello")?;
+        let mut buf = vec![0u8; inode.size() as usize];
+        fs.read_file(&fs.open_file(inode.num(), None)?, &mut buf)?;
+        assert_eq!(b"hello flail", buf.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_growing_without_reserved_gdt_blocks_is_rejected() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("test.img");
+        let fs = ExtFilesystem::create(&img, 16 * 1024 * 1024, None)?;
+
+        assert!(fs.resize(200 * 1024 * 1024).is_err());
+        assert_eq!(16 * 1024 * 1024, fs::metadata(&img)?.len());
+        fs.resize(64 * 1024 * 1024)?;
+        assert_eq!(64 * 1024 * 1024, fs::metadata(&img)?.len());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,109 @@
+use super::*;
+
+/// Options for opening an existing filesystem with
+/// [`ExtFilesystem::open_with_options`].
+#[derive(Clone, Debug)]
+pub struct ExtOpenOptions {
+    pub(crate) block_size: Option<u32>,
+    pub(crate) flags: ExtFilesystemOpenFlags,
+    pub(crate) window: ExtImageWindow,
+    pub(crate) undo_file: Option<PathBuf>,
+    pub(crate) trace_io: bool,
+}
+
+impl Default for ExtOpenOptions {
+    fn default() -> Self {
+        Self {
+            block_size: None,
+            flags: ExtFilesystemOpenFlags::OPEN_64BIT,
+            window: ExtImageWindow::default(),
+            undo_file: None,
+            trace_io: false,
+        }
+    }
+}
+
+impl ExtOpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skips probing for the block size.
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn flags(mut self, flags: ExtFilesystemOpenFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Where the filesystem starts in the backing file, eg. the start of its
+    /// partition in a whole disk image.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.window.offset = offset;
+        self
+    }
+
+    /// How many bytes past the offset belong to the filesystem. Without a
+    /// length, the filesystem may use everything up to the end of the file.
+    pub fn length(mut self, length: u64) -> Self {
+        self.window.length = Some(length);
+        self
+    }
+
+    /// Records the original contents of every block the filesystem changes
+    /// in `undo_file`, replacing any file already there. The changes can be
+    /// rolled back with [`crate::undo::apply`] once the filesystem is closed.
+    pub fn undo_file<P: Into<PathBuf>>(mut self, undo_file: P) -> Self {
+        self.undo_file = Some(undo_file.into());
+        self
+    }
+
+    /// Records every read, write, discard and flush the filesystem does, for
+    /// [`ExtFilesystem::io_trace`]. Every event is also logged at the trace
+    /// level.
+    pub fn trace_io(mut self, trace_io: bool) -> Self {
+        self.trace_io = trace_io;
+        self
+    }
+}
+
+/// The part of its backing file a filesystem lives in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtImageWindow {
+    pub(crate) offset: u64,
+    pub(crate) length: Option<u64>,
+}
+
+impl ExtImageWindow {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// The `io_options` that make libe2fs's io channel read and write
+    /// relative to the start of the window.
+    pub(crate) fn io_options(&self) -> Result<Option<CString>> {
+        if self.offset == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(CString::new(format!("offset={}", self.offset))?))
+        }
+    }
+
+    /// Checks that a filesystem of `size` bytes fits in the window.
+    pub(crate) fn check_fits(&self, size: u64) -> Result<()> {
+        match self.length {
+            Some(length) if size > length => Err(eyre!(
+                "filesystem of {size} bytes doesn't fit in its {length} byte window at offset {}",
+                self.offset
+            )),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,880 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::time::Duration;
+
+use super::*;
+
+/// Options for copying a host directory tree into a filesystem with
+/// [`ExtFilesystem::populate_from_dir`].
+#[derive(Clone, Debug, Default)]
+pub struct ExtPopulateOptions {
+    pub(crate) owner: Option<(u32, u32)>,
+    pub(crate) excludes: Vec<String>,
+}
+
+impl ExtPopulateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives every copied inode this uid and gid instead of the ones from the
+    /// host, eg. to build a root-owned image as an unprivileged user.
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// Skips every path matching `pattern`, and everything below it.
+    ///
+    /// Patterns containing a `/` are matched against the path relative to the
+    /// source directory, starting with a `/`, eg. `/proc/*`. Other patterns
+    /// are matched against the file name alone, eg. `*.pyc`. `*` matches
+    /// within a single path component, `**` matches across components, and
+    /// `?` matches a single character.
+    pub fn exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.excludes.push(pattern.into());
+        self
+    }
+
+    pub(crate) fn is_excluded(&self, relative_path: &Path) -> bool {
+        let path = relative_path.to_string_lossy();
+        let name = relative_path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        self.excludes.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_matches(pattern.as_bytes(), path.as_bytes())
+            } else {
+                glob_matches(pattern.as_bytes(), name.as_bytes())
+            }
+        })
+    }
+}
+
+/// How much space a host tree needs once copied into a new filesystem, as
+/// worked out by [`ExtFilesystem::estimate_size`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtSizeEstimate {
+    data_blocks: u64,
+    inodes: u32,
+    size_bytes: u64,
+}
+
+impl ExtSizeEstimate {
+    /// Blocks needed for file contents, directories, slow symlinks, extent
+    /// trees and xattr blocks, not counting group metadata or the journal.
+    pub fn data_blocks(&self) -> u64 {
+        self.data_blocks
+    }
+
+    /// Inodes needed, including the reserved ones and lost+found.
+    pub fn inodes(&self) -> u32 {
+        self.inodes
+    }
+
+    /// The smallest image, in bytes, that holds everything above along with
+    /// the group metadata, journal and reserved blocks. Pass this straight
+    /// to [`ExtFilesystem::create_from_dir`].
+    pub fn size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+}
+
+impl ExtFilesystem {
+    /// Creates a new filesystem at `path` and copies the host directory
+    /// `source` into its root, like `mke2fs -d`.
+    pub fn create_from_dir<P1: Into<PathBuf>, P2: AsRef<Path>>(
+        path: P1,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+        source: P2,
+        populate_options: Option<ExtPopulateOptions>,
+    ) -> Result<Self> {
+        let fs = Self::create(path, size_bytes, options)?;
+        fs.populate_from_dir(source, "/", populate_options)?;
+        Ok(fs)
+    }
+
+    /// Works out how big a filesystem created with `options` must be to hold
+    /// the host directory `source`, without touching the disk. File sizes
+    /// come from the host's allocated blocks, so sparse files are counted by
+    /// what they actually use. A little slack is included for extent trees.
+    pub fn estimate_size<P: AsRef<Path>>(
+        source: P,
+        options: Option<ExtMkfsOptions>,
+        populate_options: Option<ExtPopulateOptions>,
+    ) -> Result<ExtSizeEstimate> {
+        let options = options.unwrap_or_default();
+        options.validate()?;
+        let populate_options = populate_options.unwrap_or_default();
+        let source = source.as_ref();
+        if !std::fs::symlink_metadata(source)?.is_dir() {
+            return Err(ExtError::ENOTDIR.into());
+        }
+
+        let mut usage = HostTreeUsage {
+            block_size: options.block_size as u64,
+            inode_size: options
+                .inode_size
+                .unwrap_or(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16)
+                as u64,
+            dir_block_tail: if options
+                .features
+                .ro_compat
+                .contains(ExtRoCompatFeatures::METADATA_CSUM)
+            {
+                12
+            } else {
+                0
+            },
+            data_blocks: 0,
+            inodes: 0,
+            seen: HashSet::new(),
+        };
+        // the root directory is one of the reserved inodes, and gets an
+        // extra entry for lost+found
+        usage.add_dir(
+            source,
+            Path::new("/"),
+            &populate_options,
+            &["lost+found".len()],
+        )?;
+        let lost_and_found = usage.dir_blocks(std::iter::empty());
+
+        let first_inode = options
+            .first_inode
+            .unwrap_or(libe2fs_sys::EXT2_GOOD_OLD_FIRST_INO);
+        let inodes = usage.inodes + first_inode;
+        let data_blocks = usage.data_blocks + lost_and_found;
+        debug!("estimate_size: {data_blocks} data blocks and {inodes} inodes for {source:?}");
+
+        let blocks = mkfs_blocks_needed(&options, data_blocks + data_blocks / 64 + 16, inodes)?;
+        Ok(ExtSizeEstimate {
+            data_blocks,
+            inodes,
+            size_bytes: blocks * options.block_size as u64,
+        })
+    }
+
+    /// Recursively copies the host directory `source` into the existing
+    /// directory `target`. Regular files, directories, symlinks, device
+    /// nodes, FIFOs, sockets and hard links are all carried over, along with
+    /// their mode, ownership, timestamps and xattrs. `target` itself takes on
+    /// the metadata of `source`.
+    pub fn populate_from_dir<P1: AsRef<Path>, P2: Into<PathBuf>>(
+        &self,
+        source: P1,
+        target: P2,
+        options: Option<ExtPopulateOptions>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let source = source.as_ref();
+        let target = target.into();
+        let options = options.unwrap_or_default();
+        debug!("populating {target:?} from {source:?}");
+
+        let target_inode = self.find_inode(&target)?;
+        if !target_inode.is_dir() {
+            return Err(ExtError::ENOTDIR.into());
+        }
+
+        let metadata = std::fs::symlink_metadata(source)?;
+        if !metadata.is_dir() {
+            return Err(ExtError::ENOTDIR.into());
+        }
+
+        let mut hard_links = HashMap::new();
+        self.populate_dir(
+            source,
+            Path::new("/"),
+            target_inode.num(),
+            &options,
+            &mut hard_links,
+        )?;
+        self.copy_host_metadata(target_inode.num(), source, &metadata, &options)?;
+
+        self.flush()
+    }
+
+    fn populate_dir(
+        &self,
+        host_dir: &Path,
+        relative_dir: &Path,
+        parent: u32,
+        options: &ExtPopulateOptions,
+        hard_links: &mut HashMap<(u64, u64), u32>,
+    ) -> Result<()> {
+        // sort so that inodes and blocks are handed out in a stable order
+        let mut entries = std::fs::read_dir(host_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let host_path = entry.path();
+            let relative_path = relative_dir.join(entry.file_name());
+            if options.is_excluded(&relative_path) {
+                debug!("populate: excluding {relative_path:?}");
+                continue;
+            }
+
+            let name = CString::new(entry.file_name().as_bytes())?;
+            let metadata = std::fs::symlink_metadata(&host_path)?;
+            let file_type = metadata.file_type();
+            debug!("populate: copying {relative_path:?}");
+
+            if !file_type.is_dir() && metadata.nlink() > 1 {
+                if let Some(inum) = hard_links.get(&(metadata.dev(), metadata.ino())) {
+                    debug!("populate: hard linking {relative_path:?} to inode {inum}");
+                    self.link_inode(parent, &name, *inum, ext_file_type(&metadata))?;
+                    let mut inode = self.read_inode(*inum)?;
+                    inode.1.i_links_count += 1;
+                    self.write_inode(&mut inode)?;
+                    continue;
+                }
+            }
+
+            let inum = if file_type.is_dir() {
+                // directories that are already there, like lost+found, are
+                // merged into instead of failing with DIR_EXISTS
+                let inum = match self.lookup_inode(parent, &name) {
+                    Ok(inum) if self.read_inode(inum)?.is_dir() => inum,
+                    _ => self.mkdir_inode(parent, &name)?,
+                };
+                self.populate_dir(&host_path, &relative_path, inum, options, hard_links)?;
+                inum
+            } else if file_type.is_file() {
+                self.copy_host_file(parent, &name, &host_path, &metadata)?
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(&host_path)?;
+                self.symlink_inode(parent, &name, &target)?
+            } else {
+                self.mknod_inode(parent, &name, &metadata)?
+            };
+
+            self.copy_host_metadata(inum, &host_path, &metadata, options)?;
+
+            if !file_type.is_dir() && metadata.nlink() > 1 {
+                hard_links.insert((metadata.dev(), metadata.ino()), inum);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Links `inode` into `dir`, growing the directory if it's full.
+    pub(crate) fn link_inode(
+        &self,
+        dir: u32,
+        name: &CStr,
+        inode: u32,
+        file_type: u32,
+    ) -> Result<()> {
+        let fs = *self.0.write().unwrap();
+        let mut err =
+            unsafe { libe2fs_sys::ext2fs_link(fs, dir, name.as_ptr(), inode, file_type as i32) };
+        if err == libe2fs_sys::EXT2_ET_DIR_NO_SPACE as i64 {
+            debug!("directory {dir} is full, expanding...");
+            err = unsafe { libe2fs_sys::ext2fs_expand_dir(fs, dir) };
+            if err == 0 {
+                err = unsafe {
+                    libe2fs_sys::ext2fs_link(fs, dir, name.as_ptr(), inode, file_type as i32)
+                };
+            }
+        }
+
+        if err == 0 {
+            Ok(())
+        } else {
+            report(err)
+        }
+    }
+
+    fn mkdir_inode(&self, parent: u32, name: &CStr) -> Result<u32> {
+        let fs = *self.0.write().unwrap();
+        let mut err = unsafe { libe2fs_sys::ext2fs_mkdir(fs, parent, 0, name.as_ptr()) };
+        if err == libe2fs_sys::EXT2_ET_DIR_NO_SPACE as i64 {
+            debug!("directory {parent} is full, expanding...");
+            err = unsafe { libe2fs_sys::ext2fs_expand_dir(fs, parent) };
+            if err == 0 {
+                err = unsafe { libe2fs_sys::ext2fs_mkdir(fs, parent, 0, name.as_ptr()) };
+            }
+        }
+        if err != 0 {
+            return report(err);
+        }
+
+        self.lookup_inode(parent, name)
+    }
+
+    fn symlink_inode(&self, parent: u32, name: &CStr, target: &Path) -> Result<u32> {
+        let fs = *self.0.write().unwrap();
+        let target = CString::new(target.as_os_str().as_bytes())?;
+        let mut err =
+            unsafe { libe2fs_sys::ext2fs_symlink(fs, parent, 0, name.as_ptr(), target.as_ptr()) };
+        if err == libe2fs_sys::EXT2_ET_DIR_NO_SPACE as i64 {
+            debug!("directory {parent} is full, expanding...");
+            err = unsafe { libe2fs_sys::ext2fs_expand_dir(fs, parent) };
+            if err == 0 {
+                err = unsafe {
+                    libe2fs_sys::ext2fs_symlink(fs, parent, 0, name.as_ptr(), target.as_ptr())
+                };
+            }
+        }
+        if err != 0 {
+            return report(err);
+        }
+
+        self.lookup_inode(parent, name)
+    }
+
+    pub(crate) fn lookup_inode(&self, dir: u32, name: &CStr) -> Result<u32> {
+        let fs = *self.0.read().unwrap();
+        let mut inum = MaybeUninit::uninit();
+        let err = unsafe {
+            libe2fs_sys::ext2fs_lookup(
+                fs,
+                dir,
+                name.as_ptr(),
+                name.to_bytes().len().try_into()?,
+                std::ptr::null_mut(),
+                inum.as_mut_ptr(),
+            )
+        };
+        if err == 0 {
+            Ok(unsafe { inum.assume_init() })
+        } else {
+            report(err)
+        }
+    }
+
+    /// Allocates a fresh inode of the given type and links it into `parent`.
+    fn new_linked_inode(
+        &self,
+        parent: u32,
+        name: &CStr,
+        mode: u16,
+        file_type: u32,
+        f: impl FnOnce(u32, &mut libe2fs_sys::ext2_inode) -> Result<()>,
+    ) -> Result<u32> {
+        let fs = *self.0.read().unwrap();
+        let mut inum = MaybeUninit::uninit();
+        let err = unsafe {
+            libe2fs_sys::ext2fs_new_inode(
+                fs,
+                parent,
+                mode as i32,
+                std::ptr::null_mut(),
+                inum.as_mut_ptr(),
+            )
+        };
+        if err != 0 {
+            return report(err);
+        }
+        let inum = unsafe { inum.assume_init() };
+        debug!("allocated inode {inum}");
+
+        self.link_inode(parent, name, inum, file_type)?;
+        unsafe {
+            // fs, inode, inuse, isdir
+            libe2fs_sys::ext2fs_inode_alloc_stats2(fs, inum, 1, 0);
+        }
+
+        let mut inode: libe2fs_sys::ext2_inode = unsafe { std::mem::zeroed() };
+        inode.i_mode = mode;
+        inode.i_links_count = 1;
+        f(inum, &mut inode)?;
+
+        let err = unsafe { libe2fs_sys::ext2fs_write_new_inode(fs, inum, &mut inode) };
+        if err == 0 {
+            Ok(inum)
+        } else {
+            report(err)
+        }
+    }
+
+    fn copy_host_file(
+        &self,
+        parent: u32,
+        name: &CStr,
+        host_path: &Path,
+        metadata: &Metadata,
+    ) -> Result<u32> {
+        let fs = *self.0.read().unwrap();
+        let has_extents = self
+            .features()
+            .incompat
+            .contains(ExtIncompatFeatures::EXTENTS);
+        let mode = libe2fs_sys::LINUX_S_IFREG as u16 | (metadata.mode() as u16 & 0o7777);
+        let inum = self.new_linked_inode(
+            parent,
+            name,
+            mode,
+            libe2fs_sys::EXT2_FT_REG_FILE,
+            |inum, inode| unsafe {
+                let err = libe2fs_sys::ext2fs_inode_size_set(fs, inode, metadata.len());
+                if err != 0 {
+                    return report(err);
+                }
+                if has_extents {
+                    // an empty extent tree header
+                    let mut handle = MaybeUninit::uninit();
+                    let err =
+                        libe2fs_sys::ext2fs_extent_open2(fs, inum, inode, handle.as_mut_ptr());
+                    if err != 0 {
+                        return report(err);
+                    }
+                    libe2fs_sys::ext2fs_extent_free(handle.assume_init());
+                }
+                Ok(())
+            },
+        )?;
+
+        let file = self.open_file(inum, Some(ExtFileOpenFlags::WRITE))?;
+        let block_size = unsafe { (*fs).blocksize } as usize;
+        let mut host_file = File::open(host_path)?;
+        let mut buf = vec![0u8; block_size * 64];
+        let mut offset = 0u64;
+        loop {
+            let read = read_full(&mut host_file, &mut buf)?;
+            if read == 0 {
+                break;
+            }
+
+            // leave holes for all-zero blocks instead of allocating them
+            for chunk in buf[..read].chunks(block_size) {
+                if chunk.iter().any(|b| *b != 0) {
+                    self.seek(&file, offset, libe2fs_sys::SEEK_SET as i32)?;
+                    let mut written = 0;
+                    let err = unsafe {
+                        libe2fs_sys::ext2fs_file_write(
+                            file.0,
+                            chunk.as_ptr() as *const ::std::ffi::c_void,
+                            chunk.len() as u32,
+                            &mut written,
+                        )
+                    };
+                    if err != 0 {
+                        return report(err);
+                    }
+                    if written as usize != chunk.len() {
+                        return Err(ExtError::EIO.into());
+                    }
+                }
+                offset += chunk.len() as u64;
+            }
+        }
+
+        let err = unsafe { libe2fs_sys::ext2fs_file_set_size2(file.0, metadata.len()) };
+        if err != 0 {
+            return report(err);
+        }
+        let mut file = file;
+        self.close_file(&mut file)?;
+
+        Ok(inum)
+    }
+
+    fn mknod_inode(&self, parent: u32, name: &CStr, metadata: &Metadata) -> Result<u32> {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_char_device() {
+            libe2fs_sys::LINUX_S_IFCHR
+        } else if file_type.is_block_device() {
+            libe2fs_sys::LINUX_S_IFBLK
+        } else if file_type.is_fifo() {
+            libe2fs_sys::LINUX_S_IFIFO
+        } else if file_type.is_socket() {
+            libe2fs_sys::LINUX_S_IFSOCK
+        } else {
+            return Err(eyre!("unsupported file type {file_type:?}"));
+        };
+        let mode = kind as u16 | (metadata.mode() as u16 & 0o7777);
+
+        self.new_linked_inode(
+            parent,
+            name,
+            mode,
+            ext_file_type(metadata),
+            |_inum, inode| {
+                if file_type.is_char_device() || file_type.is_block_device() {
+                    let (major, minor) = dev_major_minor(metadata.rdev());
+                    // same encoding as the kernel's old_encode_dev and
+                    // new_encode_dev, which is what mke2fs does too
+                    if major < 256 && minor < 256 {
+                        inode.i_block[0] = major * 256 + minor;
+                        inode.i_block[1] = 0;
+                    } else {
+                        inode.i_block[0] = 0;
+                        inode.i_block[1] = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+                    }
+                }
+                Ok(())
+            },
+        )
+    }
+
+    fn copy_host_metadata(
+        &self,
+        inum: u32,
+        host_path: &Path,
+        metadata: &Metadata,
+        options: &ExtPopulateOptions,
+    ) -> Result<()> {
+        // setting xattrs changes the ctime, so they go first
+        for (key, value) in host_xattrs(host_path)? {
+            debug!("copying xattr {key} to inode {inum}");
+            self.set_xattr_by_inode(inum, &key, &value)?;
+        }
+
+        let mut inode = self.read_inode(inum)?;
+        inode.1.i_mode =
+            (inode.1.i_mode & libe2fs_sys::LINUX_S_IFMT as u16) | (metadata.mode() as u16 & 0o7777);
+
+        let (uid, gid) = options.owner.unwrap_or((metadata.uid(), metadata.gid()));
+        inode.set_uid(uid);
+        inode.set_gid(gid);
+
+        inode.set_atime(self.clamp(host_time(metadata.atime(), metadata.atime_nsec())));
+        inode.set_mtime(self.clamp(host_time(metadata.mtime(), metadata.mtime_nsec())));
+        inode.set_ctime(self.clamp(host_time(metadata.ctime(), metadata.ctime_nsec())));
+        self.write_inode(&mut inode)
+    }
+}
+
+/// Running totals for [`ExtFilesystem::estimate_size`].
+struct HostTreeUsage {
+    block_size: u64,
+    inode_size: u64,
+    dir_block_tail: u64,
+    data_blocks: u64,
+    inodes: u32,
+    seen: HashSet<(u64, u64)>,
+}
+
+impl HostTreeUsage {
+    /// Adds up everything below `host_dir`, plus `host_dir`'s own directory
+    /// blocks. `extra_names` are the lengths of entries that will be in the
+    /// directory without coming from the host.
+    fn add_dir(
+        &mut self,
+        host_dir: &Path,
+        relative_dir: &Path,
+        options: &ExtPopulateOptions,
+        extra_names: &[usize],
+    ) -> Result<()> {
+        let mut name_lens = extra_names.to_vec();
+        for entry in std::fs::read_dir(host_dir)? {
+            let entry = entry?;
+            let relative_path = relative_dir.join(entry.file_name());
+            if options.is_excluded(&relative_path) {
+                continue;
+            }
+            name_lens.push(entry.file_name().len());
+
+            let host_path = entry.path();
+            let metadata = std::fs::symlink_metadata(&host_path)?;
+            let file_type = metadata.file_type();
+            // hard links only cost a directory entry after the first one
+            if !file_type.is_dir()
+                && metadata.nlink() > 1
+                && !self.seen.insert((metadata.dev(), metadata.ino()))
+            {
+                continue;
+            }
+
+            self.inodes += 1;
+            self.data_blocks += self.xattr_blocks(&host_path)?;
+            if file_type.is_dir() {
+                self.add_dir(&host_path, &relative_path, options, &[])?;
+            } else if file_type.is_file() {
+                let blocks = metadata
+                    .len()
+                    .div_ceil(self.block_size)
+                    .min((metadata.blocks() * 512).div_ceil(self.block_size));
+                self.data_blocks += blocks + self.extent_blocks(blocks);
+            } else if file_type.is_symlink() {
+                // targets that fit in i_block are stored in the inode itself
+                let target = std::fs::read_link(&host_path)?;
+                if target.as_os_str().len() >= std::mem::size_of::<[u32; 15]>() {
+                    self.data_blocks += 1;
+                }
+            }
+        }
+
+        let blocks = self.dir_blocks(name_lens.into_iter());
+        self.data_blocks += blocks + self.extent_blocks(blocks);
+        Ok(())
+    }
+
+    /// Directory blocks needed for `.`, `..` and entries with the given name
+    /// lengths. Entries never span blocks.
+    fn dir_blocks(&self, name_lens: impl Iterator<Item = usize>) -> u64 {
+        let usable = self.block_size - self.dir_block_tail;
+        let mut blocks = 1;
+        let mut used = 24;
+        for len in name_lens {
+            let rec_len = 8 + (len as u64).next_multiple_of(4);
+            if used + rec_len > usable {
+                blocks += 1;
+                used = 0;
+            }
+            used += rec_len;
+        }
+        blocks
+    }
+
+    /// Extent tree blocks for a file of `blocks` blocks, assuming the best
+    /// case of one extent per 32k blocks. The first four live in the inode.
+    fn extent_blocks(&self, blocks: u64) -> u64 {
+        let extents = blocks.div_ceil(32_768);
+        if extents <= 4 {
+            0
+        } else {
+            extents.div_ceil((self.block_size - 12) / 12)
+        }
+    }
+
+    /// Large inodes have room for a few xattrs after their fixed fields,
+    /// anything that doesn't fit goes to a block of its own.
+    fn xattr_blocks(&self, host_path: &Path) -> Result<u64> {
+        let xattrs = host_xattrs(host_path)?;
+        if xattrs.is_empty() {
+            return Ok(0);
+        }
+
+        let size: u64 = xattrs
+            .iter()
+            .map(|(name, value)| {
+                16 + (name.len() as u64).next_multiple_of(4)
+                    + (value.len() as u64).next_multiple_of(4)
+            })
+            .sum();
+        let in_inode = self
+            .inode_size
+            .saturating_sub(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u64 + 32 + 4);
+        Ok(if size + 4 <= in_inode { 0 } else { 1 })
+    }
+}
+
+/// Finds the smallest block count for which [`ExtFilesystem::create`] with
+/// `options` leaves room for `data_blocks` blocks and `inodes` inodes, by
+/// redoing the geometry ext2fs_initialize picks until everything fits.
+fn mkfs_blocks_needed(options: &ExtMkfsOptions, data_blocks: u64, inodes: u32) -> Result<u64> {
+    if let ExtInodeAllocation::Count(count) = options.inodes {
+        if count < inodes {
+            return Err(eyre!(
+                "{inodes} inodes are needed, but the inode count is set to {count}"
+            ));
+        }
+    }
+
+    let block_size = options.block_size as u64;
+    let first_data_block = if block_size == 1_024 { 1 } else { 0 };
+    let blocks_per_group = options
+        .blocks_per_group
+        .map(u64::from)
+        .unwrap_or(block_size * 8);
+    let inode_size = options
+        .inode_size
+        .unwrap_or(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16) as u64;
+    let inodes_per_block = block_size / inode_size;
+    let descs_per_block = block_size / options.group_desc_size() as u64;
+    let sparse_super = options
+        .features
+        .ro_compat
+        .contains(ExtRoCompatFeatures::SPARSE_SUPER);
+
+    let mut blocks = first_data_block + data_blocks;
+    loop {
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group);
+        let fs_inodes = options.inodes_count(blocks * block_size)? as u64;
+        let inodes_per_group = fs_inodes
+            .div_ceil(groups)
+            .next_multiple_of(inodes_per_block.max(8));
+        let backup_blocks =
+            1 + groups.div_ceil(descs_per_block) + options.reserved_gdt_blocks_for(blocks, groups);
+        let backups = (0..groups)
+            .filter(|group| !sparse_super || group_has_sparse_backup(*group))
+            .count() as u64;
+        // the last group is the worst case, as it may carry a backup
+        let group_overhead = backup_blocks + 2 + inodes_per_group / inodes_per_block;
+        let overhead = backups * backup_blocks + groups * (2 + inodes_per_group / inodes_per_block);
+        let journal = match options.journal_size() {
+            None => 0,
+            Some(ExtJournalSize::Blocks(journal_blocks)) => journal_blocks as u64,
+            Some(ExtJournalSize::Default) => {
+                unsafe { libe2fs_sys::ext2fs_default_journal_size(blocks) }.max(0) as u64
+            }
+        };
+        let reserved = (blocks as f64 * options.reserved_percent / 100.0) as u64;
+
+        let mut next = blocks.max(first_data_block + overhead + data_blocks + journal + reserved);
+
+        // libe2fs drops a last group too small to hold its metadata and a
+        // bit more, so either make it big enough or fill it
+        let rem = (blocks - first_data_block) % blocks_per_group;
+        if rem != 0 && rem < group_overhead + 50 {
+            next = next.max(blocks - rem + (group_overhead + 50).min(blocks_per_group));
+        }
+
+        if let ExtInodeAllocation::Ratio(ratio) = options.inodes {
+            if fs_inodes < inodes as u64 {
+                next = next.max((inodes as u64 * ratio).div_ceil(block_size));
+            }
+        }
+
+        if next == blocks {
+            return Ok(blocks);
+        }
+        blocks = next;
+    }
+}
+
+/// With sparse_super, only groups 0, 1 and powers of 3, 5 and 7 carry a
+/// superblock backup.
+fn group_has_sparse_backup(group: u64) -> bool {
+    if group <= 1 {
+        return true;
+    }
+    [3, 5, 7].iter().any(|base| {
+        let mut n = *base;
+        while n < group {
+            n *= base;
+        }
+        n == group
+    })
+}
+
+fn ext_file_type(metadata: &Metadata) -> u32 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        libe2fs_sys::EXT2_FT_DIR
+    } else if file_type.is_symlink() {
+        libe2fs_sys::EXT2_FT_SYMLINK
+    } else if file_type.is_char_device() {
+        libe2fs_sys::EXT2_FT_CHRDEV
+    } else if file_type.is_block_device() {
+        libe2fs_sys::EXT2_FT_BLKDEV
+    } else if file_type.is_fifo() {
+        libe2fs_sys::EXT2_FT_FIFO
+    } else if file_type.is_socket() {
+        libe2fs_sys::EXT2_FT_SOCK
+    } else {
+        libe2fs_sys::EXT2_FT_REG_FILE
+    }
+}
+
+/// glibc's gnu_dev_major and gnu_dev_minor.
+fn dev_major_minor(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
+
+/// A host timestamp as `stat` gives it, which may be before the epoch.
+fn host_time(secs: i64, nsec: i64) -> SystemTime {
+    let time = if secs < 0 {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    } else {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+    };
+    time + Duration::from_nanos(nsec as u64)
+}
+
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(total)
+}
+
+fn host_xattrs(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            // the host filesystem doesn't do xattrs, so there's nothing to copy
+            Some(libc::ENOTSUP) => Ok(vec![]),
+            _ => Err(err.into()),
+        };
+    }
+    if size == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::llistxattr(
+            c_path.as_ptr(),
+            names.as_mut_ptr() as *mut ::std::ffi::c_char,
+            names.len(),
+        )
+    };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    names.truncate(size as usize);
+
+    let mut out = vec![];
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let size =
+            unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut ::std::ffi::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        value.truncate(size as usize);
+        out.push((String::from_utf8(name.to_vec())?, value));
+    }
+
+    Ok(out)
+}
+
+/// A small glob matcher supporting `*`, `**` and `?`.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|i| glob_matches(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_matches(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => {
+            !text.is_empty() && text[0] != b'/' && glob_matches(&pattern[1..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_matches(&pattern[1..], &text[1..]),
+    }
+}
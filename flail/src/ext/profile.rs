@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use super::*;
+
+/// A named set of defaults for [`ExtFilesystem::create`], like the fs types
+/// and usage types of mke2fs.conf. Apply one to a set of options with
+/// [`ExtMkfsOptions::profile`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtProfile {
+    pub(crate) block_size: Option<u32>,
+    pub(crate) inode_ratio: Option<u64>,
+    pub(crate) inode_size: Option<u16>,
+    pub(crate) base_features: Option<ExtFeatures>,
+    pub(crate) enable_features: ExtFeatures,
+    pub(crate) disable_features: ExtFeatures,
+}
+
+impl Default for ExtProfile {
+    /// A profile that changes nothing.
+    fn default() -> Self {
+        Self {
+            block_size: None,
+            inode_ratio: None,
+            inode_size: None,
+            base_features: None,
+            enable_features: ExtFeatures::empty(),
+            disable_features: ExtFeatures::empty(),
+        }
+    }
+}
+
+impl ExtProfile {
+    /// The profiles flail ships with, see [`Self::builtin`].
+    pub const BUILTIN: [&'static str; 7] = [
+        "ext2",
+        "ext3",
+        "ext4",
+        "small",
+        "floppy",
+        "huge",
+        "largefile",
+    ];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// One of the profiles flail ships with, which follow the stock
+    /// mke2fs.conf:
+    ///
+    /// - `ext2`, `ext3` and `ext4` pick the features of each generation, with
+    ///   `ext2` being what old bootloaders can still read.
+    /// - `small` and `floppy` trade space for more inodes on tiny images.
+    /// - `huge` and `largefile` use 4k blocks and few inodes, for volumes of
+    ///   big files.
+    pub fn builtin(name: &str) -> Result<Self> {
+        let ext2 = Self::new()
+            .base_features("sparse_super,large_file,filetype,resize_inode,dir_index,ext_attr")?
+            .inode_size(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16);
+
+        match name {
+            "ext2" => Ok(ext2),
+            "ext3" => ext2.features("has_journal"),
+            "ext4" => ext2
+                .features("has_journal,extent,huge_file,flex_bg,metadata_csum,64bit,dir_nlink")?
+                .inode_size(256),
+            "small" => Ok(Self::new().block_size(1_024).inode_ratio(4_096)),
+            "floppy" => Ok(Self::new()
+                .block_size(1_024)
+                .inode_ratio(8_192)
+                .inode_size(libe2fs_sys::EXT2_GOOD_OLD_INODE_SIZE as u16)),
+            "huge" => Ok(Self::new().block_size(4_096).inode_ratio(65_536)),
+            "largefile" => Ok(Self::new().block_size(4_096).inode_ratio(1_048_576)),
+            _ => Err(eyre!(
+                "unknown profile {name:?}, must be one of {:?}",
+                Self::BUILTIN
+            )),
+        }
+    }
+
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn inode_ratio(mut self, bytes_per_inode: u64) -> Self {
+        self.inode_ratio = Some(bytes_per_inode);
+        self
+    }
+
+    pub fn inode_size(mut self, inode_size: u16) -> Self {
+        self.inode_size = Some(inode_size);
+        self
+    }
+
+    /// Replaces the whole feature set with the comma-separated list of
+    /// `features`, before any edits from [`Self::features`] are applied.
+    pub fn base_features(mut self, features: &str) -> Result<Self> {
+        let mut base = ExtFeatures::empty();
+        base.edit(features)?;
+        self.base_features = Some(base);
+        Ok(self)
+    }
+
+    /// Turns features on or off, in the style of mke2fs' `-O`, see
+    /// [`ExtFeatures::edit`]. Later edits win over earlier ones.
+    pub fn features(mut self, edits: &str) -> Result<Self> {
+        let (enable, disable) = ExtFeatures::parse_edits(edits)?;
+        self.enable_features.remove(disable);
+        self.enable_features.insert(enable);
+        self.disable_features.remove(enable);
+        self.disable_features.insert(disable);
+        Ok(self)
+    }
+
+    pub(crate) fn apply(&self, mut options: ExtMkfsOptions) -> ExtMkfsOptions {
+        if let Some(block_size) = self.block_size {
+            options.block_size = block_size;
+        }
+        if let Some(ratio) = self.inode_ratio {
+            options.inodes = ExtInodeAllocation::Ratio(ratio);
+        }
+        if let Some(inode_size) = self.inode_size {
+            options.inode_size = Some(inode_size);
+        }
+        if let Some(base) = self.base_features {
+            options.features = base;
+        }
+        options.features.remove(self.disable_features);
+        options.features.insert(self.enable_features);
+        options
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "blocksize" => {
+                // mke2fs.conf uses -1 for "as big as the device allows"
+                self.block_size = Some(if value == "-1" { 4_096 } else { value.parse()? });
+            }
+            "inode_ratio" => self.inode_ratio = Some(value.parse()?),
+            "inode_size" => self.inode_size = Some(value.parse()?),
+            "base_features" => *self = self.clone().base_features(value)?,
+            "features" | "default_features" => *self = self.clone().features(value)?,
+            _ => warn!("ignoring unsupported profile setting {key:?}"),
+        }
+        Ok(())
+    }
+}
+
+/// A set of named [`ExtProfile`]s, plus the defaults every one of them builds
+/// on. Start from [`ExtProfiles::builtin`], or [`ExtProfiles::load`] a
+/// mke2fs.conf-style file on top of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtProfiles {
+    pub(crate) defaults: ExtProfile,
+    pub(crate) profiles: HashMap<String, ExtProfile>,
+}
+
+impl ExtProfiles {
+    pub fn builtin() -> Self {
+        Self {
+            defaults: ExtProfile::new(),
+            profiles: ExtProfile::BUILTIN
+                .iter()
+                .map(|name| (name.to_string(), ExtProfile::builtin(name).unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Reads profiles from a file in the format of mke2fs.conf, on top of
+    /// the builtin ones. See [`Self::parse`].
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config = std::fs::read_to_string(path.as_ref())?;
+        Self::parse(&config)
+    }
+
+    /// Parses profiles in the format of mke2fs.conf, on top of the builtin
+    /// ones:
+    ///
+    /// ```text
+    /// [defaults]
+    ///     inode_size = 256
+    ///
+    /// [fs_types]
+    ///     initramfs = {
+    ///         base_features = ext_attr,filetype
+    ///         blocksize = 1024
+    ///         inode_ratio = 2048
+    ///     }
+    /// ```
+    ///
+    /// `blocksize`, `inode_ratio`, `inode_size`, `base_features` and
+    /// `features` are understood, other settings and sections are ignored.
+    /// A profile defined in the file replaces a builtin one of the same name.
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut out = Self::builtin();
+        let mut section = String::new();
+        let mut profile: Option<(String, ExtProfile)> = None;
+
+        for (i, line) in config.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line == "}" {
+                let (name, finished) = profile
+                    .take()
+                    .ok_or_else(|| eyre!("line {line_no}: unmatched '}}'"))?;
+                if section == "fs_types" {
+                    out.profiles.insert(name, finished);
+                }
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if profile.is_some() {
+                    return Err(eyre!("line {line_no}: section started inside a profile"));
+                }
+                section = name.trim().to_string();
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| eyre!("line {line_no}: expected 'key = value', got {line:?}"))?;
+            let key = key.trim();
+            let value = value.trim().trim_matches('"');
+
+            if value == "{" {
+                if profile.is_some() {
+                    return Err(eyre!("line {line_no}: profiles can't be nested"));
+                }
+                profile = Some((key.to_string(), ExtProfile::new()));
+                continue;
+            }
+
+            match (section.as_str(), profile.as_mut()) {
+                ("fs_types", Some((_, profile))) => profile
+                    .set(key, value)
+                    .map_err(|e| eyre!("line {line_no}: {e}"))?,
+                ("defaults", None) => out
+                    .defaults
+                    .set(key, value)
+                    .map_err(|e| eyre!("line {line_no}: {e}"))?,
+                _ => debug!("ignoring {key:?} in section {section:?}"),
+            }
+        }
+
+        if let Some((name, _)) = profile {
+            return Err(eyre!("profile {name:?} is missing its closing '}}'"));
+        }
+
+        Ok(out)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ExtProfile> {
+        self.profiles.get(name)
+    }
+
+    /// Builds options from the defaults followed by each of `names` in order,
+    /// the same way mke2fs combines an fs type with usage types, eg.
+    /// `["ext4", "small"]`.
+    pub fn options(&self, names: &[&str]) -> Result<ExtMkfsOptions> {
+        let mut options = ExtMkfsOptions::new().profile(&self.defaults);
+        for name in names {
+            let profile = self
+                .get(name)
+                .ok_or_else(|| eyre!("unknown profile {name:?}"))?;
+            options = options.profile(profile);
+        }
+        options.validate()?;
+        Ok(options)
+    }
+}
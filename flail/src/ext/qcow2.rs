@@ -0,0 +1,762 @@
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::*;
+
+// The qcow2 format as written by QEMU, see docs/interop/qcow2.txt there.
+const MAGIC: u32 = 0x5146_49fb;
+const VERSION: u32 = 3;
+const HEADER_LENGTH: usize = 104;
+const V2_HEADER_LENGTH: usize = 72;
+const CLUSTER_BITS: u32 = 16;
+const REFCOUNT_ORDER: u32 = 4;
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+const MAX_BACKING_DEPTH: usize = 16;
+/// The longest backing file name qemu accepts.
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+
+/// A qcow2 disk image, as used by QEMU, that filesystems can live in.
+///
+/// Clusters are allocated as they're first written, so the image only takes
+/// as much space as the filesystem has written. Images can have a backing
+/// file, raw or qcow2, that clusters the image hasn't written yet are read
+/// from. Backing files are never written to. Compressed clusters, encryption
+/// and writing to images with internal snapshots aren't supported.
+pub struct ExtQcow2Image {
+    file: File,
+    path: PathBuf,
+    writable: bool,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    refcount_order: u32,
+    l1_table_offset: u64,
+    l1: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    next_cluster: u64,
+    backing_file: Option<PathBuf>,
+    backing: Option<Box<Backing>>,
+}
+
+enum Backing {
+    Raw(File, u64),
+    Qcow2(ExtQcow2Image),
+}
+
+impl Backing {
+    fn open(path: &Path, depth: usize) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut magic = [0; 4];
+        let len = file.metadata()?.len();
+        if len >= 4 {
+            file.read_exact_at(&mut magic, 0)?;
+        }
+        if BigEndian::read_u32(&magic) == MAGIC {
+            Ok(Self::Qcow2(ExtQcow2Image::open_in(path, false, depth)?))
+        } else {
+            Ok(Self::Raw(file, len))
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Self::Raw(_, len) => *len,
+            Self::Qcow2(image) => image.size,
+        }
+    }
+
+    /// Reads like [`IoBackend::read_at`], with zeroes past the end.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let end = self.len().clamp(offset, offset + buf.len() as u64);
+        let (inside, past) = buf.split_at_mut((end - offset) as usize);
+        past.fill(0);
+        if inside.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Self::Raw(file, _) => Ok(file.read_exact_at(inside, offset)?),
+            Self::Qcow2(image) => image.read_at(offset, inside),
+        }
+    }
+}
+
+impl ExtQcow2Image {
+    /// Creates an empty image at `path` with room for `size` bytes.
+    pub fn create<P: AsRef<Path>>(path: P, size: u64) -> Result<Self> {
+        Self::create_in(path.as_ref(), size, None)
+    }
+
+    /// Creates an image at `path` that starts out as a copy of
+    /// `backing_file`, and is the same size. A relative `backing_file` is
+    /// relative to the directory of `path`, like in QEMU.
+    pub fn create_with_backing<P: AsRef<Path>, B: AsRef<Path>>(
+        path: P,
+        backing_file: B,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let backing_file = backing_file.as_ref();
+        let size = Backing::open(&resolve_backing_file(path, backing_file), 0)?.len();
+        Self::create_in(path, size, Some(backing_file))
+    }
+
+    fn create_in(path: &Path, size: u64, backing_file: Option<&Path>) -> Result<Self> {
+        debug!("creating {size} byte qcow2 image at {path:?}");
+        let cluster_size = 1u64 << CLUSTER_BITS;
+        let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        // cluster 0 holds the header and backing file name, cluster 1 the
+        // refcount table, cluster 2 the first refcount block, and the L1
+        // table comes after
+        let mut header = vec![0; cluster_size as usize];
+        BigEndian::write_u32(&mut header[..4], MAGIC);
+        BigEndian::write_u32(&mut header[4..8], VERSION);
+        if let Some(backing_file) = backing_file {
+            let name = backing_file.as_os_str().as_bytes();
+            // after the header and the end of its (empty) extensions
+            let name_offset = HEADER_LENGTH + 8;
+            if name_offset + name.len() > header.len() {
+                return Err(eyre!("backing file name {backing_file:?} is too long"));
+            }
+            header[name_offset..name_offset + name.len()].copy_from_slice(name);
+            BigEndian::write_u64(&mut header[8..16], name_offset as u64);
+            BigEndian::write_u32(&mut header[16..20], name.len() as u32);
+        }
+        BigEndian::write_u32(&mut header[20..24], CLUSTER_BITS);
+        BigEndian::write_u64(&mut header[24..32], size);
+        BigEndian::write_u32(&mut header[36..40], l1_size as u32);
+        BigEndian::write_u64(&mut header[40..48], 3 * cluster_size);
+        BigEndian::write_u64(&mut header[48..56], cluster_size);
+        BigEndian::write_u32(&mut header[56..60], 1);
+        BigEndian::write_u32(&mut header[96..100], REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut header[100..104], HEADER_LENGTH as u32);
+
+        let mut refcount_table = vec![0; cluster_size as usize];
+        BigEndian::write_u64(&mut refcount_table[..8], 2 * cluster_size);
+        let used_clusters = 3 + l1_clusters;
+        let mut refcount_block = vec![0; cluster_size as usize];
+        if used_clusters as usize > refcount_block.len() / 2 {
+            return Err(eyre!("{size} bytes is too big for a qcow2 image"));
+        }
+        for refcount in refcount_block
+            .chunks_exact_mut(2)
+            .take(used_clusters as usize)
+        {
+            BigEndian::write_u16(refcount, 1);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all_at(&header, 0)?;
+        file.write_all_at(&refcount_table, cluster_size)?;
+        file.write_all_at(&refcount_block, 2 * cluster_size)?;
+        file.set_len(used_clusters * cluster_size)?;
+        file.sync_all()?;
+        drop(file);
+
+        Self::open(path)
+    }
+
+    /// Opens the image at `path` for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_in(path.as_ref(), true, 0)
+    }
+
+    /// Opens the image at `path` for reading only. Writes to it fail.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_in(path.as_ref(), false, 0)
+    }
+
+    fn open_in(path: &Path, writable: bool, depth: usize) -> Result<Self> {
+        debug!("opening qcow2 image at {path:?}");
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let mut header = [0; HEADER_LENGTH];
+        file.read_exact_at(&mut header[..V2_HEADER_LENGTH], 0)?;
+        if BigEndian::read_u32(&header[..4]) != MAGIC {
+            return Err(eyre!("{path:?} isn't a qcow2 image"));
+        }
+        let version = BigEndian::read_u32(&header[4..8]);
+        let refcount_order = match version {
+            2 => 4,
+            3 => {
+                file.read_exact_at(&mut header[V2_HEADER_LENGTH..], V2_HEADER_LENGTH as u64)?;
+                let incompatible = BigEndian::read_u64(&header[72..80]);
+                if incompatible != 0 {
+                    return Err(eyre!(
+                        "{path:?} uses unsupported qcow2 features {incompatible:#x}, it may need `qemu-img check -r all`"
+                    ));
+                }
+                BigEndian::read_u32(&header[96..100])
+            }
+            _ => return Err(eyre!("{path:?} is unsupported qcow2 version {version}")),
+        };
+
+        let backing_file_offset = BigEndian::read_u64(&header[8..16]);
+        let backing_file_size = BigEndian::read_u32(&header[16..20]);
+        let cluster_bits = BigEndian::read_u32(&header[20..24]);
+        let size = BigEndian::read_u64(&header[24..32]);
+        let crypt_method = BigEndian::read_u32(&header[32..36]);
+        let l1_size = BigEndian::read_u32(&header[36..40]);
+        let l1_table_offset = BigEndian::read_u64(&header[40..48]);
+        let refcount_table_offset = BigEndian::read_u64(&header[48..56]);
+        let refcount_table_clusters = BigEndian::read_u32(&header[56..60]);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(eyre!("{path:?} has a bad cluster size"));
+        }
+        if crypt_method != 0 {
+            return Err(eyre!("{path:?} is encrypted, which isn't supported"));
+        }
+        if !(3..=6).contains(&refcount_order) {
+            return Err(eyre!(
+                "{path:?} has refcount order {refcount_order}, which isn't supported"
+            ));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        if (l1_size as u64) < size.div_ceil(cluster_size * (cluster_size / 8)) {
+            return Err(eyre!("{path:?} has an L1 table too small for its size"));
+        }
+
+        // check the tables fit in the file before allocating room for them
+        let file_len = file.metadata()?.len();
+        let fits =
+            |offset: u64, len: u64| offset.checked_add(len).is_some_and(|end| end <= file_len);
+        let refcount_table_len = refcount_table_clusters as u64 * cluster_size;
+        if !fits(l1_table_offset, l1_size as u64 * 8) {
+            return Err(eyre!("{path:?} has an L1 table past the end of the file"));
+        }
+        if !fits(refcount_table_offset, refcount_table_len) {
+            return Err(eyre!(
+                "{path:?} has a refcount table past the end of the file"
+            ));
+        }
+        if backing_file_offset != 0
+            && (backing_file_size > MAX_BACKING_FILE_NAME
+                || !fits(backing_file_offset, backing_file_size as u64))
+        {
+            return Err(eyre!("{path:?} has a bad backing file name"));
+        }
+
+        // writers that don't understand an autoclear feature, like dirty
+        // bitmaps, must clear it so readers know it's stale. version 2
+        // headers end before the field, leaving it zeroed.
+        let autoclear = BigEndian::read_u64(&header[88..96]);
+        if writable && autoclear != 0 {
+            debug!("clearing qcow2 autoclear features {autoclear:#x} of {path:?}");
+            header[88..96].fill(0);
+            file.write_all_at(&header[88..96], 88)?;
+        }
+
+        let l1 = read_table(&file, l1_table_offset, l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            refcount_table_offset,
+            (refcount_table_len / 8) as usize,
+        )?;
+
+        let (backing_file, backing) = if backing_file_offset != 0 {
+            if depth >= MAX_BACKING_DEPTH {
+                return Err(eyre!("too many backing files under {path:?}"));
+            }
+            let mut name = vec![0; backing_file_size as usize];
+            file.read_exact_at(&mut name, backing_file_offset)?;
+            let name = PathBuf::from(std::ffi::OsStr::from_bytes(&name));
+            let backing = Backing::open(&resolve_backing_file(path, &name), depth + 1)?;
+            (Some(name), Some(Box::new(backing)))
+        } else {
+            (None, None)
+        };
+
+        let next_cluster = file_len.div_ceil(cluster_size) * cluster_size;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            writable,
+            version,
+            size,
+            cluster_bits,
+            refcount_order,
+            l1_table_offset,
+            l1,
+            refcount_table_offset,
+            refcount_table,
+            next_cluster,
+            backing_file,
+            backing,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the disk, rather than of the image file.
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// The backing file, as written in the image.
+    pub fn backing_file(&self) -> Option<&Path> {
+        self.backing_file.as_deref()
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Splits `len` bytes at `offset` at cluster boundaries, into their
+    /// offsets and ranges in a buffer of `len` bytes.
+    fn split(&self, offset: u64, len: usize) -> Vec<(u64, Range<usize>)> {
+        let mut pieces = vec![];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let left_in_cluster = self.cluster_size() - (pos & (self.cluster_size() - 1));
+            let piece = left_in_cluster.min((len - done) as u64) as usize;
+            pieces.push((pos, done..done + piece));
+            done += piece;
+        }
+        pieces
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(eyre!(
+                "{len} bytes at offset {offset} are past the end of the {} byte disk",
+                self.size
+            )),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(eyre!("{:?} is open read-only", self.path))
+        }
+    }
+
+    /// The L2 entry for the cluster holding `pos`, 0 if there's no L2 table
+    /// for it yet.
+    fn l2_entry(&self, pos: u64) -> Result<u64> {
+        let cluster = pos >> self.cluster_bits;
+        let l2_table = self.l1[(cluster / self.l2_entries()) as usize] & OFFSET_MASK;
+        if l2_table == 0 {
+            return Ok(0);
+        }
+        read_u64(&self.file, l2_table + (cluster % self.l2_entries()) * 8)
+    }
+
+    fn read_backing(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        match &mut self.backing {
+            Some(backing) => backing.read_at(pos, buf),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// The host offset of the cluster holding `pos`, allocating it and its
+    /// L2 table if they don't exist yet.
+    fn cluster_for_write(&mut self, pos: u64) -> Result<u64> {
+        let cluster = pos >> self.cluster_bits;
+        let l2_table = self.l2_table_for_write((cluster / self.l2_entries()) as usize)?;
+        let entry_offset = l2_table + (cluster % self.l2_entries()) * 8;
+        let entry = read_u64(&self.file, entry_offset)?;
+        if entry & COMPRESSED != 0 {
+            return Err(eyre!("writing to compressed clusters isn't supported"));
+        }
+
+        let host = entry & OFFSET_MASK;
+        let zero = self.version >= 3 && entry & ZERO != 0;
+        if host != 0 && entry & COPIED == 0 {
+            return Err(eyre!(
+                "the cluster at {pos} is shared with a snapshot, which isn't supported"
+            ));
+        }
+        if host != 0 && !zero {
+            return Ok(host);
+        }
+
+        // start the new cluster off with what it reads as now
+        let mut data = vec![0; self.cluster_size() as usize];
+        if !zero {
+            self.read_backing(cluster << self.cluster_bits, &mut data)?;
+        }
+        let host = if host != 0 {
+            host
+        } else {
+            self.alloc_clusters(1)?
+        };
+        self.file.write_all_at(&data, host)?;
+        write_u64(&self.file, entry_offset, host | COPIED)?;
+        Ok(host)
+    }
+
+    fn l2_table_for_write(&mut self, l1_index: usize) -> Result<u64> {
+        let entry = self.l1[l1_index];
+        let l2_table = entry & OFFSET_MASK;
+        if l2_table != 0 {
+            if entry & COPIED == 0 {
+                return Err(eyre!(
+                    "an L2 table is shared with a snapshot, which isn't supported"
+                ));
+            }
+            return Ok(l2_table);
+        }
+
+        let l2_table = self.alloc_clusters(1)?;
+        self.file
+            .write_all_at(&vec![0; self.cluster_size() as usize], l2_table)?;
+        self.l1[l1_index] = l2_table | COPIED;
+        write_u64(
+            &self.file,
+            self.l1_table_offset + l1_index as u64 * 8,
+            l2_table | COPIED,
+        )?;
+        Ok(l2_table)
+    }
+
+    /// Allocates `count` clusters in a row at the end of the image.
+    fn alloc_clusters(&mut self, count: u64) -> Result<u64> {
+        let host = self.next_cluster;
+        self.next_cluster += count * self.cluster_size();
+        for cluster in 0..count {
+            self.set_refcount((host >> self.cluster_bits) + cluster, 1)?;
+        }
+        Ok(host)
+    }
+
+    fn set_refcount(&mut self, cluster: u64, refcount: u64) -> Result<()> {
+        let width = (1u64 << self.refcount_order) / 8;
+        let refcounts_per_block = self.cluster_size() / width;
+        let table_index = (cluster / refcounts_per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(eyre!("the refcount table of {:?} is full", self.path));
+        }
+
+        let mut block = self.refcount_table[table_index] & OFFSET_MASK;
+        if block == 0 {
+            block = self.next_cluster;
+            self.next_cluster += self.cluster_size();
+            self.file
+                .write_all_at(&vec![0; self.cluster_size() as usize], block)?;
+            self.refcount_table[table_index] = block;
+            write_u64(
+                &self.file,
+                self.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            self.set_refcount(block >> self.cluster_bits, 1)?;
+        }
+
+        let mut entry = [0; 8];
+        BigEndian::write_uint(&mut entry[..width as usize], refcount, width as usize);
+        self.file.write_all_at(
+            &entry[..width as usize],
+            block + (cluster % refcounts_per_block) * width,
+        )?;
+        Ok(())
+    }
+}
+
+impl IoBackend for ExtQcow2Image {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        for (pos, range) in self.split(offset, buf.len()) {
+            let piece = &mut buf[range];
+            let entry = self.l2_entry(pos)?;
+            let host = entry & OFFSET_MASK;
+            if entry & COMPRESSED != 0 {
+                return Err(eyre!("reading compressed clusters isn't supported"));
+            } else if self.version >= 3 && entry & ZERO != 0 {
+                piece.fill(0);
+            } else if host != 0 {
+                self.file
+                    .read_exact_at(piece, host + (pos & (self.cluster_size() - 1)))?;
+            } else {
+                self.read_backing(pos, piece)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, buf.len())?;
+        for (pos, range) in self.split(offset, buf.len()) {
+            let host = self.cluster_for_write(pos)?;
+            self.file
+                .write_all_at(&buf[range], host + (pos & (self.cluster_size() - 1)))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.writable {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Zeroes without allocating clusters that already read as zeroes.
+    fn zeroout(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, len as usize)?;
+        for (pos, range) in self.split(offset, len as usize) {
+            if self.backing.is_none() && self.l2_entry(pos)? & OFFSET_MASK == 0 {
+                continue;
+            }
+            self.write_at(pos, &vec![0; range.len()])?;
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.check_writable()?;
+        debug!("resizing {:?} to {size} bytes", self.path);
+        let cluster_size = self.cluster_size();
+        let l1_size = size.div_ceil(cluster_size * self.l2_entries()) as usize;
+        if l1_size > self.l1.len() {
+            let old_clusters = (self.l1.len() as u64 * 8).div_ceil(cluster_size).max(1);
+            let new_clusters = (l1_size as u64 * 8).div_ceil(cluster_size);
+            let mut table = vec![0; (new_clusters * cluster_size) as usize];
+            let old_len = self.l1.len();
+            self.l1.resize(l1_size, 0);
+            for (entry, raw) in self.l1.iter().zip(table.chunks_exact_mut(8)) {
+                BigEndian::write_u64(raw, *entry);
+            }
+
+            if new_clusters <= old_clusters {
+                self.file.write_all_at(
+                    &table[old_len * 8..l1_size * 8],
+                    self.l1_table_offset + old_len as u64 * 8,
+                )?;
+            } else {
+                // the table has to move somewhere with room for it
+                let old_offset = self.l1_table_offset;
+                let new_offset = self.alloc_clusters(new_clusters)?;
+                self.file.write_all_at(&table, new_offset)?;
+                self.file.sync_data()?;
+                write_u64(&self.file, 40, new_offset)?;
+                self.l1_table_offset = new_offset;
+                for cluster in 0..old_clusters {
+                    self.set_refcount((old_offset >> self.cluster_bits) + cluster, 0)?;
+                }
+            }
+
+            let mut l1_size_raw = [0; 4];
+            BigEndian::write_u32(&mut l1_size_raw, l1_size as u32);
+            self.file.write_all_at(&l1_size_raw, 36)?;
+        }
+
+        write_u64(&self.file, 24, size)?;
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Backing file names are relative to the image they're in.
+fn resolve_backing_file(image: &Path, backing_file: &Path) -> PathBuf {
+    match image.parent() {
+        Some(dir) if backing_file.is_relative() => dir.join(backing_file),
+        _ => backing_file.to_path_buf(),
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> Result<Vec<u64>> {
+    let mut raw = vec![0; entries * 8];
+    file.read_exact_at(&mut raw, offset)?;
+    Ok(raw.chunks_exact(8).map(BigEndian::read_u64).collect())
+}
+
+fn read_u64(file: &File, offset: u64) -> Result<u64> {
+    let mut raw = [0; 8];
+    file.read_exact_at(&mut raw, offset)?;
+    Ok(BigEndian::read_u64(&raw))
+}
+
+fn write_u64(file: &File, offset: u64, value: u64) -> Result<()> {
+    Ok(file.write_all_at(&value.to_be_bytes(), offset)?)
+}
+
+impl ExtFilesystem {
+    /// Creates a qcow2 image of `size_bytes` at `path`, with a filesystem
+    /// filling all of it.
+    pub fn create_qcow2<P: AsRef<Path>>(
+        path: P,
+        size_bytes: u64,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<Self> {
+        Self::create_with_io(
+            ExtQcow2Image::create(path, size_bytes)?,
+            size_bytes,
+            options,
+        )
+    }
+
+    /// Opens the filesystem in the qcow2 image at `path`, by default for
+    /// reading and writing. Without [`ExtFilesystemOpenFlags::OPEN_RW`] the
+    /// image itself is opened read-only too.
+    pub fn open_qcow2<P: AsRef<Path>>(
+        path: P,
+        flags: Option<ExtFilesystemOpenFlags>,
+    ) -> Result<Self> {
+        let flags =
+            flags.unwrap_or(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW);
+        let writable = flags.contains(ExtFilesystemOpenFlags::OPEN_RW);
+        Self::open_with_io(
+            ExtQcow2Image::open_in(path.as_ref(), writable, 0)?,
+            ExtOpenOptions::new().flags(flags),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    fn assert_qcow2_fsck_clean(path: &Path) -> Result<()> {
+        let mut image = ExtQcow2Image::open_read_only(path)?;
+        let mut raw = vec![0; image.len() as usize];
+        image.read_at(0, &mut raw)?;
+
+        let temp = TempDir::new()?;
+        let raw_path = temp.path_view().join("raw.ext4");
+        fs::write(&raw_path, raw)?;
+        assert_fsck_clean(&raw_path)
+    }
+
+    fn read_to_string(fs: &ExtFilesystem, path: &str) -> Result<String> {
+        let inode = fs.find_inode(path)?;
+        let file = fs.open_file(inode.0, None)?;
+        let mut buf = vec![0u8; inode.size() as usize];
+        fs.read_file(&file, &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    #[test]
+    pub fn test_qcow2_images_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let path = temp.path_view().join("disk.qcow2");
+        let size = 64 * 1024 * 1024;
+
+        {
+            let fs = ExtFilesystem::create_qcow2(&path, size, None)?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+        // only what's been written takes up space
+        assert!(fs::metadata(&path)?.len() < size / 2);
+        assert_qcow2_fsck_clean(&path)?;
+
+        {
+            let fs = ExtFilesystem::open_qcow2(&path, None)?;
+            assert_eq!("hello flail", read_to_string(&fs, "/test.txt")?);
+            fs.resize(2 * size)?;
+        }
+        let mut image = ExtQcow2Image::open_read_only(&path)?;
+        assert_eq!(2 * size, image.len());
+        assert!(image.write_at(0, &[0]).is_err());
+        drop(image);
+        assert_qcow2_fsck_clean(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_qcow2_headers_are_checked() -> Result<()> {
+        let temp = TempDir::new()?;
+        let path = temp.path_view().join("disk.qcow2");
+        ExtFilesystem::create_qcow2(&path, 64 * 1024 * 1024, None)?;
+        let original = fs::read(&path)?;
+        let patch = |offset: u64, bytes: &[u8]| -> Result<()> {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .write_all_at(bytes, offset)?;
+            Ok(())
+        };
+
+        // autoclear features are only cleared when the image is written
+        patch(88, &1u64.to_be_bytes())?;
+        drop(ExtFilesystem::open_qcow2(
+            &path,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT),
+        )?);
+        assert_eq!(1u64.to_be_bytes(), fs::read(&path)?[88..96]);
+        drop(ExtFilesystem::open_qcow2(&path, None)?);
+        assert_eq!([0; 8], fs::read(&path)?[88..96]);
+
+        // an L1 table far larger than the file
+        fs::write(&path, &original)?;
+        patch(36, &u32::MAX.to_be_bytes())?;
+        assert!(ExtQcow2Image::open_read_only(&path).is_err());
+
+        // a backing file name far larger than the file
+        fs::write(&path, &original)?;
+        patch(8, &512u64.to_be_bytes())?;
+        patch(16, &u32::MAX.to_be_bytes())?;
+        assert!(ExtQcow2Image::open_read_only(&path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_qcow2_backing_files_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let base = temp.path_view().join("base.qcow2");
+        let overlay = temp.path_view().join("overlay.qcow2");
+
+        {
+            let fs = ExtFilesystem::create_qcow2(&base, 64 * 1024 * 1024, None)?;
+            fs.write_to_file("/base.txt", "hello base".as_bytes())?;
+        }
+        let base_contents = fs::read(&base)?;
+
+        let image = ExtQcow2Image::create_with_backing(&overlay, "base.qcow2")?;
+        assert_eq!(Some(Path::new("base.qcow2")), image.backing_file());
+        {
+            let fs = ExtFilesystem::open_with_io(
+                image,
+                ExtOpenOptions::new()
+                    .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+            )?;
+            fs.write_to_file("/overlay.txt", "hello overlay".as_bytes())?;
+        }
+        assert_eq!(base_contents, fs::read(&base)?);
+        assert_qcow2_fsck_clean(&overlay)?;
+
+        let fs = ExtFilesystem::open_qcow2(&overlay, None)?;
+        assert_eq!("hello base", read_to_string(&fs, "/base.txt")?);
+        assert_eq!("hello overlay", read_to_string(&fs, "/overlay.txt")?);
+        drop(fs);
+
+        let fs = ExtFilesystem::open_qcow2(&base, None)?;
+        assert!(fs.find_inode("/overlay.txt").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use super::acl::{ExtAcl, ExtAclKind};
+use super::*;
+
+/// A filesystem opened for inspection only.
+///
+/// Only reading methods are available, and nothing is written to the image
+/// at any point, not even on drop, so it's safe to use on write-protected
+/// images and on images something else has open. The underlying
+/// [`ExtFilesystem`] is always opened without
+/// [`ExtFilesystemOpenFlags::OPEN_RW`], so even if it's reached some other
+/// way, its mutating methods fail with `EROFS`.
+#[derive(Debug)]
+pub struct ExtReadOnlyFilesystem(ExtFilesystem);
+
+impl ExtReadOnlyFilesystem {
+    pub fn open<P: Into<PathBuf> + std::fmt::Debug>(name: P) -> Result<Self> {
+        Self::open_with_options(name, ExtOpenOptions::new())
+    }
+
+    /// Opens with `options`, minus [`ExtFilesystemOpenFlags::OPEN_RW`].
+    /// Undo files record writes, so they aren't allowed.
+    pub fn open_with_options<P: Into<PathBuf> + std::fmt::Debug>(
+        name: P,
+        options: ExtOpenOptions,
+    ) -> Result<Self> {
+        let options = Self::read_only(options)?;
+        Ok(Self(ExtFilesystem::open_with_options(name, options)?))
+    }
+
+    pub fn open_with_io<B: IoBackend + 'static>(
+        backend: B,
+        options: ExtOpenOptions,
+    ) -> Result<Self> {
+        let options = Self::read_only(options)?;
+        Ok(Self(ExtFilesystem::open_with_io(backend, options)?))
+    }
+
+    pub fn open_in_memory(image: &ExtMemoryImage) -> Result<Self> {
+        Self::open_with_io(image.clone(), ExtOpenOptions::new())
+    }
+
+    fn read_only(options: ExtOpenOptions) -> Result<ExtOpenOptions> {
+        if options.undo_file.is_some() {
+            return Err(eyre!("read-only filesystems can't record undo files"));
+        }
+        let flags = options.flags - ExtFilesystemOpenFlags::OPEN_RW;
+        Ok(options.flags(flags))
+    }
+
+    pub fn window(&self) -> ExtImageWindow {
+        self.0.window()
+    }
+
+    pub fn features(&self) -> ExtFeatures {
+        self.0.features()
+    }
+
+    pub fn io_stats(&self) -> Result<IoStats> {
+        self.0.io_stats()
+    }
+
+    pub fn io_trace(&self) -> Option<IoTrace> {
+        self.0.io_trace()
+    }
+
+    pub fn iterate_dir<F, P: Into<PathBuf>>(&self, dir: P, f: F) -> Result<()>
+    where
+        F: FnMut(
+            *mut libe2fs_sys::ext2_dir_entry,
+            i32,
+            i32,
+            &str,
+            &[::std::ffi::c_char],
+        ) -> Result<i32>,
+    {
+        self.0.iterate_dir(dir, f)
+    }
+
+    pub fn root_inode(&self) -> Result<ExtInode> {
+        self.0.root_inode()
+    }
+
+    pub fn read_inode(&self, inode: u32) -> Result<ExtInode> {
+        self.0.read_inode(inode)
+    }
+
+    pub fn find_inode<P: Into<PathBuf>>(&self, path: P) -> Result<ExtInode> {
+        self.0.find_inode(path)
+    }
+
+    pub fn find_inode_follow<P: Into<PathBuf>>(&self, path: P) -> Result<ExtInode> {
+        self.0.find_inode_follow(path)
+    }
+
+    pub fn lookup<P: Into<PathBuf> + Clone>(&self, dir: P, name: &str) -> Result<ExtInode> {
+        self.0.lookup(dir, name)
+    }
+
+    pub fn get_pathname(&self, inode: u32) -> Result<String> {
+        self.0.get_pathname(inode)
+    }
+
+    /// Opens a file for reading.
+    pub fn open_file(&self, inode: u32) -> Result<ExtFile> {
+        self.0.open_file(inode, None)
+    }
+
+    pub fn close_file(&self, file: &mut ExtFile) -> Result<()> {
+        self.0.close_file(file)
+    }
+
+    pub fn get_inode(&self, file: &ExtFile) -> Result<ExtInode> {
+        self.0.get_inode(file)
+    }
+
+    pub fn get_inode_number(&self, file: &ExtFile) -> Result<u32> {
+        self.0.get_inode_number(file)
+    }
+
+    pub fn read_file(&self, file: &ExtFile, buf: &mut [u8]) -> Result<usize> {
+        self.0.read_file(file, buf)
+    }
+
+    pub fn seek(&self, file: &ExtFile, offset: u64, direction: i32) -> Result<()> {
+        self.0.seek(file, offset, direction)
+    }
+
+    pub fn list_xattrs<P: Into<PathBuf>>(&self, path: P) -> Result<Vec<String>> {
+        self.0.list_xattrs(path)
+    }
+
+    pub fn list_xattrs_by_inode(&self, inode: u32) -> Result<Vec<String>> {
+        self.0.list_xattrs_by_inode(inode)
+    }
+
+    pub fn get_xattr<P: Into<PathBuf>>(&self, path: P, key: &str) -> Result<Option<Vec<u8>>> {
+        self.0.get_xattr(path, key)
+    }
+
+    pub fn get_xattr_by_inode(&self, inode: u32, key: &str) -> Result<Option<Vec<u8>>> {
+        self.0.get_xattr_by_inode(inode, key)
+    }
+
+    pub fn get_acl<P: Into<PathBuf>>(&self, path: P, kind: ExtAclKind) -> Result<Option<ExtAcl>> {
+        self.0.get_acl(path, kind)
+    }
+
+    pub fn get_acl_by_inode(&self, inode: u32, kind: ExtAclKind) -> Result<Option<ExtAcl>> {
+        self.0.get_acl_by_inode(inode, kind)
+    }
+
+    pub fn inode_bitmap(&self) -> ExtInodeBitmap {
+        self.0.inode_bitmap()
+    }
+
+    pub fn block_bitmap(&self) -> ExtBlockBitmap {
+        self.0.block_bitmap()
+    }
+
+    /// The read-only filesystem underneath, for anything not wrapped here.
+    pub fn as_filesystem(&self) -> &ExtFilesystem {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::ext::tests::{TempDir, TempImage};
+
+    #[test]
+    pub fn test_read_only_opens_change_nothing() -> Result<()> {
+        let temp = TempDir::new()?;
+        let path = temp.path_view().join("empty.ext4");
+        fs::copy("./fixtures/empty.ext4", &path)?;
+        {
+            let fs = ExtFilesystem::open(
+                &path,
+                None,
+                Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+            )?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+        let before = fs::read(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o444))?;
+
+        {
+            let fs = ExtReadOnlyFilesystem::open(&path)?;
+            let file = fs.open_file(fs.find_inode("/test.txt")?.0)?;
+            let mut buf = [0u8; 11];
+            fs.read_file(&file, &mut buf)?;
+            assert_eq!(b"hello flail", &buf);
+
+            // mutating through the filesystem underneath fails cleanly
+            let err = fs
+                .as_filesystem()
+                .write_to_file("/other.txt", "nope".as_bytes())
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ExtError>(),
+                Some(ExtError::EROFS)
+            ));
+        }
+        assert_eq!(before, fs::read(&path)?);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_read_only_flags_are_enforced() -> Result<()> {
+        let img = TempImage::new("./fixtures/empty.ext4")?;
+        let before = fs::read(img.path_view())?;
+
+        let fs = ExtFilesystem::open(img.path_view(), None, None)?;
+        assert!(fs.is_read_only());
+        for result in [
+            fs.mkdir("/", "dir"),
+            fs.unlink("/lost+found"),
+            fs.write_bitmaps(),
+            fs.flush(),
+        ] {
+            assert!(matches!(
+                result.unwrap_err().downcast_ref::<ExtError>(),
+                Some(ExtError::EROFS)
+            ));
+        }
+        assert!(fs.resize(64 * 1024 * 1024).is_err());
+        drop(fs);
+        assert_eq!(before, fs::read(img.path_view())?);
+
+        assert!(ExtReadOnlyFilesystem::open_with_options(
+            img.path_view(),
+            ExtOpenOptions::new().undo_file("/tmp/nope.e2undo")
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
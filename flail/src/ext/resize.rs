@@ -0,0 +1,1221 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+
+use super::*;
+
+impl ExtFilesystem {
+    /// The smallest size, in bytes, that this filesystem can be shrunk to
+    /// with [`Self::shrink_to_fit`]. A little slack is left over so that
+    /// extent trees have room to grow while blocks are being moved.
+    pub fn minimum_size(&self) -> Result<u64> {
+        let fs = *self.0.read().unwrap();
+        self.check_resizable()?;
+        let geometry = ExtGeometry::of(fs);
+        let superblock = unsafe { *(*fs).super_ };
+
+        let used_inodes = superblock.s_inodes_count - superblock.s_free_inodes_count;
+        let used_blocks = unsafe {
+            libe2fs_sys::ext2fs_blocks_count((*fs).super_)
+                - libe2fs_sys::ext2fs_free_blocks_count((*fs).super_)
+        };
+        let overhead: u64 = (0..geometry.groups)
+            .map(|group| geometry.group_overhead(fs, group, geometry.desc_blocks))
+            .sum();
+        let data_blocks = used_blocks.saturating_sub(geometry.first_data_block + overhead);
+        let needed = data_blocks + data_blocks / 64 + 32;
+        debug!("minimum_size: {data_blocks} data blocks, {used_inodes} inodes in use");
+
+        let mut groups = used_inodes.div_ceil(geometry.inodes_per_group).max(1);
+        loop {
+            let desc_blocks = geometry.desc_blocks_for(groups);
+            let capacity = |groups: u32| -> u64 {
+                (0..groups)
+                    .map(|group| {
+                        geometry.blocks_per_group - geometry.group_overhead(fs, group, desc_blocks)
+                    })
+                    .sum()
+            };
+
+            if capacity(groups) >= needed {
+                // only use as much of the last group as we need, while
+                // keeping it big enough that libe2fs won't consider it junk
+                let last_overhead = geometry.group_overhead(fs, groups - 1, desc_blocks);
+                let in_last_group = needed.saturating_sub(capacity(groups - 1));
+                let last_group_blocks = (last_overhead + in_last_group)
+                    .max(last_overhead + 50)
+                    .min(geometry.blocks_per_group);
+                let blocks = geometry.first_data_block
+                    + (groups as u64 - 1) * geometry.blocks_per_group
+                    + last_group_blocks;
+                let blocks = blocks.min(geometry.blocks);
+                return Ok(blocks * geometry.block_size);
+            }
+
+            if groups >= geometry.groups {
+                return Ok(geometry.blocks * geometry.block_size);
+            }
+            groups += 1;
+        }
+    }
+
+    /// Moves all data down to the start of the filesystem, shrinks it to
+    /// [`Self::minimum_size`], and truncates the backing file to match.
+    /// Returns the new size in bytes.
+    pub fn shrink_to_fit(&self) -> Result<u64> {
+        self.check_writable()?;
+        self.check_window_resizable()?;
+        let size = self.minimum_size()?;
+        let block_size = ExtGeometry::of(*self.0.read().unwrap()).block_size;
+        self.shrink_to(size / block_size)?;
+        self.resize_backing_file(size)?;
+        Ok(size)
+    }
+
+    /// Grows or shrinks the filesystem to `new_size_bytes`, like resize2fs.
+    ///
+    /// Growing adds block groups, taking any extra descriptor blocks out of
+    /// the reserved gdt blocks (see [`ExtMkfsOptions::resize_inode`]).
+    /// Shrinking first moves inodes and blocks out of the groups that are
+    /// going away, fixing up extent trees and directory entries as it goes.
+    /// Either way, the backing file is extended or truncated to match,
+    /// unless the filesystem was opened with a fixed length window, which it
+    /// then has to fit in. A filesystem at an offset needs such a window, as
+    /// whatever follows it in the backing file would be lost otherwise.
+    ///
+    /// The size is rounded down to whole blocks, and a last group too small
+    /// to hold its own metadata is dropped, the same as mke2fs does.
+    pub fn resize(&self, new_size_bytes: u64) -> Result<()> {
+        self.check_writable()?;
+        self.check_window_resizable()?;
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        let mut new_blocks = new_size_bytes / geometry.block_size;
+        if new_blocks <= geometry.first_data_block {
+            return Err(eyre!(
+                "can't resize to {new_size_bytes} bytes, that's not even a group"
+            ));
+        }
+
+        let new_groups = geometry.groups_for(new_blocks);
+        let rem = (new_blocks - geometry.first_data_block) % geometry.blocks_per_group;
+        let last_overhead =
+            geometry.group_overhead(fs, new_groups - 1, geometry.desc_blocks_for(new_groups));
+        if rem != 0 && rem < last_overhead + 50 {
+            if new_groups == 1 {
+                return Err(eyre!(
+                    "can't resize to {new_size_bytes} bytes, that's too small"
+                ));
+            }
+            new_blocks -= rem;
+        }
+
+        match new_blocks.cmp(&geometry.blocks) {
+            std::cmp::Ordering::Less => {
+                self.shrink_to(new_blocks)?;
+                self.resize_backing_file(new_blocks * geometry.block_size)
+            }
+            std::cmp::Ordering::Greater => self.grow_to(new_blocks),
+            std::cmp::Ordering::Equal => Ok(()),
+        }
+    }
+
+    /// Makes room for a filesystem of `size` bytes in the backing file. A
+    /// filesystem with a fixed window, eg. one in a partition, can only use
+    /// what's already there, otherwise the file is grown or truncated to fit.
+    pub(crate) fn resize_backing_file(&self, size: u64) -> Result<()> {
+        self.check_window_resizable()?;
+        self.2.check_fits(size)?;
+        if self.2.length.is_some() {
+            return Ok(());
+        }
+        debug!("resizing {:?} to {} bytes", self.1, self.2.offset + size);
+        let fs = *self.0.read().unwrap();
+        if let Some(backend) = backend_of(unsafe { (*fs).io }) {
+            return backend.lock().unwrap().set_len(self.2.offset + size);
+        }
+        let file = OpenOptions::new().write(true).open(&self.1)?;
+        file.set_len(self.2.offset + size)?;
+        Ok(())
+    }
+
+    /// Without a length, a window at an offset runs to the end of the backing
+    /// file, which may hold more than this filesystem, eg. later partitions
+    /// or a backup GPT header. Resizing the file would clobber or cut those.
+    fn check_window_resizable(&self) -> Result<()> {
+        if self.2.offset != 0 && self.2.length.is_none() {
+            return Err(eyre!(
+                "can't resize a filesystem at offset {} without knowing the length of its window",
+                self.2.offset
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_resizable(&self) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let features = self.features();
+        if features.incompat.contains(ExtIncompatFeatures::META_BG) {
+            return Err(eyre!("resizing meta_bg filesystems is not supported"));
+        }
+        if features.incompat.contains(ExtIncompatFeatures::EA_INODE) {
+            return Err(eyre!("resizing ea_inode filesystems is not supported"));
+        }
+        if features.ro_compat.contains(ExtRoCompatFeatures::BIGALLOC) {
+            return Err(eyre!("resizing bigalloc filesystems is not supported"));
+        }
+        if unsafe { (*(*fs).super_).s_last_orphan } != 0 {
+            return Err(eyre!("filesystem has orphaned inodes, run fsck first"));
+        }
+        Ok(())
+    }
+
+    /// Shrinks the filesystem to `new_blocks` blocks, first moving inodes and
+    /// blocks out of the part that's being cut off. Doesn't touch the
+    /// backing file.
+    pub(crate) fn shrink_to(&self, new_blocks: u64) -> Result<()> {
+        self.check_resizable()?;
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        if new_blocks >= geometry.blocks {
+            return Ok(());
+        }
+
+        let new_groups = geometry.groups_for(new_blocks);
+        debug!(
+            "shrinking from {} blocks in {} groups to {new_blocks} blocks in {new_groups} groups",
+            geometry.blocks, geometry.groups
+        );
+        let last_group_blocks = new_blocks
+            - geometry.first_data_block
+            - (new_groups as u64 - 1) * geometry.blocks_per_group;
+        let new_desc_blocks = geometry.desc_blocks_for(new_groups);
+        if last_group_blocks < geometry.group_overhead(fs, new_groups - 1, new_desc_blocks) + 50 {
+            return Err(eyre!(
+                "{new_blocks} blocks would leave a last group too small to be useful"
+            ));
+        }
+
+        // with flex_bg, the metadata of a kept group could live anywhere
+        for group in 0..new_groups {
+            for (start, len) in geometry.group_metadata(fs, group) {
+                if start + len > new_blocks {
+                    return Err(eyre!(
+                        "metadata for group {group} lives past block {new_blocks}, can't shrink that far"
+                    ));
+                }
+            }
+        }
+
+        self.relocate_inodes(new_groups)?;
+        self.relocate_blocks(new_blocks)?;
+        self.set_geometry(new_blocks)?;
+        self.finish_resize()
+    }
+
+    /// Grows the filesystem to `new_blocks` blocks, extending the backing
+    /// file first so that the new groups have somewhere to go.
+    pub(crate) fn grow_to(&self, new_blocks: u64) -> Result<()> {
+        self.check_resizable()?;
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        if new_blocks <= geometry.blocks {
+            return Ok(());
+        }
+
+        if !self
+            .features()
+            .incompat
+            .contains(ExtIncompatFeatures::BIT64)
+            && new_blocks > u32::MAX as u64
+        {
+            return Err(eyre!("growing past 2^32 blocks requires the 64bit feature"));
+        }
+        let new_groups = geometry.groups_for(new_blocks);
+        if new_groups as u64 * geometry.inodes_per_group as u64 > u32::MAX as u64 {
+            return Err(eyre!(
+                "{new_groups} groups would have more than 2^32 inodes"
+            ));
+        }
+        self.check_desc_blocks(new_groups)?;
+        debug!(
+            "growing from {} blocks in {} groups to {new_blocks} blocks in {new_groups} groups",
+            geometry.blocks, geometry.groups
+        );
+
+        self.resize_backing_file(new_blocks * geometry.block_size)?;
+        self.set_geometry(new_blocks)?;
+        self.init_new_groups(geometry.groups)?;
+        self.finish_resize()
+    }
+
+    /// Checks that the descriptors for `new_groups` groups fit in the
+    /// descriptor blocks we have, plus the reserved ones.
+    fn check_desc_blocks(&self, new_groups: u32) -> Result<()> {
+        let geometry = ExtGeometry::of(*self.0.read().unwrap());
+        let new_desc_blocks = geometry.desc_blocks_for(new_groups);
+        if new_desc_blocks > geometry.desc_blocks + geometry.reserved_gdt_blocks {
+            return Err(eyre!(
+                "{new_groups} groups need {new_desc_blocks} group descriptor blocks, but only {} are available (see ExtMkfsOptions::resize_inode)",
+                geometry.desc_blocks + geometry.reserved_gdt_blocks
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lays out the groups added by growing the filesystem: their superblock
+    /// backups, descriptors, bitmaps and zeroed inode tables.
+    fn init_new_groups(&self, first_new_group: u32) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        let has_group_csum = self
+            .features()
+            .ro_compat
+            .intersects(ExtRoCompatFeatures::GDT_CSUM | ExtRoCompatFeatures::METADATA_CSUM);
+
+        // reserve every backup before allocating any tables, as flex_bg may
+        // put a group's tables in another group
+        for group in first_new_group..geometry.groups {
+            unsafe {
+                let desc = libe2fs_sys::ext2fs_group_desc(fs, (*fs).group_desc, group);
+                std::ptr::write_bytes(desc as *mut u8, 0, geometry.desc_size as usize);
+                if has_group_csum {
+                    libe2fs_sys::ext2fs_bg_flags_set(
+                        fs,
+                        group,
+                        libe2fs_sys::EXT2_BG_INODE_ZEROED as u16,
+                    );
+                }
+                libe2fs_sys::ext2fs_reserve_super_and_bgd(fs, group, (*fs).block_map);
+            }
+        }
+
+        for group in first_new_group..geometry.groups {
+            debug!("allocating tables for group {group}");
+            let err =
+                unsafe { libe2fs_sys::ext2fs_allocate_group_table(fs, group, (*fs).block_map) };
+            if err != 0 {
+                return report(err);
+            }
+
+            let err = unsafe {
+                libe2fs_sys::ext2fs_zero_blocks2(
+                    fs,
+                    libe2fs_sys::ext2fs_inode_table_loc(fs, group),
+                    geometry.inode_blocks_per_group as i32,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish_resize(&self) -> Result<()> {
+        if self
+            .features()
+            .compat
+            .contains(ExtCompatFeatures::RESIZE_INODE)
+        {
+            self.rebuild_resize_inode()?;
+        }
+        self.recalculate_summary_stats()?;
+        self.flush()
+    }
+
+    /// Moves every in-use inode numbered above what `new_groups` groups can
+    /// hold into a free slot below it, and points everything that referred
+    /// to it at the new number.
+    fn relocate_inodes(&self, new_groups: u32) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        let last_inode = new_groups * geometry.inodes_per_group;
+        let inodes_count = unsafe { (*(*fs).super_).s_inodes_count };
+
+        let to_move: Vec<u32> = (last_inode + 1..=inodes_count)
+            .filter(|inum| unsafe {
+                libe2fs_sys::ext2fs_test_generic_bmap((*fs).inode_map, *inum as u64) != 0
+            })
+            .collect();
+        if to_move.is_empty() {
+            return Ok(());
+        }
+        debug!("relocating {} inodes", to_move.len());
+
+        // allocate out of a copy of the inode bitmap where everything we're
+        // cutting off is already taken
+        let alloc_map = ExtBitmapCopy::of(unsafe { (*fs).inode_map })?;
+        for inum in last_inode + 1..=inodes_count {
+            alloc_map.mark(inum as u64);
+        }
+
+        let inode_size = geometry.inode_size;
+        let mut moved = HashMap::new();
+        for old in to_move {
+            let mut buf = vec![0u32; inode_size / 4];
+            let err = unsafe {
+                libe2fs_sys::ext2fs_read_inode_full(
+                    fs,
+                    old,
+                    buf.as_mut_ptr() as *mut libe2fs_sys::ext2_inode,
+                    inode_size as i32,
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+            let mode = unsafe { (*(buf.as_ptr() as *const libe2fs_sys::ext2_inode)).i_mode };
+            let is_dir = (mode as u32 & libe2fs_sys::LINUX_S_IFMT) == libe2fs_sys::LINUX_S_IFDIR;
+
+            let mut new = MaybeUninit::uninit();
+            let err = unsafe {
+                libe2fs_sys::ext2fs_new_inode(fs, 0, mode as i32, alloc_map.0, new.as_mut_ptr())
+            };
+            if err != 0 {
+                return report(err);
+            }
+            let new = unsafe { new.assume_init() };
+            alloc_map.mark(new as u64);
+            debug!("moving inode {old} to {new}");
+
+            let err = unsafe {
+                libe2fs_sys::ext2fs_write_inode_full(
+                    fs,
+                    new,
+                    buf.as_mut_ptr() as *mut libe2fs_sys::ext2_inode,
+                    inode_size as i32,
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+            unsafe {
+                libe2fs_sys::ext2fs_inode_alloc_stats2(fs, new, 1, is_dir as i32);
+                libe2fs_sys::ext2fs_inode_alloc_stats2(fs, old, -1, is_dir as i32);
+            }
+
+            if self.has_metadata_csum() {
+                // extent block checksums are seeded with the inode number
+                let flags = unsafe { (*(buf.as_ptr() as *const libe2fs_sys::ext2_inode)).i_flags };
+                if flags & libe2fs_sys::EXT4_EXTENTS_FL != 0 {
+                    rewrite_extent_checksums(fs, new)?;
+                }
+            }
+
+            moved.insert(old, new);
+        }
+
+        self.rewrite_inode_references(&moved)
+    }
+
+    /// Rewrites directory entries and superblock fields that point at a moved
+    /// inode. Directories that moved themselves get every block rewritten,
+    /// so that their checksums match their new inode number.
+    fn rewrite_inode_references(&self, moved: &HashMap<u32, u32>) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        unsafe {
+            let superblock = (*fs).super_;
+            for field in [
+                &mut (*superblock).s_usr_quota_inum,
+                &mut (*superblock).s_grp_quota_inum,
+                &mut (*superblock).s_prj_quota_inum,
+                &mut (*superblock).s_orphan_file_inum,
+                &mut (*superblock).s_lpf_ino,
+            ] {
+                if let Some(new) = moved.get(field) {
+                    *field = *new;
+                }
+            }
+        }
+
+        let moved_inums: Vec<u32> = moved.values().copied().collect();
+        self.for_each_inode(|inum, inode| {
+            if (inode.i_mode as u32 & libe2fs_sys::LINUX_S_IFMT) != libe2fs_sys::LINUX_S_IFDIR
+                || inode.i_links_count == 0
+            {
+                return Ok(());
+            }
+
+            let mut ctx = DirentRewrite {
+                moved,
+                rewrite_all: moved_inums.contains(&inum),
+            };
+            let err = unsafe {
+                libe2fs_sys::ext2fs_dir_iterate2(
+                    fs,
+                    inum,
+                    libe2fs_sys::DIRENT_FLAG_INCLUDE_EMPTY as i32,
+                    std::ptr::null_mut(),
+                    Some(rewrite_dirent),
+                    &mut ctx as *mut _ as *mut ::std::ffi::c_void,
+                )
+            };
+            if err == 0 {
+                Ok(())
+            } else {
+                report(err)
+            }
+        })
+    }
+
+    /// Moves every block at or past `new_blocks` that belongs to an inode
+    /// somewhere below it, fixing up block maps, extent trees and xattr
+    /// block pointers as it goes.
+    fn relocate_blocks(&self, new_blocks: u64) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+
+        let alloc_map = ExtBitmapCopy::of(unsafe { (*fs).block_map })?;
+        unsafe {
+            libe2fs_sys::ext2fs_mark_block_bitmap_range2(
+                alloc_map.0,
+                new_blocks,
+                (geometry.blocks - new_blocks) as u32,
+            );
+        }
+
+        let mut mover = BlockMover {
+            alloc_map: &alloc_map,
+            new_blocks,
+            goal: geometry.first_data_block,
+            moved_xattr_blocks: HashMap::new(),
+            err: 0,
+        };
+
+        // libe2fs allocates blocks of its own while we remap extents, so
+        // make sure those come out of the part of the fs that we're keeping
+        let mover_ptr = &mut mover as *mut BlockMover;
+        let mut old_alloc_block = None;
+        let old_priv_data = unsafe { (*fs).priv_data };
+        unsafe {
+            (*fs).priv_data = mover_ptr as *mut ::std::ffi::c_void;
+            libe2fs_sys::ext2fs_set_alloc_block_callback(
+                fs,
+                Some(alloc_block_below),
+                &mut old_alloc_block,
+            );
+        }
+
+        let res = self.for_each_inode(|inum, inode| {
+            let mover = unsafe { &mut *mover_ptr };
+            if inum == libe2fs_sys::EXT2_RESIZE_INO
+                || (inode.i_links_count == 0 && inum >= geometry.first_inode)
+            {
+                return Ok(());
+            }
+
+            let mut inode = *inode;
+            mover.move_xattr_block(fs, inum, &mut inode)?;
+
+            if unsafe { libe2fs_sys::ext2fs_inode_has_valid_blocks2(fs, &mut inode) } == 0 {
+                return Ok(());
+            }
+
+            mover.goal = unsafe {
+                libe2fs_sys::ext2fs_group_first_block2(fs, (inum - 1) / geometry.inodes_per_group)
+            };
+            let err = unsafe {
+                libe2fs_sys::ext2fs_block_iterate3(
+                    fs,
+                    inum,
+                    0,
+                    std::ptr::null_mut(),
+                    Some(move_block),
+                    mover_ptr as *mut ::std::ffi::c_void,
+                )
+            };
+            if mover.err != 0 {
+                return report(mover.err);
+            }
+            if err != 0 {
+                return report(err);
+            }
+            Ok(())
+        });
+
+        unsafe {
+            libe2fs_sys::ext2fs_set_alloc_block_callback(fs, old_alloc_block, std::ptr::null_mut());
+            (*fs).priv_data = old_priv_data;
+        }
+        res?;
+
+        self.update_journal_backup()
+    }
+
+    /// Keeps the copy of the journal's block map in `s_jnl_blocks` in sync
+    /// after the journal moved.
+    pub(crate) fn update_journal_backup(&self) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let superblock = unsafe { (*fs).super_ };
+        let journal_inum = unsafe { (*superblock).s_journal_inum };
+        if journal_inum == 0
+            || unsafe { (*superblock).s_jnl_backup_type }
+                != libe2fs_sys::EXT3_JNL_BACKUP_BLOCKS as u8
+        {
+            return Ok(());
+        }
+
+        let journal = self.read_inode(journal_inum)?;
+        unsafe {
+            (*superblock).s_jnl_blocks[..15].copy_from_slice(&journal.1.i_block);
+            (*superblock).s_jnl_blocks[15] = journal.1.i_size_high;
+            (*superblock).s_jnl_blocks[16] = journal.1.i_size;
+        }
+        Ok(())
+    }
+
+    /// Resizes the in-memory geometry (block count, group count, descriptor
+    /// blocks, bitmaps) to `new_blocks`, assuming nothing in use lives past
+    /// the new end anymore.
+    pub(crate) fn set_geometry(&self, new_blocks: u64) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        let new_groups = geometry.groups_for(new_blocks);
+        let new_desc_blocks = geometry.desc_blocks_for(new_groups);
+        let superblock = unsafe { (*fs).super_ };
+        let has_resize_inode = self
+            .features()
+            .compat
+            .contains(ExtCompatFeatures::RESIZE_INODE);
+
+        // the metadata of groups that are going away may live in groups we're
+        // keeping, thanks to flex_bg
+        for group in new_groups..geometry.groups {
+            for (start, len) in geometry.group_metadata(fs, group) {
+                let end = (start + len).min(new_blocks);
+                if start < end {
+                    unsafe {
+                        libe2fs_sys::ext2fs_block_alloc_stats_range(
+                            fs,
+                            start,
+                            (end - start) as u32,
+                            -1,
+                        )
+                    };
+                }
+            }
+        }
+
+        self.check_desc_blocks(new_groups)?;
+        if new_desc_blocks > geometry.desc_blocks {
+            // grow the in-memory descriptor table, zeroing out the new blocks
+            let old_size = geometry.desc_blocks as usize * geometry.block_size as usize;
+            let new_size = new_desc_blocks as usize * geometry.block_size as usize;
+            let err = unsafe {
+                libe2fs_sys::ext2fs_resize_mem(
+                    old_size as _,
+                    new_size as _,
+                    &mut (*fs).group_desc as *mut _ as *mut ::std::ffi::c_void,
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+            unsafe {
+                std::ptr::write_bytes(
+                    ((*fs).group_desc as *mut u8).add(old_size),
+                    0,
+                    new_size - old_size,
+                );
+            }
+        }
+
+        // with a resize inode, descriptor blocks come out of, or go back to,
+        // the reserved gdt blocks, so the layout of every backup stays the
+        // same. Without one, or if there'd be too many reserved blocks for
+        // the resize inode to map, the leftovers are freed.
+        let old_gdt_blocks = geometry.desc_blocks + geometry.reserved_gdt_blocks;
+        let new_reserved_gdt_blocks = if has_resize_inode {
+            old_gdt_blocks
+                .saturating_sub(new_desc_blocks)
+                .min(geometry.block_size / 4)
+        } else {
+            0
+        };
+        let new_gdt_blocks = new_desc_blocks + new_reserved_gdt_blocks;
+        if new_gdt_blocks < old_gdt_blocks {
+            for group in 0..new_groups.min(geometry.groups) {
+                if unsafe { libe2fs_sys::ext2fs_bg_has_super(fs, group) } == 0 {
+                    continue;
+                }
+                let first = unsafe { libe2fs_sys::ext2fs_group_first_block2(fs, group) };
+                unsafe {
+                    libe2fs_sys::ext2fs_block_alloc_stats_range(
+                        fs,
+                        first + 1 + new_gdt_blocks,
+                        (old_gdt_blocks - new_gdt_blocks) as u32,
+                        -1,
+                    )
+                };
+            }
+        }
+        unsafe { (*superblock).s_reserved_gdt_blocks = new_reserved_gdt_blocks as u16 };
+
+        let new_real_end =
+            geometry.first_data_block + new_groups as u64 * geometry.blocks_per_group - 1;
+        unsafe {
+            if new_blocks < geometry.blocks {
+                libe2fs_sys::ext2fs_unmark_block_bitmap_range2(
+                    (*fs).block_map,
+                    new_blocks,
+                    (geometry.blocks - new_blocks) as u32,
+                );
+            }
+
+            let err = libe2fs_sys::ext2fs_resize_block_bitmap2(
+                new_blocks - 1,
+                new_real_end,
+                (*fs).block_map,
+            );
+            if err != 0 {
+                return report(err);
+            }
+            let new_inodes = new_groups * geometry.inodes_per_group;
+            let err =
+                libe2fs_sys::ext2fs_resize_inode_bitmap2(new_inodes, new_inodes, (*fs).inode_map);
+            if err != 0 {
+                return report(err);
+            }
+
+            let r_blocks = libe2fs_sys::ext2fs_r_blocks_count(superblock) as f64
+                / geometry.blocks as f64
+                * new_blocks as f64;
+            libe2fs_sys::ext2fs_blocks_count_set(superblock, new_blocks);
+            libe2fs_sys::ext2fs_r_blocks_count_set(superblock, r_blocks as u64);
+            (*superblock).s_inodes_count = new_inodes;
+            (*superblock).s_overhead_clusters = 0;
+            (*fs).group_desc_count = new_groups;
+            (*fs).desc_blocks = new_desc_blocks as _;
+
+            // the last group is the only one that may be partial, and its
+            // bitmap is never left uninitialised
+            libe2fs_sys::ext2fs_bg_flags_clear(
+                fs,
+                new_groups - 1,
+                libe2fs_sys::EXT2_BG_BLOCK_UNINIT as u16,
+            );
+            if new_groups > geometry.groups {
+                libe2fs_sys::ext2fs_bg_flags_clear(
+                    fs,
+                    geometry.groups - 1,
+                    libe2fs_sys::EXT2_BG_BLOCK_UNINIT as u16,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recreates the resize inode from `s_reserved_gdt_blocks`, the same way
+    /// resize2fs does after the number of descriptor blocks changed.
+    pub(crate) fn rebuild_resize_inode(&self) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let mut inode = self.read_inode(libe2fs_sys::EXT2_RESIZE_INO)?;
+        unsafe {
+            let err = libe2fs_sys::ext2fs_iblk_set(fs, inode.as_small_mut(), 1);
+            if err != 0 {
+                return report(err);
+            }
+        }
+        self.write_inode(&mut inode)?;
+
+        let dind = inode.1.i_block[libe2fs_sys::EXT2_DIND_BLOCK as usize] as u64;
+        if dind >= unsafe { libe2fs_sys::ext2fs_blocks_count((*fs).super_) } {
+            // cut off by shrinking, so let libe2fs allocate a new one
+            inode.1.i_block[libe2fs_sys::EXT2_DIND_BLOCK as usize] = 0;
+            self.write_inode(&mut inode)?;
+        } else if dind != 0 {
+            let err = unsafe {
+                libe2fs_sys::ext2fs_zero_blocks2(
+                    fs,
+                    dind,
+                    1,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+        }
+        let err = unsafe { libe2fs_sys::ext2fs_create_resize_inode(fs) };
+        if err == 0 {
+            Ok(())
+        } else {
+            report(err)
+        }
+    }
+
+    /// Recounts free blocks and inodes for every group and the superblock
+    /// from the bitmaps, and redoes the group descriptor checksums.
+    pub(crate) fn recalculate_summary_stats(&self) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+
+        for group in 0..geometry.groups {
+            let (first, last) = unsafe {
+                (
+                    libe2fs_sys::ext2fs_group_first_block2(fs, group),
+                    libe2fs_sys::ext2fs_group_last_block2(fs, group),
+                )
+            };
+            let mut used = 0;
+            let err =
+                unsafe { libe2fs_sys::ext2fs_count_used_clusters(fs, first, last, &mut used) };
+            if err != 0 {
+                return report(err);
+            }
+            let group_free_blocks = last - first + 1 - used;
+
+            let first_inode = group * geometry.inodes_per_group + 1;
+            let group_free_inodes = (first_inode..first_inode + geometry.inodes_per_group)
+                .filter(|inum| unsafe {
+                    libe2fs_sys::ext2fs_test_generic_bmap((*fs).inode_map, *inum as u64) == 0
+                })
+                .count() as u32;
+
+            unsafe {
+                libe2fs_sys::ext2fs_bg_free_blocks_count_set(fs, group, group_free_blocks as _);
+                libe2fs_sys::ext2fs_bg_free_inodes_count_set(fs, group, group_free_inodes);
+                libe2fs_sys::ext2fs_group_desc_csum_set(fs, group);
+            }
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+
+        unsafe {
+            libe2fs_sys::ext2fs_free_blocks_count_set((*fs).super_, free_blocks);
+            (*(*fs).super_).s_free_inodes_count = free_inodes;
+            (*fs).flags |= (libe2fs_sys::EXT2_FLAG_DIRTY
+                | libe2fs_sys::EXT2_FLAG_CHANGED
+                | libe2fs_sys::EXT2_FLAG_BB_DIRTY
+                | libe2fs_sys::EXT2_FLAG_IB_DIRTY) as i32;
+        }
+
+        let err = unsafe { libe2fs_sys::ext2fs_set_gdt_csum(fs) };
+        if err == 0 {
+            Ok(())
+        } else {
+            report(err)
+        }
+    }
+
+    /// Calls `f` with every inode in the filesystem, in order.
+    pub(crate) fn for_each_inode(
+        &self,
+        mut f: impl FnMut(u32, &libe2fs_sys::ext2_inode) -> Result<()>,
+    ) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let mut scan = MaybeUninit::uninit();
+        let err = unsafe { libe2fs_sys::ext2fs_open_inode_scan(fs, 0, scan.as_mut_ptr()) };
+        if err != 0 {
+            return report(err);
+        }
+        let scan = unsafe { scan.assume_init() };
+
+        let res = (|| loop {
+            let mut inum = 0;
+            let mut inode = MaybeUninit::<libe2fs_sys::ext2_inode>::uninit();
+            let err = unsafe {
+                libe2fs_sys::ext2fs_get_next_inode_full(
+                    scan,
+                    &mut inum,
+                    inode.as_mut_ptr(),
+                    std::mem::size_of::<libe2fs_sys::ext2_inode>() as i32,
+                )
+            };
+            if inum == 0 {
+                return Ok(());
+            }
+            let in_use =
+                unsafe { libe2fs_sys::ext2fs_test_generic_bmap((*fs).inode_map, inum as u64) != 0 };
+            if err != 0 {
+                // unused inodes are allowed to be garbage
+                if in_use {
+                    return report(err);
+                }
+                continue;
+            }
+            if in_use {
+                f(inum, unsafe { inode.assume_init_ref() })?;
+            }
+        })();
+
+        unsafe { libe2fs_sys::ext2fs_close_inode_scan(scan) };
+        res
+    }
+
+    pub(crate) fn has_metadata_csum(&self) -> bool {
+        self.features()
+            .ro_compat
+            .contains(ExtRoCompatFeatures::METADATA_CSUM)
+    }
+}
+
+/// The numbers needed to reason about block group layout.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ExtGeometry {
+    pub(crate) block_size: u64,
+    pub(crate) blocks: u64,
+    pub(crate) first_data_block: u64,
+    pub(crate) blocks_per_group: u64,
+    pub(crate) inodes_per_group: u32,
+    pub(crate) inode_blocks_per_group: u64,
+    pub(crate) inode_size: usize,
+    pub(crate) first_inode: u32,
+    pub(crate) groups: u32,
+    pub(crate) desc_blocks: u64,
+    pub(crate) desc_size: u64,
+    pub(crate) descs_per_block: u64,
+    pub(crate) reserved_gdt_blocks: u64,
+}
+
+impl ExtGeometry {
+    pub(crate) fn of(fs: libe2fs_sys::ext2_filsys) -> Self {
+        unsafe {
+            let superblock = *(*fs).super_;
+            let desc_size = if ExtFeatures::from_superblock(&superblock)
+                .incompat
+                .contains(ExtIncompatFeatures::BIT64)
+            {
+                superblock.s_desc_size as u64
+            } else {
+                libe2fs_sys::EXT2_MIN_DESC_SIZE as u64
+            };
+            Self {
+                block_size: (*fs).blocksize as u64,
+                blocks: libe2fs_sys::ext2fs_blocks_count((*fs).super_),
+                first_data_block: superblock.s_first_data_block as u64,
+                blocks_per_group: superblock.s_blocks_per_group as u64,
+                inodes_per_group: superblock.s_inodes_per_group,
+                inode_blocks_per_group: (*fs).inode_blocks_per_group as u64,
+                inode_size: superblock.s_inode_size as usize,
+                first_inode: superblock.s_first_ino,
+                groups: (*fs).group_desc_count,
+                desc_blocks: (*fs).desc_blocks as u64,
+                desc_size,
+                descs_per_block: (*fs).blocksize as u64 / desc_size,
+                reserved_gdt_blocks: superblock.s_reserved_gdt_blocks as u64,
+            }
+        }
+    }
+
+    pub(crate) fn groups_for(&self, blocks: u64) -> u32 {
+        (blocks - self.first_data_block).div_ceil(self.blocks_per_group) as u32
+    }
+
+    pub(crate) fn desc_blocks_for(&self, groups: u32) -> u64 {
+        (groups as u64).div_ceil(self.descs_per_block)
+    }
+
+    /// Blocks used by a group's superblock backup, descriptors, bitmaps and
+    /// inode table, if the fs had `desc_blocks` descriptor blocks. Reserved
+    /// gdt blocks trade places with descriptor blocks as the fs is resized,
+    /// so the two always add up to the same amount.
+    pub(crate) fn group_overhead(
+        &self,
+        fs: libe2fs_sys::ext2_filsys,
+        group: u32,
+        desc_blocks: u64,
+    ) -> u64 {
+        let has_super = unsafe { libe2fs_sys::ext2fs_bg_has_super(fs, group) } != 0;
+        let super_blocks = if has_super {
+            let gdt_blocks = if self.reserved_gdt_blocks > 0 {
+                self.desc_blocks + self.reserved_gdt_blocks
+            } else {
+                desc_blocks
+            };
+            1 + gdt_blocks.max(desc_blocks)
+        } else {
+            0
+        };
+        super_blocks + 2 + self.inode_blocks_per_group
+    }
+
+    /// Where a group's bitmaps and inode table live, as (start, len) pairs.
+    pub(crate) fn group_metadata(
+        &self,
+        fs: libe2fs_sys::ext2_filsys,
+        group: u32,
+    ) -> [(u64, u64); 3] {
+        unsafe {
+            [
+                (libe2fs_sys::ext2fs_block_bitmap_loc(fs, group), 1),
+                (libe2fs_sys::ext2fs_inode_bitmap_loc(fs, group), 1),
+                (
+                    libe2fs_sys::ext2fs_inode_table_loc(fs, group),
+                    self.inode_blocks_per_group,
+                ),
+            ]
+        }
+    }
+}
+
+/// A scratch copy of a bitmap, freed on drop.
+pub(crate) struct ExtBitmapCopy(pub(crate) libe2fs_sys::ext2fs_generic_bitmap);
+
+impl ExtBitmapCopy {
+    pub(crate) fn of(bitmap: libe2fs_sys::ext2fs_generic_bitmap) -> Result<Self> {
+        let mut copy = MaybeUninit::uninit();
+        let err = unsafe { libe2fs_sys::ext2fs_copy_bitmap(bitmap, copy.as_mut_ptr()) };
+        if err == 0 {
+            Ok(Self(unsafe { copy.assume_init() }))
+        } else {
+            report(err)
+        }
+    }
+
+    pub(crate) fn mark(&self, arg: u64) {
+        unsafe { libe2fs_sys::ext2fs_mark_generic_bmap(self.0, arg) };
+    }
+}
+
+impl Drop for ExtBitmapCopy {
+    fn drop(&mut self) {
+        unsafe { libe2fs_sys::ext2fs_free_generic_bmap(self.0) };
+    }
+}
+
+struct DirentRewrite<'a> {
+    moved: &'a HashMap<u32, u32>,
+    rewrite_all: bool,
+}
+
+unsafe extern "C" fn rewrite_dirent(
+    _dir: libe2fs_sys::ext2_ino_t,
+    _entry: i32,
+    dirent: *mut libe2fs_sys::ext2_dir_entry,
+    _offset: i32,
+    _blocksize: i32,
+    _buf: *mut ::std::ffi::c_char,
+    priv_data: *mut ::std::ffi::c_void,
+) -> i32 {
+    let ctx = &*(priv_data as *const DirentRewrite);
+    let mut changed = ctx.rewrite_all;
+    if let Some(new) = ctx.moved.get(&(*dirent).inode) {
+        (*dirent).inode = *new;
+        changed = true;
+    }
+    if changed {
+        libe2fs_sys::DIRENT_CHANGED as i32
+    } else {
+        0
+    }
+}
+
+/// Touches the first entry of every extent block so that libe2fs writes it
+/// back out with a checksum for the inode's current number and the current
+/// checksum seed.
+pub(crate) fn rewrite_extent_checksums(fs: libe2fs_sys::ext2_filsys, inum: u32) -> Result<()> {
+    let mut handle = MaybeUninit::uninit();
+    let err = unsafe { libe2fs_sys::ext2fs_extent_open(fs, inum, handle.as_mut_ptr()) };
+    if err != 0 {
+        return report(err);
+    }
+    let handle = unsafe { handle.assume_init() };
+
+    let res = (|| unsafe {
+        let mut extent = MaybeUninit::<libe2fs_sys::ext2fs_extent>::zeroed().assume_init();
+        let mut err = libe2fs_sys::ext2fs_extent_get(
+            handle,
+            libe2fs_sys::EXT2_EXTENT_ROOT as i32,
+            &mut extent,
+        );
+        while err == 0 {
+            let mut info = MaybeUninit::<libe2fs_sys::ext2_extent_info>::uninit();
+            let info_err = libe2fs_sys::ext2fs_extent_get_info(handle, info.as_mut_ptr());
+            if info_err != 0 {
+                return report(info_err);
+            }
+            let info = info.assume_init();
+
+            if info.curr_entry == 1
+                && info.curr_level != 0
+                && extent.e_flags & libe2fs_sys::EXT2_EXTENT_FLAGS_SECOND_VISIT == 0
+            {
+                let replace_err = libe2fs_sys::ext2fs_extent_replace(handle, 0, &mut extent);
+                if replace_err != 0 {
+                    return report(replace_err);
+                }
+            }
+
+            if extent.e_flags & libe2fs_sys::EXT2_EXTENT_FLAGS_LEAF != 0 {
+                let sib_err = libe2fs_sys::ext2fs_extent_get(
+                    handle,
+                    libe2fs_sys::EXT2_EXTENT_LAST_SIB as i32,
+                    &mut extent,
+                );
+                if sib_err != 0 {
+                    return report(sib_err);
+                }
+            }
+
+            err = libe2fs_sys::ext2fs_extent_get(
+                handle,
+                libe2fs_sys::EXT2_EXTENT_NEXT as i32,
+                &mut extent,
+            );
+        }
+
+        if err == libe2fs_sys::EXT2_ET_EXTENT_NO_NEXT as i64 {
+            Ok(())
+        } else {
+            report(err)
+        }
+    })();
+
+    unsafe { libe2fs_sys::ext2fs_extent_free(handle) };
+    res
+}
+
+struct BlockMover<'a> {
+    alloc_map: &'a ExtBitmapCopy,
+    new_blocks: u64,
+    goal: u64,
+    moved_xattr_blocks: HashMap<u64, u64>,
+    err: i64,
+}
+
+impl BlockMover<'_> {
+    fn alloc(&mut self, fs: libe2fs_sys::ext2_filsys) -> Result<u64, i64> {
+        let mut block = 0;
+        let err =
+            unsafe { libe2fs_sys::ext2fs_new_block2(fs, self.goal, self.alloc_map.0, &mut block) };
+        if err != 0 {
+            return Err(err);
+        }
+        self.alloc_map.mark(block);
+        self.goal = block + 1;
+        Ok(block)
+    }
+
+    fn copy_block(&mut self, fs: libe2fs_sys::ext2_filsys, from: u64, to: u64) -> Result<(), i64> {
+        let mut buf = vec![0u8; unsafe { (*fs).blocksize } as usize];
+        let err = unsafe {
+            libe2fs_sys::io_channel_read_blk64(
+                (*fs).io,
+                from,
+                1,
+                buf.as_mut_ptr() as *mut ::std::ffi::c_void,
+            )
+        };
+        if err != 0 {
+            return Err(err);
+        }
+        let err = unsafe {
+            libe2fs_sys::io_channel_write_blk64(
+                (*fs).io,
+                to,
+                1,
+                buf.as_ptr() as *const ::std::ffi::c_void,
+            )
+        };
+        if err != 0 {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Moves an inode's xattr block, rewriting it rather than copying it
+    /// because its checksum covers its block number. Shared blocks only move
+    /// once.
+    fn move_xattr_block(
+        &mut self,
+        fs: libe2fs_sys::ext2_filsys,
+        inum: u32,
+        inode: &mut libe2fs_sys::ext2_inode,
+    ) -> Result<()> {
+        let old = unsafe { libe2fs_sys::ext2fs_file_acl_block(fs, inode) };
+        if old < self.new_blocks {
+            return Ok(());
+        }
+
+        let new = match self.moved_xattr_blocks.get(&old) {
+            Some(new) => *new,
+            None => {
+                let new = match self.alloc(fs) {
+                    Ok(new) => new,
+                    Err(err) => return report(err),
+                };
+                let mut buf = vec![0u8; unsafe { (*fs).blocksize } as usize];
+                unsafe {
+                    let ptr = buf.as_mut_ptr() as *mut ::std::ffi::c_void;
+                    let err = libe2fs_sys::ext2fs_read_ext_attr3(fs, old, ptr, inum);
+                    if err != 0 {
+                        return report(err);
+                    }
+                    let err = libe2fs_sys::ext2fs_write_ext_attr3(fs, new, ptr, inum);
+                    if err != 0 {
+                        return report(err);
+                    }
+                    libe2fs_sys::ext2fs_block_alloc_stats2(fs, new, 1);
+                    libe2fs_sys::ext2fs_block_alloc_stats2(fs, old, -1);
+                }
+                self.moved_xattr_blocks.insert(old, new);
+                new
+            }
+        };
+
+        debug!("moving xattr block {old} of inode {inum} to {new}");
+        let err = unsafe {
+            libe2fs_sys::ext2fs_file_acl_block_set(fs, inode, new);
+            libe2fs_sys::ext2fs_write_inode(fs, inum, inode)
+        };
+        if err == 0 {
+            Ok(())
+        } else {
+            report(err)
+        }
+    }
+}
+
+unsafe extern "C" fn move_block(
+    fs: libe2fs_sys::ext2_filsys,
+    blocknr: *mut libe2fs_sys::blk64_t,
+    _blockcnt: libe2fs_sys::e2_blkcnt_t,
+    _ref_blk: libe2fs_sys::blk64_t,
+    _ref_offset: i32,
+    priv_data: *mut ::std::ffi::c_void,
+) -> i32 {
+    let mover = &mut *(priv_data as *mut BlockMover);
+    let old = *blocknr;
+    if old < mover.new_blocks {
+        return 0;
+    }
+
+    let res = mover
+        .alloc(fs)
+        .and_then(|new| mover.copy_block(fs, old, new).map(|_| new));
+    match res {
+        Ok(new) => {
+            libe2fs_sys::ext2fs_block_alloc_stats2(fs, new, 1);
+            libe2fs_sys::ext2fs_block_alloc_stats2(fs, old, -1);
+            *blocknr = new;
+            libe2fs_sys::BLOCK_CHANGED as i32
+        }
+        Err(err) => {
+            mover.err = err;
+            libe2fs_sys::BLOCK_ABORT as i32
+        }
+    }
+}
+
+unsafe extern "C" fn alloc_block_below(
+    fs: libe2fs_sys::ext2_filsys,
+    _goal: libe2fs_sys::blk64_t,
+    ret: *mut libe2fs_sys::blk64_t,
+) -> libe2fs_sys::errcode_t {
+    let mover = &mut *((*fs).priv_data as *mut BlockMover);
+    match mover.alloc(fs) {
+        Ok(block) => {
+            *ret = block;
+            0
+        }
+        Err(err) => err,
+    }
+}
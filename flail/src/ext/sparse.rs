@@ -0,0 +1,416 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::*;
+
+// The Android sparse image format, see libsparse's sparse_format.h.
+const ANDROID_SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const ANDROID_SPARSE_MAJOR: u16 = 1;
+const ANDROID_SPARSE_MINOR: u16 = 0;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+
+/// How much data to buffer before starting a new raw chunk.
+const MAX_RAW_CHUNK: usize = 16 * 1024 * 1024;
+
+/// How many blocks to read from the filesystem at once.
+const READ_BATCH: u64 = 256;
+
+/// What a block of the filesystem holds, as far as exporting goes.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum BlockContents {
+    /// Not in use, so whatever's there doesn't matter.
+    Free,
+    /// Nothing but the same four bytes over and over, usually zeroes.
+    Fill(u32),
+    Data,
+}
+
+impl BlockContents {
+    fn of(block: &[u8]) -> Self {
+        let fill = LittleEndian::read_u32(&block[..4]);
+        if block
+            .chunks_exact(4)
+            .all(|word| LittleEndian::read_u32(word) == fill)
+        {
+            Self::Fill(fill)
+        } else {
+            Self::Data
+        }
+    }
+
+    /// Whether the block can be left as a hole in a plain image.
+    fn is_hole(&self) -> bool {
+        matches!(self, Self::Free | Self::Fill(0))
+    }
+}
+
+impl ExtFilesystem {
+    /// Writes the filesystem to `path` as a plain image, leaving holes where
+    /// blocks are free or zeroed so they take no space on the host. Free
+    /// blocks come from the block bitmap, and read back as zeroes.
+    pub fn export_sparse<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        debug!("exporting sparse image to {path:?}");
+        let file = File::create(path)?;
+        let block_size = self.prepare_export()? as u64;
+
+        let mut holes = 0;
+        self.for_each_block(|block, contents, data| {
+            if contents.is_hole() {
+                holes += 1;
+                Ok(())
+            } else {
+                Ok(file.write_all_at(data, block * block_size)?)
+            }
+        })?;
+        file.set_len(self.export_size()?)?;
+        file.sync_all()?;
+
+        debug!("left {holes} blocks as holes");
+        Ok(())
+    }
+
+    /// Writes the filesystem to `path` in the Android sparse image format,
+    /// as used by fastboot. Free blocks become don't-care chunks, and blocks
+    /// of one repeated word become fill chunks.
+    pub fn export_android_sparse<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        debug!("exporting android sparse image to {path:?}");
+        let block_size = self.prepare_export()?;
+        let total_blocks: u32 = (self.export_size()? / block_size as u64)
+            .try_into()
+            .map_err(|_| eyre!("filesystem is too big for an android sparse image"))?;
+
+        let file = File::create(path)?;
+        let mut out = BufWriter::new(&file);
+        out.write_all(&[0; FILE_HEADER_SIZE])?;
+
+        let mut chunks = 0;
+        let mut run: Option<(BlockContents, u32)> = None;
+        let mut raw = vec![];
+        let mut write_run =
+            |out: &mut BufWriter<&File>, run: (BlockContents, u32), raw: &mut Vec<u8>| {
+                let (contents, blocks) = run;
+                let mut header = [0; CHUNK_HEADER_SIZE];
+                let (chunk_type, body) = match contents {
+                    BlockContents::Free => (CHUNK_DONT_CARE, 0),
+                    BlockContents::Fill(_) => (CHUNK_FILL, 4),
+                    BlockContents::Data => (CHUNK_RAW, raw.len()),
+                };
+                LittleEndian::write_u16(&mut header[..2], chunk_type);
+                LittleEndian::write_u32(&mut header[4..8], blocks);
+                LittleEndian::write_u32(&mut header[8..12], (CHUNK_HEADER_SIZE + body) as u32);
+                out.write_all(&header)?;
+                match contents {
+                    BlockContents::Free => {}
+                    BlockContents::Fill(fill) => out.write_all(&fill.to_le_bytes())?,
+                    BlockContents::Data => out.write_all(raw)?,
+                }
+                raw.clear();
+                chunks += 1;
+                Ok::<(), eyre::Report>(())
+            };
+
+        self.for_each_block(|_, contents, data| {
+            match &mut run {
+                Some((current, blocks)) if *current == contents && raw.len() < MAX_RAW_CHUNK => {
+                    *blocks += 1
+                }
+                _ => {
+                    if let Some(run) = run.take() {
+                        write_run(&mut out, run, &mut raw)?;
+                    }
+                    run = Some((contents, 1));
+                }
+            }
+            if contents == BlockContents::Data {
+                raw.extend_from_slice(data);
+            }
+            Ok(())
+        })?;
+        if let Some(run) = run.take() {
+            write_run(&mut out, run, &mut raw)?;
+        }
+        out.flush()?;
+        drop(out);
+
+        let mut header = [0; FILE_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[..4], ANDROID_SPARSE_MAGIC);
+        LittleEndian::write_u16(&mut header[4..6], ANDROID_SPARSE_MAJOR);
+        LittleEndian::write_u16(&mut header[6..8], ANDROID_SPARSE_MINOR);
+        LittleEndian::write_u16(&mut header[8..10], FILE_HEADER_SIZE as u16);
+        LittleEndian::write_u16(&mut header[10..12], CHUNK_HEADER_SIZE as u16);
+        LittleEndian::write_u32(&mut header[12..16], block_size);
+        LittleEndian::write_u32(&mut header[16..20], total_blocks);
+        LittleEndian::write_u32(&mut header[20..24], chunks);
+        file.write_all_at(&header, 0)?;
+        file.sync_all()?;
+
+        debug!("wrote {total_blocks} blocks in {chunks} chunks");
+        Ok(())
+    }
+
+    /// Gets what's on disk up to date, returning the block size.
+    fn prepare_export(&self) -> Result<u32> {
+        let fs = *self.0.read().unwrap();
+        if unsafe { (*fs).flags } & libe2fs_sys::EXT2_FLAG_RW as i32 != 0 {
+            self.write_bitmaps()?;
+            self.flush()?;
+        }
+        Ok(unsafe { (*fs).blocksize } as u32)
+    }
+
+    fn export_size(&self) -> Result<u64> {
+        let fs = *self.0.read().unwrap();
+        Ok(unsafe { libe2fs_sys::ext2fs_blocks_count((*fs).super_) * (*fs).blocksize as u64 })
+    }
+
+    /// Calls `f` with every block of the filesystem in order. Free blocks
+    /// aren't read, and are passed as zeroes.
+    fn for_each_block(
+        &self,
+        mut f: impl FnMut(u64, BlockContents, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let (block_size, blocks, first_data_block) = unsafe {
+            (
+                (*fs).blocksize as usize,
+                libe2fs_sys::ext2fs_blocks_count((*fs).super_),
+                (*(*fs).super_).s_first_data_block as u64,
+            )
+        };
+        // blocks before the first data block aren't in the bitmap, but hold
+        // the boot sector
+        let is_used = |block: u64| unsafe {
+            block < first_data_block
+                || libe2fs_sys::ext2fs_test_generic_bmap((*fs).block_map, block) != 0
+        };
+
+        let zeroes = vec![0; block_size];
+        let mut buf = vec![0; block_size * READ_BATCH as usize];
+        let mut block = 0;
+        while block < blocks {
+            if !is_used(block) {
+                f(block, BlockContents::Free, &zeroes)?;
+                block += 1;
+                continue;
+            }
+
+            let mut count = 1;
+            while count < READ_BATCH && block + count < blocks && is_used(block + count) {
+                count += 1;
+            }
+            let data = &mut buf[..block_size * count as usize];
+            let err = unsafe {
+                libe2fs_sys::io_channel_read_blk64(
+                    (*fs).io,
+                    block,
+                    count as i32,
+                    data.as_mut_ptr() as *mut ::std::ffi::c_void,
+                )
+            };
+            if err != 0 {
+                return report(err);
+            }
+            for (i, data) in data.chunks_exact(block_size).enumerate() {
+                f(block + i as u64, BlockContents::of(data), data)?;
+            }
+            block += count;
+        }
+        Ok(())
+    }
+}
+
+/// Turns the Android sparse image at `sparse` into a plain image at `raw`,
+/// leaving holes for don't-care and zero-filled chunks.
+pub fn android_sparse_to_raw<P: AsRef<Path>, Q: AsRef<Path>>(sparse: P, raw: Q) -> Result<()> {
+    let raw = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(raw)?;
+    read_android_sparse(
+        &File::open(sparse)?,
+        |size| Ok(raw.set_len(size)?),
+        |offset, data| Ok(raw.write_all_at(data, offset)?),
+    )?;
+    raw.sync_all()?;
+    Ok(())
+}
+
+impl ExtMemoryImage {
+    /// Reads the Android sparse image at `path` into memory.
+    pub fn from_android_sparse<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let image = Self::default();
+        read_android_sparse(
+            &File::open(path)?,
+            |size| image.clone().set_len(size),
+            |offset, data| image.clone().write_at(offset, data),
+        )?;
+        Ok(image)
+    }
+}
+
+/// Reads an Android sparse image, calling `set_len` with its size once the
+/// header is read, then `write_at` for every chunk that isn't all zeroes.
+fn read_android_sparse(
+    file: &File,
+    mut set_len: impl FnMut(u64) -> Result<()>,
+    mut write_at: impl FnMut(u64, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut header = [0; FILE_HEADER_SIZE];
+    file.read_exact_at(&mut header, 0)?;
+    if LittleEndian::read_u32(&header[..4]) != ANDROID_SPARSE_MAGIC {
+        return Err(eyre!("not an android sparse image"));
+    }
+    let major = LittleEndian::read_u16(&header[4..6]);
+    if major != ANDROID_SPARSE_MAJOR {
+        return Err(eyre!("unsupported android sparse image version {major}"));
+    }
+    let file_header_size = LittleEndian::read_u16(&header[8..10]) as u64;
+    let chunk_header_size = LittleEndian::read_u16(&header[10..12]) as u64;
+    let block_size = LittleEndian::read_u32(&header[12..16]) as u64;
+    let total_blocks = LittleEndian::read_u32(&header[16..20]) as u64;
+    let total_chunks = LittleEndian::read_u32(&header[20..24]);
+    if (file_header_size as usize) < FILE_HEADER_SIZE
+        || (chunk_header_size as usize) < CHUNK_HEADER_SIZE
+        || block_size == 0
+        || block_size % 4 != 0
+    {
+        return Err(eyre!("bad android sparse image header"));
+    }
+    set_len(total_blocks * block_size)?;
+
+    let mut pos = file_header_size;
+    let mut block = 0;
+    let mut chunk = [0; CHUNK_HEADER_SIZE];
+    for i in 0..total_chunks {
+        file.read_exact_at(&mut chunk, pos)?;
+        let chunk_type = LittleEndian::read_u16(&chunk[..2]);
+        let blocks = LittleEndian::read_u32(&chunk[4..8]) as u64;
+        let total_size = LittleEndian::read_u32(&chunk[8..12]) as u64;
+        let body = pos + chunk_header_size;
+        let body_size = total_size
+            .checked_sub(chunk_header_size)
+            .ok_or_else(|| eyre!("chunk {i} is smaller than its header"))?;
+        if block + blocks > total_blocks {
+            return Err(eyre!("chunk {i} runs past the end of the image"));
+        }
+
+        match chunk_type {
+            CHUNK_RAW => {
+                if body_size != blocks * block_size {
+                    return Err(eyre!("raw chunk {i} has {body_size} bytes of data"));
+                }
+                let mut data = vec![0; block_size as usize];
+                for n in 0..blocks {
+                    file.read_exact_at(&mut data, body + n * block_size)?;
+                    if BlockContents::of(&data) != BlockContents::Fill(0) {
+                        write_at((block + n) * block_size, &data)?;
+                    }
+                }
+            }
+            CHUNK_FILL => {
+                if body_size != 4 {
+                    return Err(eyre!("fill chunk {i} has {body_size} bytes of data"));
+                }
+                let mut fill = [0; 4];
+                file.read_exact_at(&mut fill, body)?;
+                if fill != [0; 4] {
+                    let data: Vec<u8> = fill.repeat(block_size as usize / 4);
+                    for n in 0..blocks {
+                        write_at((block + n) * block_size, &data)?;
+                    }
+                }
+            }
+            CHUNK_DONT_CARE => {}
+            // checksums of everything so far, which we don't check
+            CHUNK_CRC32 => {}
+            _ => return Err(eyre!("chunk {i} has unknown type {chunk_type:#x}")),
+        }
+
+        block += blocks;
+        pos = body + body_size;
+    }
+
+    if block != total_blocks {
+        return Err(eyre!(
+            "android sparse image has {block} of {total_blocks} blocks"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    #[test]
+    pub fn test_sparse_export_works() -> Result<()> {
+        let temp = TempDir::new()?;
+        let image = ExtMemoryImage::new(64 * 1024 * 1024);
+        let fs = ExtFilesystem::create_in_memory(&image, None)?;
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+
+        let sparse = temp.path_view().join("sparse.ext4");
+        fs.export_sparse(&sparse)?;
+        drop(fs);
+
+        let metadata = fs::metadata(&sparse)?;
+        assert_eq!(image.len(), metadata.len());
+        // most of an empty filesystem is holes
+        assert!(metadata.blocks() * 512 < image.len() / 2);
+        assert_fsck_clean(&sparse)?;
+
+        let fs = ExtFilesystem::open(&sparse, None, None)?;
+        let file = fs.open_file(fs.find_inode("/test.txt")?.0, None)?;
+        let mut buf = [0u8; 11];
+        fs.read_file(&file, &mut buf)?;
+        assert_eq!(b"hello flail", &buf);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_android_sparse_images_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let image = ExtMemoryImage::new(64 * 1024 * 1024);
+        let fs = ExtFilesystem::create_in_memory(&image, None)?;
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+
+        let sparse = temp.path_view().join("android.simg");
+        fs.export_android_sparse(&sparse)?;
+        drop(fs);
+        assert!(fs::metadata(&sparse)?.len() < image.len() / 2);
+
+        let raw = temp.path_view().join("raw.ext4");
+        android_sparse_to_raw(&sparse, &raw)?;
+        assert_eq!(image.len(), fs::metadata(&raw)?.len());
+        assert_fsck_clean(&raw)?;
+
+        let read_back = ExtMemoryImage::from_android_sparse(&sparse)?;
+        assert_eq!(image.len(), read_back.len());
+        let fs = ExtFilesystem::open_in_memory(&read_back, None)?;
+        let file = fs.open_file(fs.find_inode("/test.txt")?.0, None)?;
+        let mut buf = [0u8; 11];
+        fs.read_file(&file, &mut buf)?;
+        assert_eq!(b"hello flail", &buf);
+
+        // and it's a sparse image, not something else
+        assert!(android_sparse_to_raw(&raw, temp.path_view().join("nope")).is_err());
+
+        Ok(())
+    }
+}
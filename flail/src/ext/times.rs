@@ -0,0 +1,80 @@
+use super::*;
+
+impl ExtFilesystem {
+    /// Sets the access and modification times of `path`, down to the
+    /// nanosecond, like `utimensat(2)`. The change time moves to now.
+    pub fn set_times<P: Into<PathBuf>>(
+        &self,
+        path: P,
+        atime: SystemTime,
+        mtime: SystemTime,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let mut inode = self.find_inode(path)?;
+        inode.set_atime(atime);
+        inode.set_mtime(mtime);
+        inode.stamp(ExtInodeTimes::CTIME, self.now_time());
+        self.write_inode(&mut inode)
+    }
+
+    /// Moves `times` of inode `inum` to now.
+    pub(crate) fn stamp_inode(&self, inum: u32, times: ExtInodeTimes) -> Result<()> {
+        let mut inode = self.read_inode(inum)?;
+        inode.stamp(times, self.now_time());
+        self.write_inode(&mut inode)
+    }
+
+    /// Records that an entry was added to or removed from directory `dir`.
+    pub(crate) fn stamp_dir_changed(&self, dir: u32) -> Result<()> {
+        self.stamp_inode(dir, ExtInodeTimes::MTIME | ExtInodeTimes::CTIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    pub fn test_times_are_maintained() -> Result<()> {
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let fs =
+            ExtFilesystem::create_in_memory(&image, Some(ExtMkfsOptions::new().inode_size(256)))?;
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs.set_clamp_time(Some(epoch))?;
+
+        fs.mkdir("/", "dir")?;
+        fs.write_to_file("/dir/test.txt", "hello flail".as_bytes())?;
+        let inode = fs.find_inode("/dir/test.txt")?;
+        for time in [
+            inode.atime()?,
+            inode.mtime()?,
+            inode.ctime()?,
+            inode.crtime()?,
+        ] {
+            assert_eq!(epoch, time);
+        }
+        assert_eq!(epoch, fs.find_inode("/dir")?.mtime()?);
+        assert_eq!(epoch, fs.find_inode("/dir")?.crtime()?);
+
+        // explicit times keep their nanoseconds
+        let atime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(5_000_000_000, 987_654_321);
+        fs.set_times("/dir/test.txt", atime, mtime)?;
+        let inode = fs.find_inode("/dir/test.txt")?;
+        assert_eq!(atime, inode.atime()?);
+        assert_eq!(mtime, inode.mtime()?);
+
+        // removing entries changes the directory, but not when it was born
+        let later = epoch + Duration::from_secs(60);
+        fs.set_clamp_time(Some(later))?;
+        fs.unlink("/dir/test.txt")?;
+        let dir = fs.find_inode("/dir")?;
+        assert_eq!(later, dir.mtime()?);
+        assert_eq!(later, dir.ctime()?);
+        assert_eq!(epoch, dir.crtime()?);
+
+        Ok(())
+    }
+}
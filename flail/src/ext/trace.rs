@@ -0,0 +1,409 @@
+use std::ffi::{c_char, c_int, c_void};
+use std::sync::{Mutex, MutexGuard};
+
+use super::*;
+
+/// What kind of io an [`IoTraceEvent`] was.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum IoTraceOp {
+    Read,
+    Write,
+    Discard,
+    Zeroout,
+    Flush,
+}
+
+/// One io done by a traced filesystem. Offsets and lengths are in bytes,
+/// relative to the start of the filesystem's [`ExtImageWindow`]. Flushes
+/// have neither.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct IoTraceEvent {
+    op: IoTraceOp,
+    offset: u64,
+    len: u64,
+}
+
+impl IoTraceEvent {
+    pub fn op(&self) -> IoTraceOp {
+        self.op
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The io done by a filesystem opened with [`ExtOpenOptions::trace_io`], in
+/// the order libe2fs did it. Clones share the same events.
+#[derive(Clone, Debug, Default)]
+pub struct IoTrace(Arc<Mutex<Vec<IoTraceEvent>>>);
+
+impl IoTrace {
+    pub fn events(&self) -> Vec<IoTraceEvent> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Takes the events recorded so far, leaving the trace empty.
+    pub fn take(&self) -> Vec<IoTraceEvent> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    fn record(&self, op: IoTraceOp, offset: u64, len: u64) {
+        trace!("io: {op:?} of {len} bytes at {offset}");
+        self.0
+            .lock()
+            .unwrap()
+            .push(IoTraceEvent { op, offset, len });
+    }
+}
+
+impl ExtFilesystem {
+    /// The io trace of this filesystem, if it was opened with
+    /// [`ExtOpenOptions::trace_io`].
+    pub fn io_trace(&self) -> Option<IoTrace> {
+        let fs = *self.0.read().unwrap();
+        unsafe { trace_channel((*fs).io).map(|channel| channel.trace.clone()) }
+    }
+}
+
+lazy_static! {
+    /// An io_manager that records every io before passing it on to the
+    /// channel of another io_manager, like libe2fs' undo io manager does.
+    pub(crate) static ref TRACING_IO_MANAGER: IoManager = IoManager(Arc::new(RwLock::new(
+        libe2fs_sys::struct_io_manager {
+            magic: libe2fs_sys::EXT2_ET_MAGIC_IO_MANAGER as i64,
+            name: TRACING_IO_MANAGER_NAME.as_ptr(),
+            open: Some(trace_open),
+            close: Some(trace_close),
+            set_blksize: Some(trace_set_blksize),
+            read_blk: Some(trace_read_blk64),
+            write_blk: Some(trace_write_blk64),
+            flush: Some(trace_flush),
+            write_byte: Some(trace_write_byte),
+            set_option: Some(trace_set_option),
+            get_stats: Some(trace_get_stats),
+            read_blk64: Some(trace_read_blk64),
+            write_blk64: Some(trace_write_blk64),
+            discard: Some(trace_discard),
+            cache_readahead: Some(trace_cache_readahead),
+            zeroout: Some(trace_zeroout),
+            ..unsafe { std::mem::zeroed() }
+        }
+    )));
+
+    /// Like undo files, the manager to trace is handed to `trace_open`
+    /// through a global, so only one traced filesystem opens at a time.
+    static ref TRACE_SETUP: Mutex<()> = Mutex::new(());
+    static ref PENDING_TRACE: Mutex<Option<PendingTrace>> = Mutex::new(None);
+}
+
+static TRACING_IO_MANAGER_NAME: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"flail tracing io manager\0") };
+
+struct PendingTrace {
+    backing_manager: *mut libe2fs_sys::struct_io_manager,
+    trace: IoTrace,
+}
+
+// SAFETY: io managers are never changed after they're built.
+unsafe impl Send for PendingTrace {}
+
+struct TraceChannel {
+    inner: libe2fs_sys::io_channel,
+    trace: IoTrace,
+}
+
+/// Makes the next channel opened by [`TRACING_IO_MANAGER`] trace a channel
+/// of `backing_manager` into `trace`. Keep the guard until the filesystem is
+/// open.
+pub(crate) fn tracing_io_manager(
+    backing_manager: *mut libe2fs_sys::struct_io_manager,
+    trace: IoTrace,
+) -> (MutexGuard<'static, ()>, *mut libe2fs_sys::struct_io_manager) {
+    let guard = TRACE_SETUP.lock().unwrap();
+    *PENDING_TRACE.lock().unwrap() = Some(PendingTrace {
+        backing_manager,
+        trace,
+    });
+    (guard, TRACING_IO_MANAGER.as_ptr())
+}
+
+/// The channel a traced channel passes its io on to.
+pub(crate) unsafe fn traced_channel(
+    io: libe2fs_sys::io_channel,
+) -> Option<libe2fs_sys::io_channel> {
+    trace_channel(io).map(|channel| channel.inner)
+}
+
+unsafe fn trace_channel<'a>(io: libe2fs_sys::io_channel) -> Option<&'a mut TraceChannel> {
+    if io.is_null() || (*io).manager != TRACING_IO_MANAGER.as_ptr() {
+        return None;
+    }
+    Some(&mut *((*io).private_data as *mut TraceChannel))
+}
+
+/// The manager of the channel `io` passes its io on to, and that channel.
+unsafe fn inner_of(
+    io: libe2fs_sys::io_channel,
+) -> (
+    &'static libe2fs_sys::struct_io_manager,
+    libe2fs_sys::io_channel,
+) {
+    let inner = (*((*io).private_data as *mut TraceChannel)).inner;
+    (&*(*inner).manager, inner)
+}
+
+unsafe fn record(io: libe2fs_sys::io_channel, op: IoTraceOp, offset: u64, len: u64) {
+    (*((*io).private_data as *mut TraceChannel))
+        .trace
+        .record(op, offset, len);
+}
+
+const UNIMPLEMENTED: libe2fs_sys::errcode_t =
+    libe2fs_sys::EXT2_ET_UNIMPLEMENTED as libe2fs_sys::errcode_t;
+
+unsafe extern "C" fn trace_open(
+    name: *const c_char,
+    flags: c_int,
+    channel: *mut libe2fs_sys::io_channel,
+) -> libe2fs_sys::errcode_t {
+    let Some(pending) = PENDING_TRACE.lock().unwrap().take() else {
+        return libe2fs_sys::EXT2_ET_BAD_DEVICE_NAME as libe2fs_sys::errcode_t;
+    };
+
+    let mut inner = std::ptr::null_mut();
+    let err = (*pending.backing_manager).open.unwrap()(name, flags, &mut inner);
+    if err != 0 {
+        return err;
+    }
+
+    let io: libe2fs_sys::io_channel = Box::into_raw(Box::new(std::mem::zeroed()));
+    (*io).magic = libe2fs_sys::EXT2_ET_MAGIC_IO_CHANNEL as i64;
+    (*io).manager = TRACING_IO_MANAGER.as_ptr();
+    (*io).name = CStr::from_ptr(name).to_owned().into_raw();
+    (*io).block_size = (*inner).block_size;
+    (*io).refcount = 1;
+    (*io).flags = flags;
+    (*io).align = (*inner).align;
+    (*io).private_data = Box::into_raw(Box::new(TraceChannel {
+        inner,
+        trace: pending.trace,
+    })) as *mut _;
+    *channel = io;
+    0
+}
+
+unsafe extern "C" fn trace_close(io: libe2fs_sys::io_channel) -> libe2fs_sys::errcode_t {
+    // like backend_close, only the last close of a shared channel frees it
+    (*io).refcount -= 1;
+    if (*io).refcount > 0 {
+        return 0;
+    }
+    let (manager, inner) = inner_of(io);
+    let err = manager.close.unwrap()(inner);
+    drop(Box::from_raw((*io).private_data as *mut TraceChannel));
+    drop(CString::from_raw((*io).name));
+    drop(Box::from_raw(io));
+    err
+}
+
+unsafe extern "C" fn trace_set_blksize(
+    io: libe2fs_sys::io_channel,
+    block_size: c_int,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    let err = manager.set_blksize.unwrap()(inner, block_size);
+    if err == 0 {
+        (*io).block_size = block_size;
+    }
+    err
+}
+
+unsafe extern "C" fn trace_read_blk64(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: c_int,
+    data: *mut c_void,
+) -> libe2fs_sys::errcode_t {
+    let (offset, len) = byte_range(io, block, count);
+    record(io, IoTraceOp::Read, offset, len as u64);
+    let (manager, inner) = inner_of(io);
+    manager.read_blk64.unwrap()(inner, block, count, data)
+}
+
+unsafe extern "C" fn trace_write_blk64(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: c_int,
+    data: *const c_void,
+) -> libe2fs_sys::errcode_t {
+    let (offset, len) = byte_range(io, block, count);
+    record(io, IoTraceOp::Write, offset, len as u64);
+    let (manager, inner) = inner_of(io);
+    manager.write_blk64.unwrap()(inner, block, count, data)
+}
+
+unsafe extern "C" fn trace_write_byte(
+    io: libe2fs_sys::io_channel,
+    offset: u64,
+    size: c_int,
+    data: *const c_void,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    let Some(write_byte) = manager.write_byte else {
+        return UNIMPLEMENTED;
+    };
+    record(io, IoTraceOp::Write, offset, size as u64);
+    write_byte(inner, offset, size, data)
+}
+
+unsafe extern "C" fn trace_flush(io: libe2fs_sys::io_channel) -> libe2fs_sys::errcode_t {
+    record(io, IoTraceOp::Flush, 0, 0);
+    let (manager, inner) = inner_of(io);
+    manager.flush.unwrap()(inner)
+}
+
+unsafe extern "C" fn trace_set_option(
+    io: libe2fs_sys::io_channel,
+    option: *const c_char,
+    arg: *const c_char,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    match manager.set_option {
+        Some(set_option) => set_option(inner, option, arg),
+        None => libe2fs_sys::EXT2_ET_INVALID_ARGUMENT as libe2fs_sys::errcode_t,
+    }
+}
+
+unsafe extern "C" fn trace_get_stats(
+    io: libe2fs_sys::io_channel,
+    stats: *mut libe2fs_sys::io_stats,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    match manager.get_stats {
+        Some(get_stats) => get_stats(inner, stats),
+        None => UNIMPLEMENTED,
+    }
+}
+
+unsafe extern "C" fn trace_discard(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: u64,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    let Some(discard) = manager.discard else {
+        return UNIMPLEMENTED;
+    };
+    let block_size = (*io).block_size as u64;
+    record(
+        io,
+        IoTraceOp::Discard,
+        block * block_size,
+        count * block_size,
+    );
+    discard(inner, block, count)
+}
+
+unsafe extern "C" fn trace_cache_readahead(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: u64,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    match manager.cache_readahead {
+        Some(cache_readahead) => cache_readahead(inner, block, count),
+        None => UNIMPLEMENTED,
+    }
+}
+
+unsafe extern "C" fn trace_zeroout(
+    io: libe2fs_sys::io_channel,
+    block: u64,
+    count: u64,
+) -> libe2fs_sys::errcode_t {
+    let (manager, inner) = inner_of(io);
+    let Some(zeroout) = manager.zeroout else {
+        return UNIMPLEMENTED;
+    };
+    let block_size = (*io).block_size as u64;
+    record(
+        io,
+        IoTraceOp::Zeroout,
+        block * block_size,
+        count * block_size,
+    );
+    zeroout(inner, block, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext::tests::TempImage;
+
+    #[test]
+    pub fn test_io_stats_work() -> Result<()> {
+        let img = TempImage::new("./fixtures/empty.ext4")?;
+        let fs = ExtFilesystem::open(
+            img.path_view(),
+            None,
+            Some(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW),
+        )?;
+        let before = fs.io_stats()?;
+        assert!(before.bytes_read() > 0);
+
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        assert!(fs.io_stats()?.bytes_written() > before.bytes_written());
+
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let fs = ExtFilesystem::create_in_memory(&image, None)?;
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        let stats = fs.io_stats()?;
+        assert!(stats.bytes_written() > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_io_tracing_works() -> Result<()> {
+        let img = TempImage::new("./fixtures/empty.ext4")?;
+        let fs = ExtFilesystem::open_with_options(
+            img.path_view(),
+            ExtOpenOptions::new()
+                .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW)
+                .trace_io(true),
+        )?;
+        let trace = fs.io_trace().unwrap();
+
+        // opening reads the superblock
+        let events = trace.take();
+        assert!(events.contains(&IoTraceEvent {
+            op: IoTraceOp::Read,
+            offset: 1024,
+            len: 1024,
+        }));
+
+        fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        let events = trace.take();
+        assert!(events.iter().any(|event| event.op() == IoTraceOp::Write));
+        assert!(events.iter().any(|event| event.op() == IoTraceOp::Flush));
+        assert!(trace.events().is_empty());
+
+        // tracing doesn't get in the way of stats
+        assert!(fs.io_stats()?.bytes_written() > 0);
+
+        let image = ExtMemoryImage::new(16 * 1024 * 1024);
+        let untraced = ExtFilesystem::create_in_memory(&image, None)?;
+        assert!(untraced.io_trace().is_none());
+
+        Ok(())
+    }
+}
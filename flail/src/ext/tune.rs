@@ -0,0 +1,676 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use super::resize::{rewrite_extent_checksums, ExtGeometry};
+use super::*;
+
+/// What the kernel does when it finds an error in the filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtErrorBehaviour {
+    Continue,
+    RemountReadOnly,
+    Panic,
+}
+
+impl ExtErrorBehaviour {
+    pub(crate) fn to_raw(self) -> u16 {
+        (match self {
+            Self::Continue => libe2fs_sys::EXT2_ERRORS_CONTINUE,
+            Self::RemountReadOnly => libe2fs_sys::EXT2_ERRORS_RO,
+            Self::Panic => libe2fs_sys::EXT2_ERRORS_PANIC,
+        }) as u16
+    }
+}
+
+bitflags! {
+    /// Mount options the kernel applies unless told otherwise. The journal
+    /// modes share bits, so only set one of them.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ExtDefaultMountOptions: u32 {
+        const DEBUG = libe2fs_sys::EXT2_DEFM_DEBUG;
+        const BSD_GROUPS = libe2fs_sys::EXT2_DEFM_BSDGROUPS;
+        const USER_XATTR = libe2fs_sys::EXT2_DEFM_XATTR_USER;
+        const ACL = libe2fs_sys::EXT2_DEFM_ACL;
+        const UID16 = libe2fs_sys::EXT2_DEFM_UID16;
+        const JOURNAL_DATA = libe2fs_sys::EXT3_DEFM_JMODE_DATA;
+        const JOURNAL_ORDERED = libe2fs_sys::EXT3_DEFM_JMODE_ORDERED;
+        const JOURNAL_WRITEBACK = libe2fs_sys::EXT3_DEFM_JMODE_WBACK;
+        const NOBARRIER = libe2fs_sys::EXT4_DEFM_NOBARRIER;
+        const BLOCK_VALIDITY = libe2fs_sys::EXT4_DEFM_BLOCK_VALIDITY;
+        const DISCARD = libe2fs_sys::EXT4_DEFM_DISCARD;
+        const NODELALLOC = libe2fs_sys::EXT4_DEFM_NODELALLOC;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ExtReservedBlocks {
+    Percent(f64),
+    Blocks(u64),
+}
+
+/// Changes to make to an existing filesystem with [`ExtFilesystem::tune`],
+/// like tune2fs. Anything left unset is left alone.
+#[derive(Clone, Debug)]
+pub struct ExtTuneOptions {
+    pub(crate) label: Option<String>,
+    pub(crate) uuid: Option<Uuid>,
+    pub(crate) max_mount_count: Option<i16>,
+    pub(crate) check_interval: Option<Duration>,
+    pub(crate) errors: Option<ExtErrorBehaviour>,
+    pub(crate) default_mount_options: Option<ExtDefaultMountOptions>,
+    pub(crate) reserved_blocks: Option<ExtReservedBlocks>,
+    pub(crate) reserved_uid: Option<u32>,
+    pub(crate) reserved_gid: Option<u32>,
+    pub(crate) enable_features: ExtFeatures,
+    pub(crate) disable_features: ExtFeatures,
+}
+
+impl Default for ExtTuneOptions {
+    fn default() -> Self {
+        Self {
+            label: None,
+            uuid: None,
+            max_mount_count: None,
+            check_interval: None,
+            errors: None,
+            default_mount_options: None,
+            reserved_blocks: None,
+            reserved_uid: None,
+            reserved_gid: None,
+            enable_features: ExtFeatures::empty(),
+            disable_features: ExtFeatures::empty(),
+        }
+    }
+}
+
+impl ExtTuneOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Changes the uuid. With `metadata_csum` but without
+    /// `metadata_csum_seed`, every checksum is derived from the uuid, so
+    /// they all get rewritten.
+    pub fn uuid(mut self, uuid: Uuid) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// How many mounts until the kernel asks for a fsck, or -1 to never ask.
+    pub fn max_mount_count(mut self, count: i16) -> Self {
+        self.max_mount_count = Some(count);
+        self
+    }
+
+    /// How long until the kernel asks for a fsck, or zero to never ask.
+    pub fn check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = Some(interval);
+        self
+    }
+
+    pub fn errors(mut self, errors: ExtErrorBehaviour) -> Self {
+        self.errors = Some(errors);
+        self
+    }
+
+    pub fn default_mount_options(mut self, options: ExtDefaultMountOptions) -> Self {
+        self.default_mount_options = Some(options);
+        self
+    }
+
+    /// Reserves a percentage of the filesystem for the reserved uid and gid.
+    pub fn reserved_percent(mut self, percent: f64) -> Self {
+        self.reserved_blocks = Some(ExtReservedBlocks::Percent(percent));
+        self
+    }
+
+    /// Reserves exactly `blocks` blocks for the reserved uid and gid.
+    pub fn reserved_blocks(mut self, blocks: u64) -> Self {
+        self.reserved_blocks = Some(ExtReservedBlocks::Blocks(blocks));
+        self
+    }
+
+    pub fn reserved_uid(mut self, uid: u32) -> Self {
+        self.reserved_uid = Some(uid);
+        self
+    }
+
+    pub fn reserved_gid(mut self, gid: u32) -> Self {
+        self.reserved_gid = Some(gid);
+        self
+    }
+
+    /// Turns features on. See [`Self::toggleable_features`] for which ones
+    /// can be.
+    pub fn enable_features(mut self, features: ExtFeatures) -> Self {
+        self.disable_features.remove(features);
+        self.enable_features.insert(features);
+        self
+    }
+
+    /// Turns features off. `extents` and `huge_file` can only be turned off
+    /// while no inode uses them.
+    pub fn disable_features(mut self, features: ExtFeatures) -> Self {
+        self.enable_features.remove(features);
+        self.disable_features.insert(features);
+        self
+    }
+
+    /// The features that can be turned on or off without reformatting.
+    ///
+    /// Turning on `metadata_csum`, or turning off `dir_index`, turns every
+    /// indexed directory back into a plain one, which the kernel reads just
+    /// the same. Turning off `metadata_csum` turns on `uninit_bg` instead,
+    /// unless that is turned off too.
+    pub fn toggleable_features() -> ExtFeatures {
+        ExtFeatures {
+            compat: ExtCompatFeatures::DIR_INDEX,
+            incompat: ExtIncompatFeatures::EXTENTS | ExtIncompatFeatures::CSUM_SEED,
+            ro_compat: ExtRoCompatFeatures::HUGE_FILE
+                | ExtRoCompatFeatures::LARGE_FILE
+                | ExtRoCompatFeatures::DIR_NLINK
+                | ExtRoCompatFeatures::GDT_CSUM
+                | ExtRoCompatFeatures::METADATA_CSUM,
+        }
+    }
+
+    /// The features a filesystem that has `features` ends up with.
+    pub(crate) fn features_after(&self, features: ExtFeatures) -> ExtFeatures {
+        let mut out = features;
+        out.remove(self.disable_features);
+        out.insert(self.enable_features);
+
+        if features
+            .ro_compat
+            .contains(ExtRoCompatFeatures::METADATA_CSUM)
+            && !out.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM)
+        {
+            out.incompat.remove(ExtIncompatFeatures::CSUM_SEED);
+            // keep the group descriptors checksummed, like tune2fs
+            if !self
+                .disable_features
+                .ro_compat
+                .contains(ExtRoCompatFeatures::GDT_CSUM)
+            {
+                out.ro_compat.insert(ExtRoCompatFeatures::GDT_CSUM);
+            }
+        }
+        if out.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM) {
+            // metadata_csum supersedes it
+            out.ro_compat.remove(ExtRoCompatFeatures::GDT_CSUM);
+        }
+        out
+    }
+
+    /// Checks the options against the filesystem they're for, so that we
+    /// fail before touching the disk.
+    pub(crate) fn validate(&self, fs: &ExtFilesystem) -> Result<()> {
+        let raw = *fs.0.read().unwrap();
+        let geometry = ExtGeometry::of(raw);
+
+        if let Some(label) = &self.label {
+            if label.len() > libe2fs_sys::EXT2_LABEL_LEN as usize {
+                return Err(eyre!(
+                    "volume label {label:?} is longer than {} bytes",
+                    libe2fs_sys::EXT2_LABEL_LEN
+                ));
+            }
+        }
+
+        if let Some(interval) = self.check_interval {
+            if interval.as_secs() > u32::MAX as u64 {
+                return Err(eyre!("invalid check interval {interval:?}, too long"));
+            }
+        }
+
+        match self.reserved_blocks {
+            Some(ExtReservedBlocks::Percent(percent)) if !(0.0..=50.0).contains(&percent) => {
+                return Err(eyre!(
+                    "invalid reserved block percentage {percent}, must be between 0 and 50"
+                ));
+            }
+            Some(ExtReservedBlocks::Blocks(blocks)) if blocks > geometry.blocks / 2 => {
+                return Err(eyre!(
+                    "invalid reserved block count {blocks}, must be at most half of the {} blocks",
+                    geometry.blocks
+                ));
+            }
+            _ => {}
+        }
+
+        for (name, id) in [("uid", self.reserved_uid), ("gid", self.reserved_gid)] {
+            if let Some(id) = id {
+                if id > u16::MAX as u32 {
+                    return Err(eyre!(
+                        "invalid reserved {name} {id}, the superblock only holds 16 bits"
+                    ));
+                }
+            }
+        }
+
+        let toggleable = Self::toggleable_features();
+        let untoggleable = self
+            .enable_features
+            .difference(toggleable)
+            .union(self.disable_features.difference(toggleable));
+        if !untoggleable.is_empty() {
+            return Err(eyre!(
+                "these features can't be turned on or off: {untoggleable:?}"
+            ));
+        }
+
+        let old = fs.features();
+        let new = self.features_after(old);
+        new.validate(geometry.block_size as u32, geometry.desc_size as u16)?;
+
+        let changed = old.difference(new).union(new.difference(old));
+        let unsupported = old.unsupported();
+        if !changed.is_empty() && !unsupported.is_empty() {
+            return Err(eyre!(
+                "can't change features of a filesystem that uses {unsupported:?}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl ExtFilesystem {
+    /// Changes superblock settings and features of the filesystem, like
+    /// tune2fs. The filesystem must be open read-write, and must not be
+    /// mounted anywhere.
+    pub fn tune(&self, options: ExtTuneOptions) -> Result<()> {
+        self.check_writable()?;
+        let fs = *self.0.read().unwrap();
+        options.validate(self)?;
+
+        // whatever checksums we come across are about to be rewritten, or
+        // are stale because their seed or feature is changing
+        let ignore = libe2fs_sys::EXT2_FLAG_IGNORE_CSUM_ERRORS as i32;
+        let was_ignoring = unsafe { (*fs).flags } & ignore;
+        unsafe { (*fs).flags |= ignore };
+        let res = self.apply_tune(&options);
+        unsafe { (*fs).flags = ((*fs).flags & !ignore) | was_ignoring };
+        res
+    }
+
+    fn apply_tune(&self, options: &ExtTuneOptions) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let old = self.features();
+        let new = options.features_after(old);
+        let enabled = new.difference(old);
+        let disabled = old.difference(new);
+
+        if disabled.incompat.contains(ExtIncompatFeatures::EXTENTS) {
+            self.check_feature_unused("extents", |inode| {
+                inode.i_flags & libe2fs_sys::EXT4_EXTENTS_FL != 0
+            })?;
+        }
+        if disabled.ro_compat.contains(ExtRoCompatFeatures::HUGE_FILE) {
+            self.check_feature_unused("huge_file", |inode| {
+                inode.i_flags & libe2fs_sys::EXT4_HUGE_FILE_FL != 0
+                    || unsafe { inode.osd2.linux2.l_i_blocks_hi } != 0
+            })?;
+        }
+
+        let csum_after = new.ro_compat.contains(ExtRoCompatFeatures::METADATA_CSUM);
+        let enabling_csum = enabled
+            .ro_compat
+            .contains(ExtRoCompatFeatures::METADATA_CSUM);
+        let linearize = enabling_csum || disabled.compat.contains(ExtCompatFeatures::DIR_INDEX);
+        if csum_after && linearize {
+            // every plain directory block needs a checksum tail, so find out
+            // whether they all have room for one before changing anything
+            self.for_each_directory(|inum| self.rewrite_dir_blocks(inum, true, true))?;
+        }
+
+        let seed_before = unsafe { (*fs).csum_seed };
+        let uuid_seed_before = unsafe {
+            let uuid = &(*(*fs).super_).s_uuid;
+            libe2fs_sys::ext2fs_crc32c_le(!0, uuid.as_ptr(), uuid.len())
+        };
+
+        unsafe {
+            let superblock = (*fs).super_;
+
+            if let Some(label) = &options.label {
+                let mut volume_name = [0; libe2fs_sys::EXT2_LABEL_LEN as usize];
+                volume_name[..label.len()].copy_from_slice(label.as_bytes());
+                (*superblock).s_volume_name = volume_name;
+            }
+            if let Some(count) = options.max_mount_count {
+                (*superblock).s_max_mnt_count = count;
+            }
+            if let Some(interval) = options.check_interval {
+                (*superblock).s_checkinterval = interval.as_secs() as u32;
+            }
+            if let Some(errors) = options.errors {
+                (*superblock).s_errors = errors.to_raw();
+            }
+            if let Some(mount_options) = options.default_mount_options {
+                (*superblock).s_default_mount_opts = mount_options.bits();
+            }
+            if let Some(reserved) = options.reserved_blocks {
+                let blocks = libe2fs_sys::ext2fs_blocks_count(superblock);
+                let reserved = match reserved {
+                    ExtReservedBlocks::Percent(percent) => (blocks as f64 * percent / 100.0) as u64,
+                    ExtReservedBlocks::Blocks(reserved) => reserved,
+                };
+                libe2fs_sys::ext2fs_r_blocks_count_set(superblock, reserved);
+            }
+            if let Some(uid) = options.reserved_uid {
+                (*superblock).s_def_resuid = uid as u16;
+            }
+            if let Some(gid) = options.reserved_gid {
+                (*superblock).s_def_resgid = gid as u16;
+            }
+
+            if enabled.incompat.contains(ExtIncompatFeatures::CSUM_SEED) {
+                // pin the seed checksums are currently made with, so that
+                // changing the uuid from here on doesn't change it
+                (*superblock).s_checksum_seed = if enabling_csum {
+                    uuid_seed_before
+                } else {
+                    seed_before
+                };
+            }
+            if let Some(uuid) = options.uuid {
+                (*superblock).s_uuid = *uuid.as_bytes();
+            }
+            if enabled.compat.contains(ExtCompatFeatures::DIR_INDEX)
+                && (*superblock).s_hash_seed.iter().all(|word| *word == 0)
+            {
+                let hash_seed = Uuid::new_v4();
+                for (i, word) in (*superblock).s_hash_seed.iter_mut().enumerate() {
+                    *word = u32::from_le_bytes(hash_seed.as_bytes()[i * 4..i * 4 + 4].try_into()?);
+                }
+                (*superblock).s_def_hash_version = libe2fs_sys::EXT2_HASH_HALF_MD4 as u8;
+            }
+            if enabling_csum {
+                (*superblock).s_checksum_type = libe2fs_sys::EXT2_CRC32C_CHKSUM as u8;
+            }
+
+            (*superblock).s_feature_compat = new.compat.bits();
+            (*superblock).s_feature_incompat = new.incompat.bits();
+            (*superblock).s_feature_ro_compat = new.ro_compat.bits();
+            if !new.incompat.contains(ExtIncompatFeatures::CSUM_SEED) {
+                (*superblock).s_checksum_seed = 0;
+            }
+            libe2fs_sys::ext2fs_init_csum_seed(fs);
+        }
+
+        let reseeded = unsafe { (*fs).csum_seed } != seed_before;
+        if linearize || (csum_after && (enabling_csum || reseeded)) {
+            self.rewrite_metadata(linearize, csum_after)?;
+        }
+
+        let group_csum = |features: ExtFeatures| {
+            features
+                .ro_compat
+                .intersects(ExtRoCompatFeatures::GDT_CSUM | ExtRoCompatFeatures::METADATA_CSUM)
+        };
+        if group_csum(old) && !group_csum(new) {
+            self.clear_uninit_groups()?;
+        }
+
+        unsafe {
+            (*fs).flags |= (libe2fs_sys::EXT2_FLAG_DIRTY
+                | libe2fs_sys::EXT2_FLAG_CHANGED
+                | libe2fs_sys::EXT2_FLAG_BB_DIRTY
+                | libe2fs_sys::EXT2_FLAG_IB_DIRTY) as i32;
+        }
+        let err = unsafe { libe2fs_sys::ext2fs_set_gdt_csum(fs) };
+        if err != 0 {
+            return report(err);
+        }
+        self.write_bitmaps()?;
+        self.flush()
+    }
+
+    fn check_feature_unused(
+        &self,
+        feature: &str,
+        uses: impl Fn(&libe2fs_sys::ext2_inode) -> bool,
+    ) -> Result<()> {
+        self.for_each_inode(|inum, inode| {
+            if uses(inode) {
+                Err(eyre!(
+                    "can't turn off {feature}, inode {inum} still uses it"
+                ))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Calls `f` with the number of every directory whose contents live in
+    /// blocks rather than in the inode.
+    fn for_each_directory(&self, mut f: impl FnMut(u32) -> Result<()>) -> Result<()> {
+        let mut dirs = vec![];
+        self.for_each_inode(|inum, inode| {
+            if (inode.i_mode as u32 & libe2fs_sys::LINUX_S_IFMT) == libe2fs_sys::LINUX_S_IFDIR
+                && inode.i_flags & libe2fs_sys::EXT4_INLINE_DATA_FL == 0
+            {
+                dirs.push(inum);
+            }
+            Ok(())
+        })?;
+        dirs.into_iter().try_for_each(f)
+    }
+
+    /// Writes every inode back out, and with `checksums` every extent, xattr
+    /// and directory block too, so that they're checksummed with the current
+    /// seed. With `linearize`, indexed directories become plain ones.
+    fn rewrite_metadata(&self, linearize: bool, checksums: bool) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let inode_size = ExtGeometry::of(fs).inode_size;
+        let mut inums = vec![];
+        self.for_each_inode(|inum, _| {
+            inums.push(inum);
+            Ok(())
+        })?;
+
+        let mut xattr_blocks = HashSet::new();
+        for inum in inums {
+            let mut buf = vec![0u32; inode_size / 4];
+            let inode = buf.as_mut_ptr() as *mut libe2fs_sys::ext2_inode;
+            let err =
+                unsafe { libe2fs_sys::ext2fs_read_inode_full(fs, inum, inode, inode_size as i32) };
+            if err != 0 {
+                return report(err);
+            }
+
+            let (mode, flags) = unsafe { ((*inode).i_mode, (*inode).i_flags) };
+            let is_dir = (mode as u32 & libe2fs_sys::LINUX_S_IFMT) == libe2fs_sys::LINUX_S_IFDIR;
+            let indexed = is_dir && flags & libe2fs_sys::EXT2_INDEX_FL != 0;
+            if linearize && indexed {
+                unsafe { (*inode).i_flags &= !libe2fs_sys::EXT2_INDEX_FL };
+            }
+            let err =
+                unsafe { libe2fs_sys::ext2fs_write_inode_full(fs, inum, inode, inode_size as i32) };
+            if err != 0 {
+                return report(err);
+            }
+
+            if !checksums {
+                continue;
+            }
+            let inline = flags & libe2fs_sys::EXT4_INLINE_DATA_FL != 0;
+            if flags & libe2fs_sys::EXT4_EXTENTS_FL != 0 && !inline {
+                rewrite_extent_checksums(fs, inum)?;
+            }
+
+            let xattr_block = unsafe { libe2fs_sys::ext2fs_file_acl_block(fs, inode) };
+            if xattr_block != 0 && xattr_blocks.insert(xattr_block) {
+                let mut block = vec![0u32; unsafe { (*fs).blocksize } as usize / 4];
+                let ptr = block.as_mut_ptr() as *mut ::std::ffi::c_void;
+                let err = unsafe { libe2fs_sys::ext2fs_read_ext_attr3(fs, xattr_block, ptr, inum) };
+                if err != 0 {
+                    return report(err);
+                }
+                let err =
+                    unsafe { libe2fs_sys::ext2fs_write_ext_attr3(fs, xattr_block, ptr, inum) };
+                if err != 0 {
+                    return report(err);
+                }
+            }
+
+            if is_dir && !inline {
+                // indexed directories keep the checksums of their index
+                // blocks in a tail of their own
+                self.rewrite_dir_blocks(inum, !indexed || linearize, false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes every block of a directory back out, giving the blocks that
+    /// lack a checksum tail one if `insert_tails`. With `dry_run`, nothing
+    /// is written, but it still fails if a block has no room for a tail.
+    fn rewrite_dir_blocks(&self, inum: u32, insert_tails: bool, dry_run: bool) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let mut blocks: Vec<u64> = vec![];
+        let err = unsafe {
+            libe2fs_sys::ext2fs_block_iterate3(
+                fs,
+                inum,
+                (libe2fs_sys::BLOCK_FLAG_READ_ONLY | libe2fs_sys::BLOCK_FLAG_DATA_ONLY) as i32,
+                std::ptr::null_mut(),
+                Some(collect_block),
+                &mut blocks as *mut _ as *mut ::std::ffi::c_void,
+            )
+        };
+        if err != 0 {
+            return report(err);
+        }
+
+        let mut buf = vec![0u32; unsafe { (*fs).blocksize } as usize / 4];
+        for block in blocks {
+            let ptr = buf.as_mut_ptr() as *mut ::std::ffi::c_void;
+            let err = unsafe { libe2fs_sys::ext2fs_read_dir_block4(fs, block, ptr, 0, inum) };
+            if err != 0 {
+                return report(err);
+            }
+            if insert_tails && !has_dirent_tail(&buf) && !insert_dirent_tail(fs, &mut buf)? {
+                return Err(eyre!(
+                    "block {block} of directory inode {inum} has no room for a checksum, run `e2fsck -fD` first"
+                ));
+            }
+            if dry_run {
+                continue;
+            }
+            let err = unsafe { libe2fs_sys::ext2fs_write_dir_block4(fs, block, ptr, 0, inum) };
+            if err != 0 {
+                return report(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks every group initialised, for when group descriptors stop being
+    /// checksummed and the kernel can no longer trust the uninit flags.
+    fn clear_uninit_groups(&self) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let geometry = ExtGeometry::of(fs);
+        for group in 0..geometry.groups {
+            unsafe {
+                if libe2fs_sys::ext2fs_bg_flags_test(
+                    fs,
+                    group,
+                    libe2fs_sys::EXT2_BG_INODE_UNINIT as u16,
+                ) != 0
+                {
+                    let err = libe2fs_sys::ext2fs_zero_blocks2(
+                        fs,
+                        libe2fs_sys::ext2fs_inode_table_loc(fs, group),
+                        geometry.inode_blocks_per_group as i32,
+                        std::ptr::null_mut(),
+                        std::ptr::null_mut(),
+                    );
+                    if err != 0 {
+                        return report(err);
+                    }
+                }
+                libe2fs_sys::ext2fs_bg_flags_clear(
+                    fs,
+                    group,
+                    (libe2fs_sys::EXT2_BG_BLOCK_UNINIT | libe2fs_sys::EXT2_BG_INODE_UNINIT) as u16,
+                );
+                libe2fs_sys::ext2fs_bg_itable_unused_set(fs, group, 0);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn has_dirent_tail(buf: &[u32]) -> bool {
+    let tail_words = std::mem::size_of::<libe2fs_sys::ext2_dir_entry_tail>() / 4;
+    let tail = unsafe {
+        &*(buf[buf.len() - tail_words..].as_ptr() as *const libe2fs_sys::ext2_dir_entry_tail)
+    };
+    tail.det_reserved_zero1 == 0
+        && tail.det_rec_len as usize == tail_words * 4
+        && tail.det_reserved_name_len == libe2fs_sys::EXT2_DIR_NAME_LEN_CSUM as u16
+}
+
+/// Shrinks the last entry of a directory block to make room for a checksum
+/// tail, and puts one there. Returns false if the entry has no room to give.
+fn insert_dirent_tail(fs: libe2fs_sys::ext2_filsys, buf: &mut [u32]) -> Result<bool> {
+    let block_size = buf.len() * 4;
+    let tail_size = std::mem::size_of::<libe2fs_sys::ext2_dir_entry_tail>();
+    let mut offset = 0;
+    loop {
+        let dirent = buf[offset / 4..].as_mut_ptr() as *mut libe2fs_sys::ext2_dir_entry;
+        let mut rec_len = 0;
+        let err = unsafe { libe2fs_sys::ext2fs_get_rec_len(fs, dirent, &mut rec_len) };
+        if err != 0 {
+            return report(err);
+        }
+        let rec_len = rec_len as usize;
+        if rec_len < 8 || rec_len % 4 != 0 || offset + rec_len > block_size {
+            return Err(eyre!("corrupt directory entry at offset {offset}"));
+        }
+        if offset + rec_len < block_size {
+            offset += rec_len;
+            continue;
+        }
+
+        let name_len = unsafe { (*dirent).name_len } as usize & 0xff;
+        if rec_len < (8 + name_len).next_multiple_of(4) + tail_size {
+            return Ok(false);
+        }
+        unsafe {
+            let err = libe2fs_sys::ext2fs_set_rec_len(fs, (rec_len - tail_size) as u32, dirent);
+            if err != 0 {
+                return report(err);
+            }
+            libe2fs_sys::ext2fs_initialize_dirent_tail(
+                fs,
+                buf[(block_size - tail_size) / 4..].as_mut_ptr()
+                    as *mut libe2fs_sys::ext2_dir_entry_tail,
+            );
+        }
+        return Ok(true);
+    }
+}
+
+unsafe extern "C" fn collect_block(
+    _fs: libe2fs_sys::ext2_filsys,
+    blocknr: *mut libe2fs_sys::blk64_t,
+    _blockcnt: libe2fs_sys::e2_blkcnt_t,
+    _ref_blk: libe2fs_sys::blk64_t,
+    _ref_offset: i32,
+    priv_data: *mut ::std::ffi::c_void,
+) -> i32 {
+    let blocks = &mut *(priv_data as *mut Vec<u64>);
+    blocks.push(*blocknr);
+    0
+}
@@ -0,0 +1,242 @@
+use std::ffi::{c_char, c_int, c_void};
+
+use super::*;
+
+/// The xattr namespaces ext4 can store, by their key prefix.
+const XATTR_NAMESPACES: [&str; 4] = ["user.", "trusted.", "security.", "system."];
+
+impl ExtFilesystem {
+    /// Lists the keys of every xattr on `path`, with their namespace prefix,
+    /// eg. `user.comment`.
+    pub fn list_xattrs<P: Into<PathBuf>>(&self, path: P) -> Result<Vec<String>> {
+        self.list_xattrs_by_inode(self.find_inode(path)?.0)
+    }
+
+    pub fn list_xattrs_by_inode(&self, inode: u32) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.with_xattrs(inode, 0, |handle| unsafe {
+            libe2fs_sys::ext2fs_xattrs_iterate(
+                handle,
+                Some(collect_xattr_key),
+                &mut keys as *mut _ as *mut c_void,
+            )
+        })?;
+        Ok(keys)
+    }
+
+    /// Reads the xattr `key` of `path`, or `None` if it isn't set.
+    pub fn get_xattr<P: Into<PathBuf>>(&self, path: P, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get_xattr_by_inode(self.find_inode(path)?.0, key)
+    }
+
+    pub fn get_xattr_by_inode(&self, inode: u32, key: &str) -> Result<Option<Vec<u8>>> {
+        self.read_xattr(inode, key, 0)
+    }
+
+    /// Reads an xattr through a handle with `flags`, such as
+    /// `XATTR_HANDLE_FLAG_RAW` to skip libe2fs's ACL conversion.
+    pub(crate) fn read_xattr(&self, inode: u32, key: &str, flags: u32) -> Result<Option<Vec<u8>>> {
+        let key = xattr_key(key)?;
+        let mut value = None;
+        self.with_xattrs(inode, flags, |handle| unsafe {
+            let mut buf = std::ptr::null_mut();
+            let mut len = 0;
+            let err = libe2fs_sys::ext2fs_xattr_get(handle, key.as_ptr(), &mut buf, &mut len);
+            if err == libe2fs_sys::EXT2_ET_EA_KEY_NOT_FOUND as i64 {
+                return 0;
+            }
+            if err == 0 {
+                // libe2fs allocates the value with malloc
+                value = Some(std::slice::from_raw_parts(buf as *const u8, len).to_vec());
+                libc::free(buf);
+            }
+            err
+        })?;
+        Ok(value)
+    }
+
+    /// Sets the xattr `key` of `path`, replacing any value it had. Values
+    /// that don't fit in the inode go in an xattr block.
+    pub fn set_xattr<P: Into<PathBuf>>(&self, path: P, key: &str, value: &[u8]) -> Result<()> {
+        self.set_xattr_by_inode(self.find_inode(path)?.0, key, value)
+    }
+
+    pub fn set_xattr_by_inode(&self, inode: u32, key: &str, value: &[u8]) -> Result<()> {
+        self.write_xattr(inode, key, value, 0)
+    }
+
+    /// Sets an xattr through a handle with `flags`, see [`Self::read_xattr`].
+    pub(crate) fn write_xattr(
+        &self,
+        inode: u32,
+        key: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let key = xattr_key(key)?;
+        self.with_xattrs(inode, flags, |handle| unsafe {
+            libe2fs_sys::ext2fs_xattr_set(
+                handle,
+                key.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+            )
+        })?;
+        self.stamp_inode(inode, ExtInodeTimes::CTIME)
+    }
+
+    /// Removes the xattr `key` from `path`. Removing one that isn't set
+    /// fails with `ENODATA`, like `removexattr(2)`.
+    pub fn remove_xattr<P: Into<PathBuf>>(&self, path: P, key: &str) -> Result<()> {
+        self.remove_xattr_by_inode(self.find_inode(path)?.0, key)
+    }
+
+    pub fn remove_xattr_by_inode(&self, inode: u32, key: &str) -> Result<()> {
+        self.check_writable()?;
+        if self.get_xattr_by_inode(inode, key)?.is_none() {
+            return Err(ExtError::ENODATA.into());
+        }
+        let key = xattr_key(key)?;
+        self.with_xattrs(inode, 0, |handle| unsafe {
+            libe2fs_sys::ext2fs_xattr_remove(handle, key.as_ptr())
+        })?;
+        self.stamp_inode(inode, ExtInodeTimes::CTIME)
+    }
+
+    fn with_xattrs(
+        &self,
+        inode: u32,
+        mut flags: u32,
+        f: impl FnOnce(*mut libe2fs_sys::ext2_xattr_handle) -> i64,
+    ) -> Result<()> {
+        let fs = *self.0.read().unwrap();
+        let mut handle = MaybeUninit::uninit();
+        let err = unsafe { libe2fs_sys::ext2fs_xattrs_open(fs, inode, handle.as_mut_ptr()) };
+        if err != 0 {
+            return report(err);
+        }
+        let mut handle = unsafe { handle.assume_init() };
+
+        let err = match flags {
+            0 => 0,
+            _ => unsafe {
+                libe2fs_sys::ext2fs_xattrs_flags(handle, &mut flags, std::ptr::null_mut())
+            },
+        };
+        let err = if err == 0 {
+            unsafe { libe2fs_sys::ext2fs_xattrs_read(handle) }
+        } else {
+            err
+        };
+        let err = if err == 0 { f(handle) } else { err };
+
+        // always close the handle, but report the first error we saw
+        let close_err = unsafe { libe2fs_sys::ext2fs_xattrs_close(&mut handle) };
+        if err != 0 {
+            report(err)
+        } else if close_err != 0 {
+            report(close_err)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Checks that `key` is in a namespace ext4 can store, like the kernel does.
+fn xattr_key(key: &str) -> Result<CString> {
+    match XATTR_NAMESPACES
+        .iter()
+        .find_map(|namespace| key.strip_prefix(namespace))
+    {
+        Some("") => Err(ExtError::EINVAL.into()),
+        Some(name) if name.len() > 255 => Err(ExtError::ERANGE.into()),
+        Some(_) => Ok(CString::new(key)?),
+        None => Err(ExtError::EOPNOTSUPP.into()),
+    }
+}
+
+unsafe extern "C" fn collect_xattr_key(
+    name: *mut c_char,
+    _value: *mut c_char,
+    _value_len: usize,
+    data: *mut c_void,
+) -> c_int {
+    let keys = &mut *(data as *mut Vec<String>);
+    keys.push(CStr::from_ptr(name).to_string_lossy().into_owned());
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext::readonly::ExtReadOnlyFilesystem;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    #[test]
+    pub fn test_xattrs_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let img = temp.path_view().join("xattrs.ext4");
+        {
+            let fs = ExtFilesystem::create(
+                &img,
+                16 * 1024 * 1024,
+                Some(ExtMkfsOptions::new().inode_size(256)),
+            )?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+            assert!(fs.list_xattrs("/test.txt")?.is_empty());
+            assert_eq!(None, fs.get_xattr("/test.txt", "user.comment")?);
+
+            fs.set_xattr("/test.txt", "user.comment", b"hi")?;
+            fs.set_xattr("/test.txt", "trusted.overlay.opaque", b"y")?;
+            fs.set_xattr(
+                "/test.txt",
+                "security.selinux",
+                b"system_u:object_r:etc_t:s0\0",
+            )?;
+            // too big for the inode, so it goes in an xattr block
+            let big = vec![0xf1u8; 2_000];
+            fs.set_xattr("/", "user.big", &big)?;
+
+            let mut keys = fs.list_xattrs("/test.txt")?;
+            keys.sort();
+            assert_eq!(
+                vec!["security.selinux", "trusted.overlay.opaque", "user.comment"],
+                keys
+            );
+            assert_eq!(
+                Some(b"hi".to_vec()),
+                fs.get_xattr("/test.txt", "user.comment")?
+            );
+            assert_eq!(Some(big), fs.get_xattr("/", "user.big")?);
+            assert_ne!(0, fs.root_inode()?.1.i_file_acl);
+
+            fs.set_xattr("/test.txt", "user.comment", b"replaced")?;
+            assert_eq!(
+                Some(b"replaced".to_vec()),
+                fs.get_xattr("/test.txt", "user.comment")?
+            );
+
+            fs.remove_xattr("/test.txt", "user.comment")?;
+            assert_eq!(None, fs.get_xattr("/test.txt", "user.comment")?);
+            let err = fs.remove_xattr("/test.txt", "user.comment").unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ExtError>(),
+                Some(ExtError::ENODATA)
+            ));
+
+            for key in ["nonsense.key", "user.", "comment"] {
+                assert!(fs.set_xattr("/test.txt", key, b"nope").is_err());
+            }
+        }
+        assert_fsck_clean(&img)?;
+
+        let fs = ExtReadOnlyFilesystem::open(&img)?;
+        assert_eq!(
+            Some(b"y".to_vec()),
+            fs.get_xattr("/test.txt", "trusted.overlay.opaque")?
+        );
+
+        Ok(())
+    }
+}
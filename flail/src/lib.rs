@@ -1 +1,3 @@
 pub mod ext;
+pub mod partition;
+pub mod undo;
@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::*;
+
+pub(crate) const ENTRY_COUNT: u32 = 128;
+pub(crate) const LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_SIZE: u32 = 128;
+const MAX_ENTRY_SIZE: u32 = 4096;
+const ENTRY_SECTORS: u64 = (ENTRY_COUNT * ENTRY_SIZE) as u64 / SECTOR_SIZE;
+const LABEL_UNITS: usize = 36;
+
+/// The first and last sectors partitions may use, inclusive, leaving room
+/// for the primary table at the start of the disk and the backup at the end.
+pub(crate) fn usable_lbas(sectors: u64) -> (u64, u64) {
+    (2 + ENTRY_SECTORS, sectors.saturating_sub(2 + ENTRY_SECTORS))
+}
+
+pub(crate) fn check_label(label: &str) -> Result<()> {
+    let units = label.encode_utf16().count();
+    if units > LABEL_UNITS {
+        return Err(eyre!(
+            "partition label {label:?} is {units} UTF-16 units long, the limit is {LABEL_UNITS}"
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the disk GUID and partitions, from the backup table if the primary
+/// one is damaged.
+pub(crate) fn read(path: &Path, sectors: u64) -> Result<(Uuid, Vec<Partition>)> {
+    let file = File::open(path)?;
+    match read_table(&file, 1, sectors) {
+        Ok(table) => Ok(table),
+        Err(err) => {
+            warn!("primary GPT of {path:?} is damaged ({err}), trying the backup");
+            let backup_lba = sectors
+                .checked_sub(1)
+                .ok_or_else(|| eyre!("{path:?} is empty, there's no backup GPT"))?;
+            read_table(&file, backup_lba, sectors)
+        }
+    }
+}
+
+fn read_table(file: &File, header_lba: u64, sectors: u64) -> Result<(Uuid, Vec<Partition>)> {
+    let mut header = [0; SECTOR_SIZE as usize];
+    file.read_exact_at(&mut header, header_lba * SECTOR_SIZE)?;
+    if &header[..8] != SIGNATURE {
+        return Err(eyre!("no GPT header at sector {header_lba}"));
+    }
+
+    let header_size = LittleEndian::read_u32(&header[12..16]) as usize;
+    if !(HEADER_SIZE as usize..=SECTOR_SIZE as usize).contains(&header_size) {
+        return Err(eyre!("GPT header has a bad size of {header_size}"));
+    }
+    let header_crc = LittleEndian::read_u32(&header[16..20]);
+    let mut unchecked = header[..header_size].to_vec();
+    unchecked[16..20].fill(0);
+    if crc32(&unchecked) != header_crc {
+        return Err(eyre!(
+            "GPT header at sector {header_lba} has a bad checksum"
+        ));
+    }
+
+    let first_usable = LittleEndian::read_u64(&header[40..48]);
+    let last_usable = LittleEndian::read_u64(&header[48..56]);
+    if first_usable > last_usable || last_usable >= sectors {
+        return Err(eyre!(
+            "GPT usable sectors {first_usable}..={last_usable} don't fit a disk of {sectors} sectors"
+        ));
+    }
+    let disk_guid = Uuid::from_bytes_le(header[56..72].try_into()?);
+    let entries_lba = LittleEndian::read_u64(&header[72..80]);
+    let entry_count = LittleEndian::read_u32(&header[80..84]);
+    let entry_size = LittleEndian::read_u32(&header[84..88]);
+    let entries_crc = LittleEndian::read_u32(&header[88..92]);
+    if entry_size < ENTRY_SIZE
+        || entry_size > MAX_ENTRY_SIZE
+        || entry_size % ENTRY_SIZE != 0
+        || entry_count > ENTRY_COUNT
+    {
+        return Err(eyre!(
+            "unsupported GPT layout of {entry_count} entries of {entry_size} bytes"
+        ));
+    }
+
+    let entries_len = (entry_count as u64)
+        .checked_mul(entry_size as u64)
+        .ok_or_else(|| eyre!("GPT entries at sector {entries_lba} are too large"))?;
+    let entries_offset = entries_lba
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(|| eyre!("GPT entries at sector {entries_lba} are past the end of the disk"))?;
+    let mut entries = vec![0; entries_len as usize];
+    file.read_exact_at(&mut entries, entries_offset)?;
+    if crc32(&entries) != entries_crc {
+        return Err(eyre!(
+            "GPT entries at sector {entries_lba} have a bad checksum"
+        ));
+    }
+
+    let mut partitions = vec![];
+    for (i, entry) in entries.chunks_exact(entry_size as usize).enumerate() {
+        let type_guid = Uuid::from_bytes_le(entry[..16].try_into()?);
+        if type_guid.is_nil() {
+            continue;
+        }
+
+        let label: Vec<u16> = entry[56..56 + LABEL_UNITS * 2]
+            .chunks_exact(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|unit| *unit != 0)
+            .collect();
+        let first_lba = LittleEndian::read_u64(&entry[32..40]);
+        let last_lba = LittleEndian::read_u64(&entry[40..48]);
+        if first_lba > last_lba || first_lba < first_usable || last_lba > last_usable {
+            return Err(eyre!(
+                "GPT partition {} at sectors {first_lba}..={last_lba} is outside the usable sectors {first_usable}..={last_usable}",
+                i + 1
+            ));
+        }
+        let attributes = LittleEndian::read_u64(&entry[48..56]);
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            partition_type: PartitionType::Gpt(type_guid),
+            guid: Some(Uuid::from_bytes_le(entry[16..32].try_into()?)),
+            label: Some(String::from_utf16_lossy(&label)),
+            first_lba,
+            last_lba,
+            bootable: attributes & LEGACY_BIOS_BOOTABLE != 0,
+            attributes,
+        });
+    }
+
+    Ok((disk_guid, partitions))
+}
+
+/// Writes the protective MBR and both copies of the table.
+pub(crate) fn write(file: &File, table: &PartitionTable) -> Result<()> {
+    let mut entries = vec![0; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+    for partition in &table.partitions {
+        let PartitionType::Gpt(type_guid) = partition.partition_type else {
+            return Err(eyre!(
+                "partition {} isn't a GPT partition",
+                partition.number
+            ));
+        };
+        let attributes = if partition.bootable {
+            partition.attributes | LEGACY_BIOS_BOOTABLE
+        } else {
+            partition.attributes & !LEGACY_BIOS_BOOTABLE
+        };
+
+        let offset = (partition.number - 1) as usize * ENTRY_SIZE as usize;
+        let entry = &mut entries[offset..offset + ENTRY_SIZE as usize];
+        entry[..16].copy_from_slice(&type_guid.to_bytes_le());
+        entry[16..32].copy_from_slice(&partition.guid.unwrap_or_else(Uuid::new_v4).to_bytes_le());
+        LittleEndian::write_u64(&mut entry[32..40], partition.first_lba);
+        LittleEndian::write_u64(&mut entry[40..48], partition.last_lba);
+        LittleEndian::write_u64(&mut entry[48..56], attributes);
+        if let Some(label) = &partition.label {
+            check_label(label)?;
+            for (i, unit) in label.encode_utf16().enumerate() {
+                LittleEndian::write_u16(&mut entry[56 + i * 2..58 + i * 2], unit);
+            }
+        }
+    }
+    let entries_crc = crc32(&entries);
+
+    let too_small = || eyre!("a disk of {} sectors is too small for a GPT", table.sectors);
+    let last_lba = table.sectors.checked_sub(1).ok_or_else(too_small)?;
+    let backup_entries_lba = last_lba
+        .checked_sub(ENTRY_SECTORS)
+        .filter(|lba| *lba >= 2 + ENTRY_SECTORS)
+        .ok_or_else(too_small)?;
+    let primary = header(table, 1, last_lba, 2, entries_crc);
+    let backup = header(table, last_lba, 1, backup_entries_lba, entries_crc);
+
+    mbr::write_protective(file, table)?;
+    file.write_all_at(&primary, SECTOR_SIZE)?;
+    file.write_all_at(&entries, 2 * SECTOR_SIZE)?;
+    file.write_all_at(&entries, backup_entries_lba * SECTOR_SIZE)?;
+    file.write_all_at(&backup, last_lba * SECTOR_SIZE)?;
+    Ok(())
+}
+
+fn header(
+    table: &PartitionTable,
+    current_lba: u64,
+    backup_lba: u64,
+    entries_lba: u64,
+    entries_crc: u32,
+) -> [u8; SECTOR_SIZE as usize] {
+    let (first_usable, last_usable) = usable_lbas(table.sectors);
+
+    let mut header = [0; SECTOR_SIZE as usize];
+    header[..8].copy_from_slice(SIGNATURE);
+    LittleEndian::write_u32(&mut header[8..12], REVISION);
+    LittleEndian::write_u32(&mut header[12..16], HEADER_SIZE);
+    LittleEndian::write_u64(&mut header[24..32], current_lba);
+    LittleEndian::write_u64(&mut header[32..40], backup_lba);
+    LittleEndian::write_u64(&mut header[40..48], first_usable);
+    LittleEndian::write_u64(&mut header[48..56], last_usable);
+    header[56..72].copy_from_slice(&table.disk_guid.to_bytes_le());
+    LittleEndian::write_u64(&mut header[72..80], entries_lba);
+    LittleEndian::write_u32(&mut header[80..84], ENTRY_COUNT);
+    LittleEndian::write_u32(&mut header[84..88], ENTRY_SIZE);
+    LittleEndian::write_u32(&mut header[88..92], entries_crc);
+    // the checksum covers the header with its own checksum zeroed
+    let header_crc = crc32(&header[..HEADER_SIZE as usize]);
+    LittleEndian::write_u32(&mut header[16..20], header_crc);
+    header
+}
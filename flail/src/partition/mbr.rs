@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::*;
+
+pub(crate) const BOOT_CODE_LEN: usize = 440;
+pub(crate) const ENTRY_COUNT: usize = 4;
+pub(crate) const PROTECTIVE_TYPE: u8 = 0xee;
+
+const SIGNATURE_OFFSET: usize = 440;
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const ACTIVE: u8 = 0x80;
+
+pub(crate) fn read_sector0(path: &Path) -> Result<[u8; SECTOR_SIZE as usize]> {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    File::open(path)?.read_exact_at(&mut sector, 0)?;
+    Ok(sector)
+}
+
+/// Splits the first sector into its boot code, disk signature and primary
+/// partitions, which must fit in a disk of `disk_sectors` sectors.
+pub(crate) fn parse(
+    sector: &[u8; SECTOR_SIZE as usize],
+    disk_sectors: u64,
+) -> Result<(Vec<u8>, u32, Vec<Partition>)> {
+    if sector[510..] != BOOT_SIGNATURE {
+        return Err(eyre!(
+            "no partition table found, the boot signature is missing"
+        ));
+    }
+
+    let mut partitions = vec![];
+    for i in 0..ENTRY_COUNT {
+        let entry = &sector[ENTRIES_OFFSET + i * ENTRY_SIZE..ENTRIES_OFFSET + (i + 1) * ENTRY_SIZE];
+        let partition_type = entry[4];
+        let first_lba = LittleEndian::read_u32(&entry[8..12]) as u64;
+        let sectors = LittleEndian::read_u32(&entry[12..16]) as u64;
+        if partition_type == 0 || sectors == 0 {
+            continue;
+        }
+        let last_lba = first_lba + sectors - 1;
+        // protective entries often claim the most sectors they can, whatever
+        // the size of the disk
+        if partition_type != PROTECTIVE_TYPE && last_lba >= disk_sectors {
+            return Err(eyre!(
+                "MBR partition {} at sectors {first_lba}..={last_lba} runs past the end of the disk at sector {disk_sectors}",
+                i + 1
+            ));
+        }
+
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            partition_type: PartitionType::Mbr(partition_type),
+            guid: None,
+            label: None,
+            first_lba,
+            last_lba,
+            bootable: entry[0] & ACTIVE != 0,
+            attributes: 0,
+        });
+    }
+
+    Ok((
+        sector[..BOOT_CODE_LEN].to_vec(),
+        LittleEndian::read_u32(&sector[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 4]),
+        partitions,
+    ))
+}
+
+pub(crate) fn write(file: &File, table: &PartitionTable) -> Result<()> {
+    let mut sector = header(table);
+    for partition in &table.partitions {
+        let PartitionType::Mbr(partition_type) = partition.partition_type else {
+            return Err(eyre!(
+                "partition {} isn't an MBR partition",
+                partition.number
+            ));
+        };
+        write_entry(
+            &mut sector,
+            partition.number as usize - 1,
+            partition.bootable,
+            partition_type,
+            partition.first_lba,
+            partition.last_lba,
+        );
+    }
+
+    file.write_all_at(&sector, 0)?;
+    Ok(())
+}
+
+/// The MBR in front of a GPT, which covers the whole disk with a single
+/// partition so that tools that don't know GPT leave the disk alone.
+pub(crate) fn write_protective(file: &File, table: &PartitionTable) -> Result<()> {
+    let mut sector = header(table);
+    let last_lba = table.sectors.saturating_sub(1).min(u32::MAX as u64);
+    write_entry(&mut sector, 0, false, PROTECTIVE_TYPE, 1, last_lba);
+
+    file.write_all_at(&sector, 0)?;
+    Ok(())
+}
+
+fn header(table: &PartitionTable) -> [u8; SECTOR_SIZE as usize] {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    sector[..BOOT_CODE_LEN].copy_from_slice(&table.boot_code[..BOOT_CODE_LEN]);
+    LittleEndian::write_u32(
+        &mut sector[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 4],
+        table.disk_signature,
+    );
+    sector[510..].copy_from_slice(&BOOT_SIGNATURE);
+    sector
+}
+
+fn write_entry(
+    sector: &mut [u8; SECTOR_SIZE as usize],
+    index: usize,
+    bootable: bool,
+    partition_type: u8,
+    first_lba: u64,
+    last_lba: u64,
+) {
+    let entry =
+        &mut sector[ENTRIES_OFFSET + index * ENTRY_SIZE..ENTRIES_OFFSET + (index + 1) * ENTRY_SIZE];
+    entry[0] = if bootable { ACTIVE } else { 0 };
+    entry[1..4].copy_from_slice(&chs(first_lba));
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&chs(last_lba));
+    LittleEndian::write_u32(&mut entry[8..12], first_lba as u32);
+    LittleEndian::write_u32(&mut entry[12..16], (last_lba - first_lba + 1) as u32);
+}
+
+/// The cylinder/head/sector address of a sector, assuming the usual 255
+/// heads and 63 sectors per track. Sectors past what CHS can address get the
+/// maximum, which tells readers to use the LBA fields instead.
+fn chs(lba: u64) -> [u8; 3] {
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) as u8 & 0xc0),
+        cylinder as u8,
+    ]
+}
@@ -0,0 +1,775 @@
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use log::*;
+use uuid::Uuid;
+
+use crate::ext::facade::ExtFacadeFloppyDisk;
+use crate::ext::mkfs::ExtMkfsOptions;
+use crate::ext::open::ExtOpenOptions;
+use crate::ext::{ExtFilesystem, ExtFilesystemOpenFlags};
+
+mod gpt;
+mod mbr;
+
+/// Partition tables address disks in 512 byte sectors. Images with 4k
+/// logical sectors aren't supported.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// New partitions start on 1MiB boundaries, like fdisk and parted do.
+const ALIGNMENT: u64 = 1024 * 1024 / SECTOR_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionTableKind {
+    /// A DOS partition table, holding up to four primary partitions.
+    Mbr,
+    /// A GUID partition table, holding up to 128 partitions.
+    Gpt,
+}
+
+/// What a partition is for. MBR tables identify this with a single byte,
+/// GPT tables with a GUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Uuid),
+}
+
+impl PartitionType {
+    pub const MBR_FAT32_LBA: Self = Self::Mbr(0x0c);
+    pub const MBR_LINUX_SWAP: Self = Self::Mbr(0x82);
+    pub const MBR_LINUX: Self = Self::Mbr(0x83);
+    pub const MBR_EFI_SYSTEM: Self = Self::Mbr(0xef);
+
+    pub const GPT_EFI_SYSTEM: Self =
+        Self::Gpt(Uuid::from_u128(0xc12a7328_f81f_11d2_ba4b_00a0c93ec93b));
+    pub const GPT_BIOS_BOOT: Self =
+        Self::Gpt(Uuid::from_u128(0x21686148_6449_6e6f_744e_656564454649));
+    pub const GPT_LINUX_FILESYSTEM: Self =
+        Self::Gpt(Uuid::from_u128(0x0fc63daf_8483_4772_8e79_3d69d8477de4));
+    pub const GPT_LINUX_SWAP: Self =
+        Self::Gpt(Uuid::from_u128(0x0657fd6d_a4ab_43c4_84e5_0933c84b4f4f));
+
+    fn kind(&self) -> PartitionTableKind {
+        match self {
+            Self::Mbr(_) => PartitionTableKind::Mbr,
+            Self::Gpt(_) => PartitionTableKind::Gpt,
+        }
+    }
+}
+
+/// An entry in a [`PartitionTable`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub(crate) number: u32,
+    pub(crate) partition_type: PartitionType,
+    pub(crate) guid: Option<Uuid>,
+    pub(crate) label: Option<String>,
+    pub(crate) first_lba: u64,
+    pub(crate) last_lba: u64,
+    pub(crate) bootable: bool,
+    pub(crate) attributes: u64,
+}
+
+impl Partition {
+    /// The 1-based number of the partition, as in `/dev/sda1`.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        self.partition_type
+    }
+
+    /// The unique GUID of the partition. Only GPT partitions have one.
+    pub fn guid(&self) -> Option<Uuid> {
+        self.guid
+    }
+
+    /// The name of the partition. Only GPT partitions have one.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    /// The last sector of the partition, inclusive.
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
+    }
+
+    /// Where the partition starts, in bytes from the start of the disk.
+    pub fn start(&self) -> u64 {
+        self.first_lba * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        (self.last_lba - self.first_lba + 1) * SECTOR_SIZE
+    }
+
+    /// Whether the partition is marked active in an MBR table, or legacy
+    /// BIOS bootable in a GPT one.
+    pub fn bootable(&self) -> bool {
+        self.bootable
+    }
+
+    /// The raw GPT attribute bits, always 0 for MBR partitions.
+    pub fn attributes(&self) -> u64 {
+        self.attributes
+    }
+}
+
+/// Describes a new partition for [`PartitionTable::add`].
+#[derive(Clone, Debug)]
+pub struct PartitionOptions {
+    pub(crate) partition_type: PartitionType,
+    pub(crate) start: Option<u64>,
+    pub(crate) size: Option<u64>,
+    pub(crate) label: Option<String>,
+    pub(crate) guid: Option<Uuid>,
+    pub(crate) bootable: bool,
+}
+
+impl PartitionOptions {
+    pub fn new(partition_type: PartitionType) -> Self {
+        Self {
+            partition_type,
+            start: None,
+            size: None,
+            label: None,
+            guid: None,
+            bootable: false,
+        }
+    }
+
+    /// Where the partition starts, in bytes. Must be a multiple of
+    /// [`SECTOR_SIZE`]. Without a start, the partition goes in the first
+    /// 1MiB aligned gap that it fits in.
+    pub fn start(mut self, start: u64) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    /// The size of the partition in bytes, rounded up to whole sectors.
+    /// Without a size, the partition fills the gap it's put in.
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// The name of a GPT partition, at most 36 UTF-16 code units.
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// The unique GUID of a GPT partition. Defaults to a random one.
+    pub fn guid(mut self, guid: Uuid) -> Self {
+        self.guid = Some(guid);
+        self
+    }
+
+    pub fn bootable(mut self, bootable: bool) -> Self {
+        self.bootable = bootable;
+        self
+    }
+}
+
+/// An MBR or GPT partition table on a disk image.
+///
+/// Changes made with [`Self::add`], [`Self::resize`] and [`Self::delete`]
+/// only happen in memory until [`Self::write`] is called. Only primary
+/// partitions of MBR tables are supported, extended partitions are listed
+/// but not followed.
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    pub(crate) path: PathBuf,
+    pub(crate) kind: PartitionTableKind,
+    pub(crate) sectors: u64,
+    pub(crate) disk_guid: Uuid,
+    pub(crate) disk_signature: u32,
+    pub(crate) boot_code: Vec<u8>,
+    pub(crate) partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Writes a new, empty partition table to an existing image, replacing
+    /// whatever table it had. The boot code in the first sector is kept.
+    pub fn create<P: Into<PathBuf>>(path: P, kind: PartitionTableKind) -> Result<Self> {
+        let path = path.into();
+        debug!("creating {kind:?} partition table on {path:?}");
+        let sectors = Self::disk_sectors(&path)?;
+        let boot_code = match mbr::read_sector0(&path) {
+            Ok(sector) => sector[..mbr::BOOT_CODE_LEN].to_vec(),
+            Err(_) => vec![0; mbr::BOOT_CODE_LEN],
+        };
+
+        let out = Self {
+            path,
+            kind,
+            sectors,
+            disk_guid: match kind {
+                PartitionTableKind::Mbr => Uuid::nil(),
+                PartitionTableKind::Gpt => Uuid::new_v4(),
+            },
+            disk_signature: rand::random(),
+            boot_code,
+            partitions: vec![],
+        };
+        let (first_usable, last_usable) = out.usable_lbas();
+        if first_usable + ALIGNMENT > last_usable {
+            return Err(eyre!(
+                "disk of {} bytes is too small for a partition table",
+                sectors * SECTOR_SIZE
+            ));
+        }
+
+        out.write()?;
+        Ok(out)
+    }
+
+    /// Reads the partition table of an image. GPT is used if the MBR is a
+    /// protective one, falling back to the backup GPT header if the primary
+    /// one is damaged.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let sectors = Self::disk_sectors(&path)?;
+        let sector = mbr::read_sector0(&path)?;
+        let (boot_code, disk_signature, partitions) = mbr::parse(&sector, sectors)?;
+
+        let mut out = Self {
+            path,
+            kind: PartitionTableKind::Mbr,
+            sectors,
+            disk_guid: Uuid::nil(),
+            disk_signature,
+            boot_code,
+            partitions,
+        };
+
+        if out
+            .partitions
+            .iter()
+            .any(|p| p.partition_type == PartitionType::Mbr(mbr::PROTECTIVE_TYPE))
+        {
+            let (disk_guid, partitions) = gpt::read(&out.path, sectors)?;
+            out.kind = PartitionTableKind::Gpt;
+            out.disk_guid = disk_guid;
+            out.partitions = partitions;
+        }
+
+        debug!(
+            "read {:?} partition table with {} partitions from {:?}",
+            out.kind,
+            out.partitions.len(),
+            out.path
+        );
+        Ok(out)
+    }
+
+    pub fn kind(&self) -> PartitionTableKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The size of the disk in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+
+    /// The GUID of a GPT disk, nil for MBR ones.
+    pub fn disk_guid(&self) -> Uuid {
+        self.disk_guid
+    }
+
+    /// The partitions in the table, ordered by number.
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    pub fn partition(&self, number: u32) -> Result<&Partition> {
+        self.partitions
+            .iter()
+            .find(|p| p.number == number)
+            .ok_or_else(|| eyre!("no partition {number} in {:?}", self.path))
+    }
+
+    /// Adds a partition, returning its number. This is the lowest number
+    /// that isn't taken yet.
+    pub fn add(&mut self, options: PartitionOptions) -> Result<u32> {
+        if options.partition_type.kind() != self.kind {
+            return Err(eyre!(
+                "can't add a {:?} partition to a {:?} table",
+                options.partition_type,
+                self.kind
+            ));
+        }
+        if self.kind == PartitionTableKind::Mbr
+            && (options.label.is_some() || options.guid.is_some())
+        {
+            return Err(eyre!("MBR partitions can't have labels or GUIDs"));
+        }
+        if let Some(label) = &options.label {
+            gpt::check_label(label)?;
+        }
+
+        let number = (1..=self.max_partitions())
+            .find(|n| !self.partitions.iter().any(|p| p.number == *n))
+            .ok_or_else(|| eyre!("no free partition slots left in {:?}", self.path))?;
+
+        let sectors = options.size.map(|size| size.div_ceil(SECTOR_SIZE));
+        if sectors == Some(0) {
+            return Err(eyre!("partitions can't be empty"));
+        }
+
+        let (first_lba, last_lba) = match options.start {
+            Some(start) => {
+                if start % SECTOR_SIZE != 0 {
+                    return Err(eyre!("partition start {start} isn't sector aligned"));
+                }
+                let first_lba = start / SECTOR_SIZE;
+                let (_, gap_end) = self
+                    .gaps()
+                    .into_iter()
+                    .find(|(first, last)| (*first..=*last).contains(&first_lba))
+                    .ok_or_else(|| eyre!("sector {first_lba} is already in use"))?;
+                match sectors {
+                    Some(sectors) => (first_lba, first_lba + sectors - 1),
+                    None => (first_lba, gap_end),
+                }
+            }
+            None => self
+                .gaps()
+                .into_iter()
+                .find_map(|(gap_first, gap_last)| {
+                    let first = gap_first.next_multiple_of(ALIGNMENT);
+                    let last = match sectors {
+                        Some(sectors) => first + sectors - 1,
+                        None => gap_last,
+                    };
+                    (first <= last && last <= gap_last).then_some((first, last))
+                })
+                .ok_or_else(|| eyre!("no free space for the partition in {:?}", self.path))?,
+        };
+        self.check_range(first_lba, last_lba, None)?;
+
+        let guid = match self.kind {
+            PartitionTableKind::Mbr => None,
+            PartitionTableKind::Gpt => Some(options.guid.unwrap_or_else(Uuid::new_v4)),
+        };
+
+        debug!("adding partition {number} at sectors {first_lba}..={last_lba}");
+        self.partitions.push(Partition {
+            number,
+            partition_type: options.partition_type,
+            guid,
+            label: options.label,
+            first_lba,
+            last_lba,
+            bootable: options.bootable,
+            attributes: if self.kind == PartitionTableKind::Gpt && options.bootable {
+                gpt::LEGACY_BIOS_BOOTABLE
+            } else {
+                0
+            },
+        });
+        self.partitions.sort_by_key(|p| p.number);
+
+        Ok(number)
+    }
+
+    /// Changes the size of a partition, keeping its start. The filesystem
+    /// inside isn't touched, so shrink it before shrinking the partition and
+    /// grow it after growing the partition.
+    pub fn resize(&mut self, number: u32, new_size: u64) -> Result<()> {
+        let sectors = new_size.div_ceil(SECTOR_SIZE);
+        if sectors == 0 {
+            return Err(eyre!("partitions can't be empty"));
+        }
+
+        let first_lba = self.partition(number)?.first_lba;
+        let last_lba = first_lba + sectors - 1;
+        self.check_range(first_lba, last_lba, Some(number))?;
+
+        debug!("resizing partition {number} to sectors {first_lba}..={last_lba}");
+        let partition = self
+            .partitions
+            .iter_mut()
+            .find(|p| p.number == number)
+            .unwrap();
+        partition.last_lba = last_lba;
+        Ok(())
+    }
+
+    /// Removes a partition. Its data stays on disk.
+    pub fn delete(&mut self, number: u32) -> Result<()> {
+        self.partition(number)?;
+        debug!("deleting partition {number}");
+        self.partitions.retain(|p| p.number != number);
+        Ok(())
+    }
+
+    /// Writes the table to the image. For GPT this writes the protective
+    /// MBR and both the primary and backup tables, moving the backup to the
+    /// end of the image if it has grown since the table was read.
+    pub fn write(&self) -> Result<()> {
+        let sectors = Self::disk_sectors(&self.path)?;
+        let table = Self {
+            sectors,
+            ..self.clone()
+        };
+        for partition in &table.partitions {
+            table.check_range(
+                partition.first_lba,
+                partition.last_lba,
+                Some(partition.number),
+            )?;
+        }
+
+        debug!("writing {:?} partition table to {:?}", self.kind, self.path);
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        match self.kind {
+            PartitionTableKind::Mbr => mbr::write(&file, &table)?,
+            PartitionTableKind::Gpt => gpt::write(&file, &table)?,
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// The [`ExtOpenOptions`] that confine a filesystem to a partition.
+    pub fn open_options(&self, number: u32) -> Result<ExtOpenOptions> {
+        let partition = self.partition(number)?;
+        Ok(ExtOpenOptions::new()
+            .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW)
+            .offset(partition.start())
+            .length(partition.size()))
+    }
+
+    /// Formats a partition, filling all of it.
+    pub fn create_ext(
+        &self,
+        number: u32,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<ExtFilesystem> {
+        let partition = self.partition(number)?;
+        ExtFilesystem::create_at(&self.path, partition.start(), partition.size(), options)
+    }
+
+    /// Opens the filesystem in a partition for reading and writing.
+    pub fn open_ext(&self, number: u32) -> Result<ExtFilesystem> {
+        ExtFilesystem::open_with_options(&self.path, self.open_options(number)?)
+    }
+
+    /// Formats a partition, filling all of it, see [`Self::create_ext`].
+    pub fn create_facade(
+        &self,
+        number: u32,
+        options: Option<ExtMkfsOptions>,
+    ) -> Result<ExtFacadeFloppyDisk> {
+        let partition = self.partition(number)?;
+        Ok(ExtFacadeFloppyDisk::create_at(
+            &self.path,
+            partition.start(),
+            partition.size(),
+            options,
+        )?)
+    }
+
+    /// Opens the filesystem in a partition for reading and writing, see
+    /// [`Self::open_ext`].
+    pub fn open_facade(&self, number: u32) -> Result<ExtFacadeFloppyDisk> {
+        Ok(ExtFacadeFloppyDisk::new_with_options(
+            &self.path,
+            self.open_options(number)?,
+        )?)
+    }
+
+    fn disk_sectors(path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(path)?.len() / SECTOR_SIZE)
+    }
+
+    fn max_partitions(&self) -> u32 {
+        match self.kind {
+            PartitionTableKind::Mbr => mbr::ENTRY_COUNT as u32,
+            PartitionTableKind::Gpt => gpt::ENTRY_COUNT,
+        }
+    }
+
+    /// The first and last sectors partitions may use, inclusive.
+    fn usable_lbas(&self) -> (u64, u64) {
+        match self.kind {
+            PartitionTableKind::Mbr => (1, self.sectors.saturating_sub(1)),
+            PartitionTableKind::Gpt => gpt::usable_lbas(self.sectors),
+        }
+    }
+
+    /// The unused ranges of sectors, inclusive.
+    fn gaps(&self) -> Vec<(u64, u64)> {
+        let (first_usable, last_usable) = self.usable_lbas();
+        let mut used: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|p| (p.first_lba, p.last_lba))
+            .collect();
+        used.sort();
+
+        let mut gaps = vec![];
+        let mut next = first_usable;
+        for (first, last) in used {
+            if first > next {
+                gaps.push((next, first - 1));
+            }
+            next = next.max(last + 1);
+        }
+        if next <= last_usable {
+            gaps.push((next, last_usable));
+        }
+        gaps
+    }
+
+    /// Checks that sectors `first..=last` are usable and don't overlap any
+    /// partition other than `except`.
+    fn check_range(&self, first: u64, last: u64, except: Option<u32>) -> Result<()> {
+        let (first_usable, last_usable) = self.usable_lbas();
+        if first < first_usable || last > last_usable || first > last {
+            return Err(eyre!(
+                "sectors {first}..={last} are outside of the usable {first_usable}..={last_usable}"
+            ));
+        }
+        if self.kind == PartitionTableKind::Mbr
+            && (first > u32::MAX as u64 || last - first + 1 > u32::MAX as u64)
+        {
+            return Err(eyre!("MBR partitions must be within the first 2TiB"));
+        }
+        if let Some(other) = self
+            .partitions
+            .iter()
+            .filter(|p| Some(p.number) != except)
+            .find(|p| first <= p.last_lba && p.first_lba <= last)
+        {
+            return Err(eyre!(
+                "sectors {first}..={last} overlap partition {}",
+                other.number
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The CRC-32 used by GPT, which is the same one as zlib's.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ext::tests::{assert_fsck_clean, TempDir};
+
+    fn blank_disk(temp: &TempDir, size: u64) -> Result<PathBuf> {
+        let path = temp.path_view().join("disk.img");
+        fs::File::create(&path)?.set_len(size)?;
+        Ok(path)
+    }
+
+    #[test]
+    pub fn test_gpt_tables_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let disk = blank_disk(&temp, 64 * 1024 * 1024)?;
+
+        let mut table = PartitionTable::create(&disk, PartitionTableKind::Gpt)?;
+        let esp = table.add(
+            PartitionOptions::new(PartitionType::GPT_EFI_SYSTEM)
+                .size(8 * 1024 * 1024)
+                .label("EFI System")
+                .bootable(true),
+        )?;
+        let root =
+            table.add(PartitionOptions::new(PartitionType::GPT_LINUX_FILESYSTEM).label("root"))?;
+        table.write()?;
+        assert_eq!((1, 2), (esp, root));
+
+        {
+            let fs = table.create_ext(root, None)?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+
+        let table = PartitionTable::open(&disk)?;
+        assert_eq!(PartitionTableKind::Gpt, table.kind());
+        assert_eq!(2, table.partitions().len());
+        let esp = table.partition(1)?;
+        assert_eq!(PartitionType::GPT_EFI_SYSTEM, esp.partition_type());
+        assert_eq!(Some("EFI System"), esp.label());
+        assert_eq!(1024 * 1024, esp.start());
+        assert_eq!(8 * 1024 * 1024, esp.size());
+        assert!(esp.bootable());
+        let root = table.partition(2)?;
+        assert_eq!(Some("root"), root.label());
+        assert_eq!(9 * 1024 * 1024, root.start());
+        assert_eq!(table.disk_size() / SECTOR_SIZE - 34, root.last_lba());
+
+        let fs = table.open_ext(2)?;
+        let file = fs.open_file(fs.find_inode("/test.txt")?.0, None)?;
+        let mut buf = [0u8; 11];
+        fs.read_file(&file, &mut buf)?;
+        assert_eq!(b"hello flail", &buf);
+        drop(fs);
+
+        let image = fs::read(&disk)?;
+        let part = temp.path_view().join("part.img");
+        fs::write(
+            &part,
+            &image[root.start() as usize..(root.start() + root.size()) as usize],
+        )?;
+        assert_fsck_clean(&part)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_damaged_gpt_falls_back_to_the_backup() -> Result<()> {
+        let temp = TempDir::new()?;
+        let disk = blank_disk(&temp, 16 * 1024 * 1024)?;
+
+        let mut table = PartitionTable::create(&disk, PartitionTableKind::Gpt)?;
+        table.add(PartitionOptions::new(PartitionType::GPT_LINUX_FILESYSTEM).label("data"))?;
+        table.write()?;
+
+        let file = OpenOptions::new().write(true).open(&disk)?;
+        std::os::unix::fs::FileExt::write_all_at(&file, &[0; SECTOR_SIZE as usize], SECTOR_SIZE)?;
+
+        let reread = PartitionTable::open(&disk)?;
+        assert_eq!(table.disk_guid(), reread.disk_guid());
+        assert_eq!(table.partitions(), reread.partitions());
+
+        Ok(())
+    }
+
+    /// Rewrites the primary GPT header and entries, fixing up both checksums.
+    fn patch_primary_gpt(disk: &Path, patch: impl FnOnce(&mut [u8], &mut [u8])) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let file = OpenOptions::new().read(true).write(true).open(disk)?;
+        let mut header = [0; SECTOR_SIZE as usize];
+        file.read_exact_at(&mut header, SECTOR_SIZE)?;
+        let mut entries = vec![0; 128 * 128];
+        file.read_exact_at(&mut entries, 2 * SECTOR_SIZE)?;
+
+        patch(&mut header, &mut entries);
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        header[16..20].fill(0);
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        file.write_all_at(&header, SECTOR_SIZE)?;
+        file.write_all_at(&entries, 2 * SECTOR_SIZE)?;
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_bogus_gpt_tables_are_rejected() -> Result<()> {
+        let temp = TempDir::new()?;
+        let disk = blank_disk(&temp, 16 * 1024 * 1024)?;
+
+        let mut table = PartitionTable::create(&disk, PartitionTableKind::Gpt)?;
+        table.add(PartitionOptions::new(PartitionType::GPT_LINUX_FILESYSTEM).label("data"))?;
+        table.write()?;
+
+        // each of these makes the primary table unusable, so the backup wins
+        let patches: [fn(&mut [u8], &mut [u8]); 4] = [
+            // a partition running past the end of the disk
+            |_, entries| entries[40..48].copy_from_slice(&u64::MAX.to_le_bytes()),
+            // a partition that ends before it starts
+            |_, entries| entries[32..40].copy_from_slice(&(1u64 << 20).to_le_bytes()),
+            // entries too large to allocate
+            |header, _| header[84..88].copy_from_slice(&0x8000_0000u32.to_le_bytes()),
+            // entries that aren't a multiple of 128 bytes
+            |header, _| header[84..88].copy_from_slice(&129u32.to_le_bytes()),
+        ];
+        for patch in patches {
+            table.write()?;
+            patch_primary_gpt(&disk, patch)?;
+            let reread = PartitionTable::open(&disk)?;
+            assert_eq!(table.partitions(), reread.partitions());
+        }
+
+        // a protective MBR with no room for either table
+        let tiny = temp.path_view().join("tiny.img");
+        fs::write(&tiny, &fs::read(&disk)?[..SECTOR_SIZE as usize])?;
+        assert!(PartitionTable::open(&tiny).is_err());
+
+        let empty = PartitionTable {
+            path: tiny,
+            partitions: vec![],
+            ..table
+        };
+        assert!(empty.write().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_mbr_tables_work() -> Result<()> {
+        let temp = TempDir::new()?;
+        let disk = blank_disk(&temp, 32 * 1024 * 1024)?;
+
+        let mut table = PartitionTable::create(&disk, PartitionTableKind::Mbr)?;
+        let boot = table.add(
+            PartitionOptions::new(PartitionType::MBR_LINUX)
+                .size(8 * 1024 * 1024)
+                .bootable(true),
+        )?;
+        let data = table.add(PartitionOptions::new(PartitionType::MBR_LINUX))?;
+        assert!(table
+            .add(PartitionOptions::new(PartitionType::GPT_LINUX_FILESYSTEM))
+            .is_err());
+        assert!(table
+            .add(PartitionOptions::new(PartitionType::MBR_LINUX).label("nope"))
+            .is_err());
+        // there's no room left after the second partition
+        assert!(table.resize(boot, 16 * 1024 * 1024).is_err());
+
+        table.delete(data)?;
+        table.resize(boot, 16 * 1024 * 1024)?;
+        table.add(PartitionOptions::new(PartitionType::MBR_LINUX_SWAP))?;
+        table.write()?;
+
+        let table = PartitionTable::open(&disk)?;
+        assert_eq!(PartitionTableKind::Mbr, table.kind());
+        assert_eq!(2, table.partitions().len());
+        let boot = table.partition(1)?;
+        assert!(boot.bootable());
+        assert_eq!(None, boot.label());
+        assert_eq!(16 * 1024 * 1024, boot.size());
+        let swap = table.partition(2)?;
+        assert_eq!(PartitionType::MBR_LINUX_SWAP, swap.partition_type());
+        assert_eq!(17 * 1024 * 1024, swap.start());
+        assert_eq!(table.disk_size(), (swap.last_lba() + 1) * SECTOR_SIZE);
+
+        // an entry running past the end of the disk
+        let mut sector = mbr::read_sector0(&disk)?;
+        sector[446 + 12..446 + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        let file = OpenOptions::new().write(true).open(&disk)?;
+        std::os::unix::fs::FileExt::write_all_at(&file, &sector, 0)?;
+        assert!(PartitionTable::open(&disk).is_err());
+
+        Ok(())
+    }
+}
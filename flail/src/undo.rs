@@ -0,0 +1,225 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use byteorder::{ByteOrder, LittleEndian};
+use eyre::{eyre, Result};
+use lazy_static::lazy_static;
+use log::*;
+
+use crate::ext::report;
+
+// The undo file format written by libe2fs' undo io manager, see undo_io.c.
+const MAGIC: &[u8; 8] = b"E2UNDO02";
+const HEADER_SIZE: usize = 512;
+const STATE_FINISHED: u32 = 0x1;
+const FEATURE_COMPAT_FS_OFFSET: u32 = 0x1;
+const KEY_BLOCK_MAGIC: u32 = 0xcade_cade;
+const KEY_BLOCK_HEADER_SIZE: usize = 16;
+const KEY_SIZE: usize = 16;
+const MIN_BLOCK_SIZE: u32 = 1024;
+const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+
+lazy_static! {
+    /// libe2fs keeps the undo file and backing manager in globals, so only
+    /// one filesystem can be opened with an undo file at a time.
+    static ref UNDO_SETUP: Mutex<()> = Mutex::new(());
+}
+
+/// Points libe2fs' undo io manager at `undo_file` and `backing_manager`,
+/// returning the manager to open the filesystem with. Keep the guard until
+/// the filesystem is open. Like tune2fs, an existing undo file is replaced.
+pub(crate) fn undo_io_manager(
+    backing_manager: *mut libe2fs_sys::struct_io_manager,
+    undo_file: &Path,
+) -> Result<(MutexGuard<'static, ()>, *mut libe2fs_sys::struct_io_manager)> {
+    let guard = UNDO_SETUP.lock().unwrap();
+    match std::fs::remove_file(undo_file) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    debug!("recording undo file at {undo_file:?}");
+    let undo_file = CString::new(undo_file.as_os_str().as_bytes())?;
+    unsafe {
+        let err = libe2fs_sys::set_undo_io_backing_manager(backing_manager);
+        if err != 0 {
+            return report(err);
+        }
+        // libe2fs copies the name
+        let err = libe2fs_sys::set_undo_io_backup_file(undo_file.as_ptr() as *mut _);
+        if err != 0 {
+            return report(err);
+        }
+        Ok((guard, libe2fs_sys::undo_io_manager))
+    }
+}
+
+struct UndoKey {
+    fs_block: u64,
+    file_block: u64,
+    crc: u32,
+    size: usize,
+}
+
+/// Rolls `image` back to how it was before the modifications recorded in
+/// `undo_file`, like e2undo does.
+///
+/// Nothing is written unless every checksum in the undo file is intact, and
+/// the filesystem in `image` is still in the state the undo file left it in.
+/// An undo file can only be applied once, as applying it changes that state.
+pub fn apply<P: AsRef<Path>, U: AsRef<Path>>(image: P, undo_file: U) -> Result<()> {
+    let image = image.as_ref();
+    let undo_file = undo_file.as_ref();
+    debug!("applying undo file {undo_file:?} to {image:?}");
+    let undo = File::open(undo_file)?;
+
+    let mut header = [0; HEADER_SIZE];
+    undo.read_exact_at(&mut header, 0)?;
+    if &header[..8] != MAGIC {
+        return Err(eyre!("{undo_file:?} isn't an undo file"));
+    }
+    if crc32c(&header[..HEADER_SIZE - 4]) != LittleEndian::read_u32(&header[HEADER_SIZE - 4..]) {
+        return Err(eyre!("the header of {undo_file:?} is corrupt"));
+    }
+
+    let num_keys = LittleEndian::read_u64(&header[8..16]);
+    let key_offset = LittleEndian::read_u64(&header[24..32]);
+    let block_size = LittleEndian::read_u32(&header[32..36]);
+    let fs_block_size = LittleEndian::read_u32(&header[36..40]) as u64;
+    let sb_crc = LittleEndian::read_u32(&header[40..44]);
+    let state = LittleEndian::read_u32(&header[44..48]);
+    let compat = LittleEndian::read_u32(&header[48..52]);
+    let incompat = LittleEndian::read_u32(&header[52..56]);
+    let ro_compat = LittleEndian::read_u32(&header[56..60]);
+    let fs_offset = if compat & FEATURE_COMPAT_FS_OFFSET != 0 {
+        LittleEndian::read_u64(&header[64..72])
+    } else {
+        0
+    };
+
+    if state & STATE_FINISHED == 0 {
+        return Err(eyre!(
+            "{undo_file:?} is incomplete, the program writing it didn't finish"
+        ));
+    }
+    if incompat != 0 || ro_compat != 0 {
+        return Err(eyre!("{undo_file:?} uses unsupported features"));
+    }
+    if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || fs_block_size == 0 {
+        return Err(eyre!("{undo_file:?} has a bad block size of {block_size}"));
+    }
+    let block_size = block_size as usize;
+
+    let target = OpenOptions::new().read(true).write(true).open(image)?;
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    target.read_exact_at(&mut superblock, fs_offset + SUPERBLOCK_OFFSET)?;
+    if crc32c(&superblock) != sb_crc {
+        return Err(eyre!(
+            "the filesystem in {image:?} doesn't match {undo_file:?}, it was changed since or the undo file was already applied"
+        ));
+    }
+
+    // read and check everything before writing anything
+    let keys_per_block = ((block_size - KEY_BLOCK_HEADER_SIZE) / KEY_SIZE) as u64;
+    let mut keys = vec![];
+    let mut key_block = vec![0; block_size];
+    let mut file_block = key_offset;
+    while (keys.len() as u64) < num_keys {
+        undo.read_exact_at(&mut key_block, file_block * block_size as u64)?;
+        if LittleEndian::read_u32(&key_block[..4]) != KEY_BLOCK_MAGIC {
+            return Err(eyre!("bad key block magic at block {file_block}"));
+        }
+        let crc = LittleEndian::read_u32(&key_block[4..8]);
+        key_block[4..8].fill(0);
+        if crc32c(&key_block) != crc {
+            return Err(eyre!("bad key block checksum at block {file_block}"));
+        }
+
+        file_block += 1;
+        let count = (num_keys - keys.len() as u64).min(keys_per_block) as usize;
+        for key in key_block[KEY_BLOCK_HEADER_SIZE..]
+            .chunks_exact(KEY_SIZE)
+            .take(count)
+        {
+            let size = LittleEndian::read_u32(&key[12..16]) as usize;
+            keys.push(UndoKey {
+                fs_block: LittleEndian::read_u64(&key[..8]),
+                file_block,
+                crc: LittleEndian::read_u32(&key[8..12]),
+                size,
+            });
+            file_block += size.div_ceil(block_size) as u64;
+        }
+    }
+
+    let mut data = vec![];
+    for key in &keys {
+        data.resize(key.size, 0);
+        undo.read_exact_at(&mut data, key.file_block * block_size as u64)?;
+        if crc32c(&data) != key.crc {
+            return Err(eyre!(
+                "bad checksum for filesystem block {} in {undo_file:?}",
+                key.fs_block
+            ));
+        }
+    }
+
+    for key in &keys {
+        data.resize(key.size, 0);
+        undo.read_exact_at(&mut data, key.file_block * block_size as u64)?;
+        target.write_all_at(&data, fs_offset + key.fs_block * fs_block_size)?;
+    }
+    target.sync_all()?;
+
+    debug!("restored {} extents from {undo_file:?}", keys.len());
+    Ok(())
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    unsafe { libe2fs_sys::ext2fs_crc32c_le(!0, data.as_ptr(), data.len()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::ext::open::ExtOpenOptions;
+    use crate::ext::tests::{assert_fsck_clean, TempDir, TempImage};
+    use crate::ext::{ExtFilesystem, ExtFilesystemOpenFlags};
+
+    #[test]
+    pub fn test_undo_files_work() -> Result<()> {
+        let img = TempImage::new("./fixtures/empty.ext4")?;
+        let temp = TempDir::new()?;
+        let undo_file = temp.path_view().join("empty.e2undo");
+        let original = fs::read(img.path_view())?;
+
+        {
+            let fs = ExtFilesystem::open_with_options(
+                img.path_view(),
+                ExtOpenOptions::new()
+                    .flags(ExtFilesystemOpenFlags::OPEN_64BIT | ExtFilesystemOpenFlags::OPEN_RW)
+                    .undo_file(&undo_file),
+            )?;
+            fs.write_to_file("/test.txt", "hello flail".as_bytes())?;
+        }
+        assert!(original != fs::read(img.path_view())?);
+
+        apply(img.path_view(), &undo_file)?;
+        assert!(original == fs::read(img.path_view())?);
+        assert_fsck_clean(img.path_view())?;
+
+        // the image is back to how it was, which isn't what the undo file
+        // expects to find
+        assert!(apply(img.path_view(), &undo_file).is_err());
+
+        Ok(())
+    }
+}